@@ -1,7 +1,6 @@
 package model
 
 import (
-	"encoding/base64"
 	"fmt"
 	types "github.com/shibafu528/dtvault/dtvault-types-golang"
 )
@@ -16,10 +15,9 @@ func NewProgramFromPb(p *types.Program) *Program {
 		})
 	}
 
-	var thumb *string
-	if len(p.Thumbnail) != 0 && len(p.ThumbnailMimeType) != 0 {
-		t := fmt.Sprintf("data:%s;base64,%s", p.ThumbnailMimeType, base64.StdEncoding.EncodeToString(p.Thumbnail))
-		thumb = &t
+	var thumbID *string
+	if p.ThumbnailId != "" {
+		thumbID = &p.ThumbnailId
 	}
 
 	return &Program{
@@ -47,6 +45,6 @@ func NewProgramFromPb(p *types.Program) *Program {
 				Name:        p.Service.Channel.Name,
 			},
 		},
-		Thumbnail: thumb,
+		ThumbnailID: thumbID,
 	}
 }
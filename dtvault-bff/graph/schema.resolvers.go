@@ -5,8 +5,11 @@ package graph
 
 import (
 	"context"
+	"fmt"
+	"net/url"
 	"regexp"
 	"strconv"
+	"strings"
 	"time"
 
 	"github.com/shibafu528/dtvault/dtvault-bff/graph/generated"
@@ -54,6 +57,18 @@ func (r *programResolver) Videos(ctx context.Context, obj *model.Program) ([]*mo
 	return videos, nil
 }
 
+func (r *programResolver) Thumbnail(ctx context.Context, obj *model.Program, size model.ThumbnailSize) (*string, error) {
+	if obj.ThumbnailID == nil {
+		return nil, nil
+	}
+
+	q := url.Values{}
+	q.Set("id", *obj.ThumbnailID)
+	q.Set("size", strings.ToLower(size.String()))
+	u := fmt.Sprintf("/thumbnail?%s", q.Encode())
+	return &u, nil
+}
+
 func (r *queryResolver) Programs(ctx context.Context) ([]*model.Program, error) {
 	conn, err := r.CentralAddr.Dial()
 	if err != nil {
@@ -10,11 +10,15 @@ import (
 	"github.com/shibafu528/dtvault/dtvault-bff/graph"
 	"github.com/shibafu528/dtvault/dtvault-bff/graph/generated"
 	"github.com/shibafu528/dtvault/dtvault-bff/grpcaddr"
+	"github.com/shibafu528/dtvault/dtvault-types-golang/central"
 	"github.com/shibafu528/dtvault/dtvault-types-golang/storage"
+	"google.golang.org/grpc/codes"
+	"google.golang.org/grpc/status"
 	"io"
 	"log"
 	"net/http"
 	"os"
+	"strings"
 )
 
 const defaultPort = "8080"
@@ -46,11 +50,78 @@ func main() {
 	http.Handle("/", playground.Handler("GraphQL playground", "/query"))
 	http.Handle("/query", srv)
 	http.HandleFunc("/stream", streamHandler)
+	http.HandleFunc("/thumbnail", thumbnailHandler)
 
 	log.Printf("connect to http://localhost:%s/ for GraphQL playground", port)
 	log.Fatal(http.ListenAndServe(":"+port, nil))
 }
 
+func thumbnailHandler(w http.ResponseWriter, r *http.Request) {
+	if r.Method != http.MethodGet {
+		w.WriteHeader(http.StatusNotFound)
+		fmt.Fprint(w, "Not found\n")
+		return
+	}
+
+	q := r.URL.Query()
+	id := q.Get("id")
+	if id == "" {
+		w.WriteHeader(http.StatusBadRequest)
+		fmt.Fprint(w, "Missing id\n")
+		return
+	}
+	size := central.GetThumbnailRequest_ORIGINAL
+	if s := q.Get("size"); s != "" {
+		v, ok := central.GetThumbnailRequest_Size_value[strings.ToUpper(s)]
+		if !ok {
+			w.WriteHeader(http.StatusBadRequest)
+			fmt.Fprintf(w, "Size '%s' not found\n", s)
+			return
+		}
+		size = central.GetThumbnailRequest_Size(v)
+	}
+
+	conn, err := centralAddr.Dial()
+	if err != nil {
+		log.Printf("fail to dial: %v", err)
+		w.WriteHeader(http.StatusInternalServerError)
+		fmt.Fprint(w, "Internal error\n")
+		return
+	}
+	defer conn.Close()
+
+	client := central.NewProgramServiceClient(conn)
+	res, err := client.GetThumbnail(r.Context(), &central.GetThumbnailRequest{
+		ThumbnailId: id,
+		Size:        size,
+		IfNoneMatch: strings.Trim(r.Header.Get("If-None-Match"), `"`),
+	})
+	if err != nil {
+		switch status.Code(err) {
+		case codes.NotFound:
+			w.WriteHeader(http.StatusNotFound)
+			fmt.Fprint(w, "Not found\n")
+		case codes.InvalidArgument:
+			w.WriteHeader(http.StatusBadRequest)
+			fmt.Fprint(w, "Invalid id\n")
+		default:
+			log.Printf("GetThumbnail: %v", err)
+			w.WriteHeader(http.StatusInternalServerError)
+			fmt.Fprint(w, "Internal error\n")
+		}
+		return
+	}
+
+	w.Header().Set("ETag", fmt.Sprintf(`"%s"`, res.Etag))
+	w.Header().Set("Cache-Control", "no-cache")
+	if res.NotModified {
+		w.WriteHeader(http.StatusNotModified)
+		return
+	}
+	w.Header().Set("Content-Type", res.MimeType)
+	w.Write(res.Data)
+}
+
 func streamHandler(w http.ResponseWriter, r *http.Request) {
 	if r.Method != http.MethodGet {
 		w.WriteHeader(http.StatusNotFound)
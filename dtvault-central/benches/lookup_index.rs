@@ -0,0 +1,176 @@
+//! 10万件の番組と動画を持つライブラリで、索引を使った検索と全件の走査を比べる。
+//! `cargo bench -p dtvault-central --bench lookup_index` で実行する。
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dtvault_central::config::Config;
+use dtvault_central::program::{Persistence, Program, ProgramKey, ProgramStore, Video, PERSIST_MAGIC, SCHEMA_VERSION};
+use dtvault_types::shibafu528::dtvault as types;
+use dtvault_types::shibafu528::dtvault::central::PersistStore;
+use prost::Message;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const PROGRAMS: u32 = 100_000;
+const SERVICES: u32 = 20;
+
+fn program(i: u32) -> Program {
+    Program::from_exchanged(types::Program {
+        network_id: 32736,
+        service_id: 1024 + i % SERVICES,
+        event_id: i % 65536,
+        start_at: Some(prost_types::Timestamp {
+            seconds: 1617202800 + (i / SERVICES) as i64 * 1800,
+            nanos: 0,
+        }),
+        duration: Some(prost_types::Duration {
+            seconds: 1800,
+            nanos: 0,
+        }),
+        name: format!("番組 {}", i),
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+fn video(program: &Program, provider_id: String, storage_id: Uuid) -> Video {
+    let mut video = Video::from_exchanged(
+        program,
+        types::storage::create_video_request::Header {
+            provider_id,
+            file_name: "video.m2ts".to_string(),
+            mime_type: "video/mp2t".to_string(),
+            ..Default::default()
+        },
+    );
+    video.storage_id = storage_id;
+    video
+}
+
+/// 合成したライブラリをデータベースとして書き出し、起動時と同じく読み込んで索引を作る。
+fn open_library(data_dir: &std::path::Path, storage_ids: &[Uuid]) -> ProgramStore {
+    let mut persisted = PersistStore {
+        magic: PERSIST_MAGIC,
+        schema_version: SCHEMA_VERSION,
+        ..Default::default()
+    };
+    for i in 0..PROGRAMS {
+        let mut sp = program(i);
+        let sv = video(&sp, format!("bench:{}", i), storage_ids[i as usize % storage_ids.len()]);
+        sp.video_ids_mut().push(sv.id);
+        persisted.programs.push(sp.persist());
+        persisted.videos.push(sv.persist());
+    }
+
+    let config = format!(
+        "[server]\nlisten = \"127.0.0.1:0\"\n[database]\ndata_dir = {:?}\n",
+        data_dir.to_str().unwrap()
+    );
+    let config: Arc<Config> = Arc::new(toml::from_str(&config).unwrap());
+    let mut buf = vec![];
+    persisted.encode(&mut buf).unwrap();
+    std::fs::write(config.database.programs_file_path(), buf).unwrap();
+    ProgramStore::new(config).unwrap()
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let storage_ids = [Uuid::new_v4(), Uuid::new_v4()];
+    let store = open_library(dir.path(), &storage_ids);
+    let snapshot = store.snapshot().unwrap();
+
+    let provider_id = format!("bench:{}", PROGRAMS / 2);
+    let mut group = c.benchmark_group("provider_id");
+    group.bench_function("indexed", |b| {
+        b.iter(|| {
+            store
+                .find_videos_by_provider_id(black_box(&provider_id), false)
+                .unwrap()
+        })
+    });
+    group.bench_function("scan", |b| {
+        b.iter(|| {
+            snapshot
+                .videos
+                .iter()
+                .filter(|v| v.provider_id == *black_box(&provider_id))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+
+    let storage_id = storage_ids[0];
+    let mut group = c.benchmark_group("storage_id");
+    group.bench_function("indexed", |b| {
+        b.iter(|| store.find_videos_by_storage_id(black_box(&storage_id), false).unwrap())
+    });
+    group.bench_function("scan", |b| {
+        b.iter(|| {
+            snapshot
+                .videos
+                .iter()
+                .filter(|v| v.storage_id == black_box(storage_id))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+
+    let service = (32736, 1030);
+    let mut group = c.benchmark_group("service");
+    group.bench_function("indexed", |b| {
+        b.iter(|| store.find_programs_by_service(black_box(service), false).unwrap())
+    });
+    group.bench_function("scan", |b| {
+        b.iter(|| {
+            snapshot
+                .programs
+                .iter()
+                .filter(|p| (p.network_id, p.service_id) == black_box(service))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+
+    let from = snapshot.programs[PROGRAMS as usize / 2].start_at;
+    let until = from + chrono::Duration::days(1);
+    let mut group = c.benchmark_group("start_at_1day");
+    group.bench_function("indexed", |b| {
+        b.iter(|| {
+            store
+                .find_programs_by_start_at(black_box(Some(from)), Some(until), false)
+                .unwrap()
+        })
+    });
+    group.bench_function("scan", |b| {
+        b.iter(|| {
+            snapshot
+                .programs
+                .iter()
+                .filter(|p| black_box(from) <= p.start_at && p.start_at < until)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+
+    // 重複の確認は provider_id の索引で行うため、番組の動画が増えても遅くならない
+    let sp = snapshot.programs[0].clone();
+    let key = ProgramKey::from_stored_program(&sp);
+    let mut n = 0;
+    c.bench_function("create_video", |b| {
+        b.iter(|| {
+            n += 1;
+            store
+                .create_video(&key, video(&sp, format!("extra:{}", n), storage_id))
+                .unwrap()
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_lookup
+}
+criterion_main!(benches);
@@ -0,0 +1,332 @@
+use crate::config::Config;
+use crate::program::{MutexPoisonError, ProgramStore, RestoreError};
+use chrono::Utc;
+use dtvault_types::shibafu528::dtvault::central::{PersistBackup, PersistBlob};
+use prost::Message;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tonic::Status;
+
+const BACKUP_PREFIX: &str = "dtvault-backup-";
+const BACKUP_EXTENSION: &str = ".pb";
+
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("Invalid backup name: {0}")]
+    InvalidName(String),
+    #[error("Backup not found: {0}")]
+    NotFound(String),
+    #[error("Broken backup: {0}")]
+    Broken(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+impl From<RestoreError> for BackupError {
+    fn from(e: RestoreError) -> Self {
+        match e {
+            RestoreError::Broken(e) => BackupError::Broken(e.to_string()),
+            RestoreError::IoError(e) => BackupError::IoError(e),
+            RestoreError::Poisoned(e) => BackupError::Poisoned(e),
+        }
+    }
+}
+
+/// 書き出したバックアップ
+pub struct BackupInfo {
+    pub name: String,
+    pub revision: u64,
+    pub program_count: usize,
+    pub video_count: usize,
+    pub thumbnail_count: usize,
+    pub size: u64,
+}
+
+/// 復元した結果
+pub struct RestoreReport {
+    pub revision: u64,
+    pub program_count: usize,
+    pub video_count: usize,
+}
+
+pub fn map_backup_error(e: BackupError) -> Status {
+    match e {
+        BackupError::InvalidName(name) => Status::invalid_argument(format!("Invalid value: name ({})", name)),
+        BackupError::NotFound(name) => Status::not_found(format!("Backup not found (name = {})", name)),
+        BackupError::Broken(msg) => Status::failed_precondition(format!("Broken backup: {}", msg)),
+        BackupError::IoError(e) => Status::internal(format!("IO error: {}", e)),
+        BackupError::Poisoned(e) => Status::aborted(format!("{}", e)),
+    }
+}
+
+fn is_backup_name(name: &str) -> bool {
+    name.starts_with(BACKUP_PREFIX)
+        && name.ends_with(BACKUP_EXTENSION)
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+        && !name.contains("..")
+}
+
+/// 現在の内容をバックアップとして backup_dir に書き出し、古いものから backup_generations を超えた分を削除する。
+/// ロックを持つのは ProgramStore::snapshot の間だけで、変換と書き込みは書き込みを止めずに行う。
+pub fn create_backup(
+    config: &Config,
+    store: &ProgramStore,
+    include_thumbnails: bool,
+) -> Result<BackupInfo, BackupError> {
+    let snapshot = store.snapshot()?;
+    let created_at = Utc::now();
+
+    let mut blobs = vec![];
+    if include_thumbnails {
+        let mut keys = HashSet::new();
+        for key in snapshot.videos.iter().filter_map(|v| v.thumbnail_blob.as_deref()) {
+            if !keys.insert(key) {
+                continue;
+            }
+            // スナップショットを取った後に削除された blob は含めない
+            if let Some(data) = store.read_blob(key)? {
+                blobs.push(PersistBlob {
+                    key: key.to_string(),
+                    data,
+                });
+            }
+        }
+    }
+
+    let backup = PersistBackup {
+        store: Some(snapshot.persist()),
+        created_at: Some(prost_types::Timestamp {
+            seconds: created_at.timestamp(),
+            nanos: created_at.timestamp_subsec_nanos() as i32,
+        }),
+        blobs,
+    };
+    let mut buf = Vec::with_capacity(backup.encoded_len());
+    backup.encode(&mut buf).map_err(io::Error::other)?;
+
+    let dir = config.database.backup_dir();
+    std::fs::create_dir_all(&dir)?;
+    let name = format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        created_at.format("%Y%m%dT%H%M%S%3fZ"),
+        BACKUP_EXTENSION
+    );
+    write_atomic(&dir.join(&name), &buf)?;
+    rotate(&dir, config.database.backup_generations())?;
+
+    Ok(BackupInfo {
+        name,
+        revision: snapshot.revision,
+        program_count: snapshot.programs.len(),
+        video_count: snapshot.videos.len(),
+        thumbnail_count: backup.blobs.len(),
+        size: buf.len() as u64,
+    })
+}
+
+/// backup_dir にある name のバックアップで ProgramStore の内容を置き換える。
+pub fn restore_backup(config: &Config, store: &ProgramStore, name: &str) -> Result<RestoreReport, BackupError> {
+    if !is_backup_name(name) {
+        return Err(BackupError::InvalidName(name.to_string()));
+    }
+    let bin = match std::fs::read(config.database.backup_dir().join(name)) {
+        Ok(bin) => bin,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(BackupError::NotFound(name.to_string())),
+        Err(e) => return Err(e.into()),
+    };
+    let backup = PersistBackup::decode(&bin[..]).map_err(|e| BackupError::Broken(e.to_string()))?;
+    let persisted = backup
+        .store
+        .ok_or_else(|| BackupError::Broken("missing store".to_string()))?;
+    let program_count = persisted.programs.len();
+    let video_count = persisted.videos.len();
+
+    let revision = store.restore(persisted, backup.blobs)?;
+    Ok(RestoreReport {
+        revision,
+        program_count,
+        video_count,
+    })
+}
+
+/// backup_dir にあるバックアップが参照している blob のキーを返す。
+pub fn retained_blob_keys(config: &Config) -> io::Result<HashSet<String>> {
+    let dir = config.database.backup_dir();
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+    let mut keys = HashSet::new();
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_str().is_some_and(is_backup_name) {
+            continue;
+        }
+        let bin = match std::fs::read(entry.path()) {
+            Ok(bin) => bin,
+            // 読む前に rotate で削除された
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let backup = PersistBackup::decode(&bin[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(store) = backup.store {
+            keys.extend(
+                store
+                    .videos
+                    .into_iter()
+                    .map(|v| v.thumbnail_blob)
+                    .filter(|k| !k.is_empty()),
+            );
+        }
+    }
+    Ok(keys)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)
+}
+
+/// 名前に日時が入っているので、名前順に並べて古いものから削除する。
+fn rotate(dir: &Path, generations: usize) -> io::Result<()> {
+    let mut names = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        if let Some(name) = entry?.file_name().to_str() {
+            if is_backup_name(name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    let excess = names.len().saturating_sub(generations.max(1));
+    for name in &names[..excess] {
+        std::fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
+
+/// 設定された間隔でバックアップを取るタスクを起動する。
+pub fn spawn_backup_scheduler(config: Arc<Config>, store: Arc<ProgramStore>) -> Option<JoinHandle<()>> {
+    let period = config.database.backup_interval()?;
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // 再起動のたびにバックアップが増えないよう、最初の1回は起動から1周期後にする
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let config = config.clone();
+            let store = store.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                create_backup(&config, &store, config.database.backup_include_thumbnails())
+            })
+            .await;
+            match result {
+                Ok(Ok(info)) => println!(
+                    "[Backup] Created {} ({} programs, {} videos, {} thumbnails)",
+                    info.name, info.program_count, info.video_count, info.thumbnail_count
+                ),
+                Ok(Err(e)) => eprintln!("[Backup] error: {}", e),
+                Err(e) => eprintln!("[Backup] error: {}", e),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::{ProgramKey, Video};
+
+    fn config(data_dir: &Path, backup_dir: &Path) -> Arc<Config> {
+        let database = format!(
+            "backup_dir = {:?}\nbackup_generations = 2\n",
+            backup_dir.to_str().unwrap()
+        );
+        Config::for_test(data_dir, &database)
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_dir = dir.path().join("backups");
+        let source_config = config(&dir.path().join("source"), &backup_dir);
+        std::fs::create_dir_all(source_config.database.data_dir()).unwrap();
+        let source = ProgramStore::new(source_config.clone()).unwrap();
+
+        let (sp, _) = source
+            .find_or_create(dtvault_types::shibafu528::dtvault::Program {
+                network_id: 32736,
+                service_id: 1024,
+                event_id: 1,
+                start_at: Some(prost_types::Timestamp {
+                    seconds: 1617202800,
+                    nanos: 0,
+                }),
+                duration: Some(prost_types::Duration {
+                    seconds: 1800,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        let key = ProgramKey::from_stored_program(&sp);
+        let video = Video::from_exchanged(
+            &sp,
+            dtvault_types::shibafu528::dtvault::storage::create_video_request::Header {
+                mime_type: "video/mp2t".to_string(),
+                ..Default::default()
+            },
+        );
+        let video = source.create_video(&key, video).unwrap();
+        source
+            .update_video_thumbnail(&video.id, vec![0xff, 0xd8], mime::IMAGE_JPEG)
+            .unwrap();
+
+        let mut names = vec![];
+        for _ in 0..3 {
+            let info = create_backup(&source_config, &source, true).unwrap();
+            assert_eq!((1, 1, 1), (info.program_count, info.video_count, info.thumbnail_count));
+            names.push(info.name);
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert!(!backup_dir.join(&names[0]).exists());
+        assert!(backup_dir.join(&names[2]).is_file());
+
+        let target_config = config(&dir.path().join("target"), &backup_dir);
+        std::fs::create_dir_all(target_config.database.data_dir()).unwrap();
+        let target = ProgramStore::new(target_config.clone()).unwrap();
+        assert!(matches!(
+            restore_backup(&target_config, &target, "../source/programs.pb"),
+            Err(BackupError::InvalidName(_))
+        ));
+        assert!(matches!(
+            restore_backup(&target_config, &target, &names[0]),
+            Err(BackupError::NotFound(_))
+        ));
+
+        let report = restore_backup(&target_config, &target, &names[2]).unwrap();
+        assert!(report.revision > source.change_feed().revision());
+        let video = target.find_video(&video.id).unwrap().unwrap();
+        let blob = target.read_blob(video.thumbnail_blob.as_ref().unwrap()).unwrap();
+        assert_eq!(Some(vec![0xff, 0xd8]), blob);
+        drop(target);
+
+        let target = ProgramStore::new(target_config).unwrap();
+        assert!(target.find(&key).unwrap().is_some());
+        assert_eq!(report.revision, target.change_feed().revision());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::program::ProgramStore;
+use std::collections::HashSet;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+const COLLECT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// 保存してから動画に登録するまでの間に削除しないよう、これより新しい blob は参照されていなくても残す
+const COLLECT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// 内容からキーを計算する。暗号学的な強度はないため、衝突は BlobStore::put で解決する。
+pub fn digest(data: &[u8]) -> String {
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(data);
+    format!("{:016x}{:08x}", hasher.finish(), data.len())
+}
+
+fn is_valid_key(key: &str) -> bool {
+    key.len() > 2 && key.bytes().all(|b| b.is_ascii_hexdigit() || b == b'-')
+}
+
+/// サムネイルなどの生成物を、内容のダイジェストをキーとしてファイルに保存する。
+/// 同じ内容は1つのファイルを共有し、読み込みは必要になるまで行わない。
+pub struct BlobStore {
+    root: PathBuf,
+    /// put と retain が同じファイルを同時に扱わないようにする
+    lock: Mutex<()>,
+}
+
+impl BlobStore {
+    pub fn open(root: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        Ok(BlobStore {
+            root,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2]).join(key)
+    }
+
+    /// 保存してキーを返す。同じ内容が既に保存されている場合は書き込まず、更新日時だけを新しくする。
+    pub fn put(&self, data: &[u8]) -> io::Result<String> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let digest = digest(data);
+        let mut key = digest.clone();
+        for n in 1.. {
+            let path = self.path(&key);
+            match std::fs::read(&path) {
+                Ok(existing) if existing == data => {
+                    // 参照されるまでの間に retain で削除されないようにする
+                    File::options()
+                        .write(true)
+                        .open(&path)?
+                        .set_modified(SystemTime::now())?;
+                    return Ok(key);
+                }
+                Ok(_) => key = format!("{}-{}", digest, n),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    write_atomic(&path, data)?;
+                    return Ok(key);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+
+    /// キーに対応する内容を読み込む。存在しない場合は None を返す。
+    pub fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        if !is_valid_key(key) {
+            return Ok(None);
+        }
+        match std::fs::read(self.path(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// keys に含まれず、更新日時が older_than より前のものを削除し、削除した数を返す。
+    /// put してから参照されるまでの間に削除しないよう、older_than には余裕を持たせること。
+    pub fn retain(&self, keys: &HashSet<String>, older_than: SystemTime) -> io::Result<usize> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut removed = 0;
+        for dir in std::fs::read_dir(&self.root)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(dir.path())? {
+                let file = file?;
+                let name = file.file_name();
+                match name.to_str() {
+                    Some(key) if is_valid_key(key) && keys.contains(key) => {}
+                    _ if file.metadata()?.modified()? >= older_than => {}
+                    _ => {
+                        std::fs::remove_file(file.path())?;
+                        removed += 1;
+                    }
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir)?;
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)
+}
+
+/// 参照されていない blob を定期的に削除するタスクを起動する。
+pub fn spawn_blob_collector(store: Arc<ProgramStore>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COLLECT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let store = store.clone();
+            match tokio::task::spawn_blocking(move || store.collect_blobs(COLLECT_GRACE_PERIOD)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => println!("[Blob] Removed {} unreferenced blobs", removed),
+                Ok(Err(e)) => eprintln!("[Blob] error: {}", e),
+                Err(e) => eprintln!("[Blob] error: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_and_retain() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = BlobStore::open(dir.path().join("blobs")).unwrap();
+
+        let a = blobs.put(b"jpeg").unwrap();
+        assert_eq!(a, blobs.put(b"jpeg").unwrap());
+        let b = blobs.put(b"png").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(Some(b"jpeg".to_vec()), blobs.get(&a).unwrap());
+        assert_eq!(None, blobs.get("../../etc/passwd").unwrap());
+
+        let keys: HashSet<String> = vec![a.clone()].into_iter().collect();
+        // 保存したばかりのものは参照されていなくても残す
+        let before_put = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(0, blobs.retain(&keys, before_put).unwrap());
+        assert!(blobs.get(&b).unwrap().is_some());
+
+        let after_put = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(1, blobs.retain(&keys, after_put).unwrap());
+        assert_eq!(None, blobs.get(&b).unwrap());
+        assert!(blobs.get(&a).unwrap().is_some());
+    }
+}
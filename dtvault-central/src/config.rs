@@ -1,9 +1,11 @@
 mod condition;
 
-use self::condition::Condition;
+pub use self::condition::Condition;
 use crate::program::{Program, Video};
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tonic::transport::Uri;
 use uuid::Uuid;
 
@@ -16,9 +18,15 @@ pub struct Config {
     #[serde(default)]
     pub outlet: Outlet,
     #[serde(default)]
+    pub trash: Trash,
+    #[serde(default)]
     pub storage_rules: Vec<StorageRule>,
     #[serde(default)]
     pub prefix_rules: Vec<PrefixRule>,
+    #[serde(default)]
+    pub series_rules: Vec<SeriesRule>,
+    #[serde(default)]
+    pub saved_searches: Vec<SavedSearch>,
 }
 
 impl Config {
@@ -38,8 +46,29 @@ impl Config {
         for rule in &self.prefix_rules {
             rule.validate()?;
         }
+        for rule in &self.series_rules {
+            rule.validate()?;
+        }
+        let mut names = HashSet::new();
+        for search in &self.saved_searches {
+            search.validate()?;
+            if !names.insert(&search.name) {
+                return Err(format!("duplicated saved search name: {}", search.name));
+            }
+        }
         Ok(())
     }
+
+    /// テスト用の設定を作る。database には [database] セクションに追加する行を渡す。
+    #[cfg(test)]
+    pub fn for_test(data_dir: &Path, database: &str) -> Arc<Config> {
+        let config = format!(
+            "[server]\nlisten = \"127.0.0.1:0\"\n[database]\ndata_dir = {:?}\n{}",
+            data_dir.to_str().unwrap(),
+            database
+        );
+        Arc::new(toml::from_str(&config).unwrap())
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -56,9 +85,34 @@ impl Server {
 #[derive(Deserialize, Debug)]
 pub struct Database {
     data_dir: String,
+    #[serde(default)]
+    backend: BackendKind,
+    #[serde(default = "Database::default_journal_compaction_threshold_mb")]
+    journal_compaction_threshold_mb: u64,
+    #[serde(default = "Database::default_snapshot_generations")]
+    snapshot_generations: usize,
+    backup_dir: Option<String>,
+    #[serde(default)]
+    backup_interval_hours: u32,
+    #[serde(default = "Database::default_backup_generations")]
+    backup_generations: usize,
+    #[serde(default)]
+    backup_include_thumbnails: bool,
 }
 
 impl Database {
+    fn default_journal_compaction_threshold_mb() -> u64 {
+        64
+    }
+
+    fn default_snapshot_generations() -> usize {
+        3
+    }
+
+    fn default_backup_generations() -> usize {
+        7
+    }
+
     pub fn validate(&self) -> Result<(), String> {
         let data_dir = Path::new(&self.data_dir);
         if !data_dir.is_dir() {
@@ -69,9 +123,106 @@ impl Database {
         Ok(())
     }
 
+    pub fn backend(&self) -> BackendKind {
+        self.backend
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        Path::new(&self.data_dir)
+    }
+
     pub fn programs_file_path(&self) -> PathBuf {
         PathBuf::from(self.data_dir.to_string()).join("programs.pb")
     }
+
+    /// 書き込み中のスナップショット
+    pub fn programs_temp_file_path(&self) -> PathBuf {
+        PathBuf::from(self.data_dir.to_string()).join("programs.pb.tmp")
+    }
+
+    /// generation 世代前のスナップショット (1始まり)
+    pub fn programs_generation_path(&self, generation: usize) -> PathBuf {
+        PathBuf::from(self.data_dir.to_string()).join(format!("programs.pb.{}", generation))
+    }
+
+    /// 残しておく古いスナップショットの数
+    pub fn snapshot_generations(&self) -> usize {
+        self.snapshot_generations
+    }
+
+    pub fn sqlite_file_path(&self) -> PathBuf {
+        PathBuf::from(self.data_dir.to_string()).join("programs.sqlite3")
+    }
+
+    /// サムネイルなどの生成物を保存するディレクトリ
+    pub fn blobs_dir(&self) -> PathBuf {
+        PathBuf::from(self.data_dir.to_string()).join("blobs")
+    }
+
+    pub fn lock_file_path(&self) -> PathBuf {
+        PathBuf::from(self.data_dir.to_string()).join(".lock")
+    }
+
+    pub fn journal_file_path(&self) -> PathBuf {
+        PathBuf::from(self.data_dir.to_string()).join("programs.log")
+    }
+
+    /// 変更ログがこのバイト数を超えたらスナップショットを作り直す
+    pub fn journal_compaction_threshold(&self) -> u64 {
+        self.journal_compaction_threshold_mb * 1024 * 1024
+    }
+
+    /// バックアップを書き出すディレクトリ。指定がなければ data_dir/backups
+    pub fn backup_dir(&self) -> PathBuf {
+        match &self.backup_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(self.data_dir.to_string()).join("backups"),
+        }
+    }
+
+    /// 定期的にバックアップを取る間隔。取らない場合は None
+    pub fn backup_interval(&self) -> Option<std::time::Duration> {
+        if self.backup_interval_hours == 0 {
+            None
+        } else {
+            Some(std::time::Duration::from_secs(
+                self.backup_interval_hours as u64 * 60 * 60,
+            ))
+        }
+    }
+
+    /// 残しておくバックアップの数
+    pub fn backup_generations(&self) -> usize {
+        self.backup_generations
+    }
+
+    /// 定期バックアップにサムネイルを含めるか
+    pub fn backup_include_thumbnails(&self) -> bool {
+        self.backup_include_thumbnails
+    }
+}
+
+/// 番組データベースの保存方法
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// スナップショットと変更ログのファイル
+    #[default]
+    File,
+    /// SQLite
+    Sqlite,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(BackendKind::File),
+            "sqlite" => Ok(BackendKind::Sqlite),
+            _ => Err(format!("unknown backend `{}`", s)),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -157,6 +308,36 @@ impl Outlet {
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Trash {
+    /// ゴミ箱に移動してから完全に削除するまでの日数。0 の場合は自動で削除しない
+    #[serde(default = "Trash::default_retention_days")]
+    pub retention_days: u32,
+}
+
+impl Default for Trash {
+    fn default() -> Self {
+        Trash {
+            retention_days: Self::default_retention_days(),
+        }
+    }
+}
+
+impl Trash {
+    fn default_retention_days() -> u32 {
+        30
+    }
+
+    /// ゴミ箱に移動してから完全に削除するまでの期間。自動で削除しない場合は None
+    pub fn retention(&self) -> Option<chrono::Duration> {
+        if self.retention_days == 0 {
+            None
+        } else {
+            Some(chrono::Duration::days(self.retention_days as i64))
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub struct StorageRule {
     condition: Condition,
@@ -203,3 +384,54 @@ impl PrefixRule {
         self.condition.matches(program, video)
     }
 }
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SeriesRule {
+    condition: Condition,
+    /// 自動判定の代わりに使用するシリーズ名
+    #[serde(default)]
+    pub series_title: String,
+    /// true の場合はシリーズとして扱わない
+    #[serde(default)]
+    pub exclude: bool,
+}
+
+impl SeriesRule {
+    pub fn validate(&self) -> Result<(), String> {
+        self.condition.validate()?;
+        if self.series_title.is_empty() != self.exclude {
+            return Err("you must specify exactly one of these properties: series_title, exclude".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, program: &Program) -> bool {
+        self.condition.matches_program(program)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SavedSearch {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub condition: Arc<Condition>,
+}
+
+impl SavedSearch {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("saved search name is empty".to_string());
+        }
+        self.condition.validate()?;
+        if self.condition.requires_video() {
+            return Err(format!(
+                "saved search {}: conditions about video cannot be used",
+                self.name
+            ));
+        }
+
+        Ok(())
+    }
+}
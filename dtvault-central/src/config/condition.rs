@@ -21,14 +21,15 @@ trait Matcher<T: Eq> {
 
 trait BoundMatcher: Send + Sync {
     fn validate(&self) -> Result<(), String>;
-    fn matches(&self, program: &Program, video: &Video) -> bool;
+    /// video が None の場合、動画に関する条件は一致しないものとして扱う。
+    fn matches(&self, program: &Program, video: Option<&Video>) -> bool;
 }
 
 struct MatcherWithThunk<T, M, Thunk>
 where
     T: Eq,
     M: Send + Sync + Matcher<T>,
-    Thunk: Send + Sync + Fn(&Program, &Video, &M) -> bool,
+    Thunk: Send + Sync + Fn(&Program, Option<&Video>, &M) -> bool,
 {
     matcher: M,
     thunk: Thunk,
@@ -39,13 +40,13 @@ impl<T, M, Thunk> BoundMatcher for MatcherWithThunk<T, M, Thunk>
 where
     T: Eq,
     M: Send + Sync + Matcher<T>,
-    Thunk: Send + Sync + Fn(&Program, &Video, &M) -> bool,
+    Thunk: Send + Sync + Fn(&Program, Option<&Video>, &M) -> bool,
 {
     fn validate(&self) -> Result<(), String> {
         self.matcher.validate()
     }
 
-    fn matches(&self, program: &Program, video: &Video) -> bool {
+    fn matches(&self, program: &Program, video: Option<&Video>) -> bool {
         (self.thunk)(program, video, &self.matcher)
     }
 }
@@ -53,6 +54,7 @@ where
 #[derive(Default)]
 pub struct Condition {
     matchers: MatcherVec,
+    keys: Vec<&'static str>,
 }
 
 impl Condition {
@@ -64,12 +66,17 @@ impl Condition {
     }
 
     pub fn matches(&self, program: &Program, video: &Video) -> bool {
-        for matcher in &self.matchers {
-            if !matcher.matches(program, video) {
-                return false;
-            }
-        }
-        true
+        self.matchers.iter().all(|m| m.matches(program, Some(video)))
+    }
+
+    /// 動画を伴わずに番組だけで判定する。動画に関する条件を含む場合は一致しない。
+    pub fn matches_program(&self, program: &Program) -> bool {
+        self.matchers.iter().all(|m| m.matches(program, None))
+    }
+
+    /// 動画に関する条件を含むかどうか
+    pub fn requires_video(&self) -> bool {
+        self.keys.iter().any(|k| k.starts_with("video_"))
     }
 }
 
@@ -86,8 +93,8 @@ impl<'de> Deserialize<'de> for Condition {
     where
         D: Deserializer<'de>,
     {
-        let matchers = deserializer.deserialize_map(ConditionVisitor)?;
-        Ok(Condition { matchers })
+        let (matchers, keys) = deserializer.deserialize_map(ConditionVisitor)?;
+        Ok(Condition { matchers, keys })
     }
 }
 
@@ -100,6 +107,7 @@ macro_rules! parse_conditions {
         ];
 
         let mut matchers = MatcherVec::new();
+        let mut keys = vec![];
 
         while let Some(key) = $access.next_key()? {
             match key {
@@ -112,28 +120,29 @@ macro_rules! parse_conditions {
                             _marker: PhantomData,
                         };
                         matchers.push(Box::new(bound));
+                        keys.push(stringify!($key));
                     }
                 )+
                 _ => return Err(serde::de::Error::unknown_field(key, KEYS)),
             }
         }
 
-        matchers
+        (matchers, keys)
     }}
 }
 
 impl<'de> Visitor<'de> for ConditionVisitor {
-    type Value = MatcherVec;
+    type Value = (MatcherVec, Vec<&'static str>);
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        todo!()
+        formatter.write_str("a map of conditions")
     }
 
     fn visit_map<A>(self, mut map: A) -> Result<Self::Value, <A as MapAccess<'de>>::Error>
     where
         A: MapAccess<'de>,
     {
-        let matchers = parse_conditions! {
+        let parsed = parse_conditions! {
             from map;
 
             title: StringOrRegex => |program, _video, matcher| { matcher.matches(&program.name) };
@@ -163,18 +172,23 @@ impl<'de> Visitor<'de> for ConditionVisitor {
                 }
                 false
             };
+            tag: StringOrRegex => |program, _video, matcher| { program.tags().iter().any(|t| matcher.matches(t)) };
             start_at: DateTimeRange => |program, _video, matcher| { matcher.matches(&program.start_at.with_timezone(&chrono::Local)) };
             video_total_length: Int64Range => |_program, video, matcher| {
-                match video.total_length.to_i64() {
+                match video.and_then(|v| v.total_length.to_i64()) {
                     Some(v) => matcher.matches(&v),
                     None => false,
                 }
             };
-            video_mime_type: StringOrRegex => |_program, video, matcher| { matcher.matches(&video.mime_type.essence_str().to_string()) };
-            video_provider_id: StringOrRegex => |_program, video, matcher| { matcher.matches(&video.provider_id) };
+            video_mime_type: StringOrRegex => |_program, video, matcher| {
+                video.is_some_and(|v| matcher.matches(&v.mime_type.essence_str().to_string()))
+            };
+            video_provider_id: StringOrRegex => |_program, video, matcher| {
+                video.is_some_and(|v| matcher.matches(&v.provider_id))
+            };
         };
 
-        Ok(matchers)
+        Ok(parsed)
     }
 }
 
@@ -191,5 +205,16 @@ mod tests {
 
         let cond: Condition = toml::from_str(input).unwrap();
         assert_eq!(2, cond.matchers.len());
+        assert!(!cond.requires_video());
+    }
+
+    #[test]
+    fn test_json() {
+        let input = r#"{"channel_type": "GR", "video_mime_type": "video/mp2t"}"#;
+
+        let cond: Condition = serde_json::from_str(input).unwrap();
+        assert_eq!(2, cond.matchers.len());
+        assert!(cond.requires_video());
+        assert!(serde_json::from_str::<Condition>("[]").is_err());
     }
 }
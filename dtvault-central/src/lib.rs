@@ -0,0 +1,11 @@
+pub mod backup;
+pub mod blob_store;
+pub mod config;
+pub mod event;
+pub mod program;
+pub mod recovery;
+pub mod serde;
+pub mod sidecar;
+pub mod thumbnail;
+pub mod trash;
+pub mod video_storage;
@@ -1,14 +1,9 @@
-mod config;
-mod event;
-mod program;
-mod serde;
-mod video_storage;
-
-use crate::config::Config;
-use crate::event::EventContext;
-use crate::program::{ProgramService, ProgramStore};
-use crate::video_storage::{FileSystem, IStorage, VideoStorageService};
 use ::serde::Deserialize;
+use dtvault_central::config::{self, BackendKind, Config};
+use dtvault_central::event::{self, EventContext};
+use dtvault_central::program::{self, ProgramService, ProgramStore};
+use dtvault_central::video_storage::{self, FileSystem, IStorage, VideoStorageService};
+use dtvault_central::{backup, blob_store, recovery, sidecar, trash};
 use dtvault_types::shibafu528::dtvault::central::program_service_server::ProgramServiceServer;
 use dtvault_types::shibafu528::dtvault::storage::video_storage_service_server::VideoStorageServiceServer;
 use envy::Error as EnvyError;
@@ -43,22 +38,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     let config = Arc::new(config);
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate-database") {
+        return migrate_database(config, args.get(2).map(String::as_str));
+    }
+    if args.get(1).map(String::as_str) == Some("recover-database") {
+        return recover_database(config, &args[2..]).await;
+    }
+    if args.get(1).map(String::as_str) == Some("resync-sidecars") {
+        return resync_sidecars(config).await;
+    }
+
     let (event_emitter, event_receiver) = event::make_event_channel();
 
     let program_store = Arc::new(ProgramStore::new(config.clone())?);
-    let program_service = ProgramService::new(program_store.clone());
 
-    let mut storages = Vec::<Arc<IStorage>>::new();
-    for conf in &config.storages {
-        match conf {
-            config::Storage::FileSystem(fs) => {
-                storages.push(Arc::new(FileSystem::new(fs.label.to_string(), fs.root_dir.to_string())))
-            }
-            config::Storage::Tempfile(tf) => {
-                storages.push(Arc::new(video_storage::Tempfile::new(tf.label.to_string())))
-            }
-        }
-    }
+    let storages = open_storages(&config);
+    let program_service = ProgramService::new(config.clone(), program_store.clone(), storages.clone());
     let video_storage_service = VideoStorageService::new(
         config.clone(),
         program_store.clone(),
@@ -70,10 +66,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         EventContext {
             config: config.clone(),
             program_store: program_store.clone(),
-            storages,
+            storages: storages.clone(),
         },
         event_receiver,
     );
+    let _trash_join_handle = trash::spawn_trash_purger(config.clone(), program_store.clone(), storages.clone());
+    let _journal_join_handle = program::spawn_compactor(program_store.clone());
+    let _blob_join_handle = blob_store::spawn_blob_collector(program_store.clone());
+    let _sidecar_join_handle = sidecar::spawn_sidecar_writer(program_store.clone(), storages);
+    let _backup_join_handle = backup::spawn_backup_scheduler(config.clone(), program_store.clone());
 
     let addr = config.server.listen.parse().unwrap();
     println!("Server listening on {}", addr);
@@ -90,6 +91,129 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// 設定されているバックエンドから、指定されたバックエンドに番組データベースを変換する。
+fn migrate_database(config: Arc<Config>, to: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let to: BackendKind = match to.map(str::parse) {
+        Some(Ok(kind)) => kind,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            exit(1)
+        }
+        None => {
+            eprintln!("Usage: dtvault-central migrate-database <file|sqlite>");
+            exit(1)
+        }
+    };
+    let from = config.database.backend();
+    if from == to {
+        eprintln!("The database is already stored in {:?} backend", to);
+        exit(1)
+    }
+
+    let program_store = ProgramStore::new(config)?;
+    program_store.export(to)?;
+    println!(
+        "Migrated from {:?} to {:?} backend. Change `backend` in [database] section to use it.",
+        from, to
+    );
+    Ok(())
+}
+
+/// ストレージに保存されている番組と動画の情報から番組データベースを修復する。
+/// --rebuild を指定した場合は、保存されている内容を読み込まずに作り直す。
+async fn recover_database(config: Arc<Config>, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rebuild = false;
+    let mut dry_run = false;
+    for arg in args {
+        match arg.as_str() {
+            "--rebuild" => rebuild = true,
+            "--dry-run" => dry_run = true,
+            _ => {
+                eprintln!("Usage: dtvault-central recover-database [--rebuild] [--dry-run]");
+                exit(1)
+            }
+        }
+    }
+
+    let program_store = if rebuild {
+        ProgramStore::open_empty(config.clone())?
+    } else {
+        ProgramStore::new(config.clone())?
+    };
+    if rebuild && !dry_run {
+        match program_store.backup_database() {
+            Ok(path) => println!("Database backed up to {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!(
+                    "Failed to back up the database, move it aside manually to rebuild: {}",
+                    e
+                );
+                exit(1)
+            }
+        }
+        // compact は blob を削除しないので、取り込み直す前のサムネイルはここでは残る
+        program_store.compact()?;
+    }
+
+    let storages = open_storages(&config);
+    let report = recovery::recover(&program_store, &storages, dry_run).await?;
+    if rebuild && !dry_run {
+        // 取り込み直した動画から参照されるようになってから削除する。data_dir は他から使われていないので猶予は要らない
+        let removed = program_store.collect_blobs(std::time::Duration::from_secs(0))?;
+        println!("{} unreferenced blobs removed.", removed);
+    }
+    for (title, items) in &[
+        ("Conflicts", &report.conflicts),
+        ("Orphans", &report.orphans),
+        ("Missing", &report.missing),
+        ("Failures", &report.failures),
+    ] {
+        if items.is_empty() {
+            continue;
+        }
+        println!("{} ({}):", title, items.len());
+        for item in items.iter() {
+            println!("  {}", item);
+        }
+    }
+    println!(
+        "{} videos matched, {} programs and {} videos {}.",
+        report.matched,
+        report.imported_programs,
+        report.imported_videos,
+        if dry_run { "would be imported" } else { "imported" }
+    );
+    Ok(())
+}
+
+/// 全ての動画について、動画と一緒に保存している番組と動画の情報を書き直す。
+async fn resync_sidecars(config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
+    let program_store = ProgramStore::new(config.clone())?;
+    let storages = open_storages(&config);
+    let report = sidecar::resync_all(&program_store, &storages).await?;
+    for failure in &report.failures {
+        eprintln!("{}", failure);
+    }
+    println!("{} videos updated, {} failed.", report.updated, report.failures.len());
+    Ok(())
+}
+
+fn open_storages(config: &Config) -> Vec<Arc<IStorage>> {
+    let mut storages = Vec::<Arc<IStorage>>::new();
+    for conf in &config.storages {
+        match conf {
+            config::Storage::FileSystem(fs) => {
+                storages.push(Arc::new(FileSystem::new(fs.label.to_string(), fs.root_dir.to_string())))
+            }
+            config::Storage::Tempfile(tf) => {
+                storages.push(Arc::new(video_storage::Tempfile::new(tf.label.to_string())))
+            }
+        }
+    }
+    storages
+}
+
 fn request_logger(req: Request<()>) -> Result<Request<()>, Status> {
     println!("Request => {:?}", req);
     Ok(req)
@@ -1,25 +1,77 @@
+mod backend;
+mod change_feed;
+mod duplicate;
+mod edit;
+mod journal;
+mod lookup_index;
+mod migration;
 mod model;
 mod program_key;
 mod program_store;
 mod prost_convert;
+mod query;
+mod registry;
+mod search_index;
+mod series;
+mod statistics;
 mod validator;
+mod watch;
 
+pub use self::backend::*;
+pub use self::change_feed::*;
+pub use self::duplicate::*;
+pub use self::edit::*;
+pub use self::journal::*;
+pub use self::lookup_index::*;
+pub use self::migration::*;
 pub use self::model::*;
 pub use self::program_key::*;
 pub use self::program_store::*;
+pub use self::query::*;
+pub use self::registry::*;
+pub use self::search_index::*;
+pub use self::series::*;
+pub use self::statistics::*;
 pub use self::validator::*;
+pub use self::watch::*;
+use crate::backup::{self, map_backup_error};
+use crate::config::{Condition, Config};
+use crate::program::prost_convert::{ToDateTimeExt, ToDurationExt, ToTimestampExt};
+use crate::thumbnail::{self, ThumbnailCache, ThumbnailSize};
+use crate::trash;
+use crate::video_storage::{require_storage_by_id, IStorage};
+use chrono::Utc;
+use dtvault_types::shibafu528::dtvault::central::list_programs_request::OrderBy;
 use dtvault_types::shibafu528::dtvault::central::program_service_server::ProgramService as ProgramServiceTrait;
+use dtvault_types::shibafu528::dtvault::central::query_programs_request::{ConditionFormat, Query};
+use dtvault_types::shibafu528::dtvault::central::update_watch_state_request::Watched;
 use dtvault_types::shibafu528::dtvault::central::*;
+use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+const MAX_PAGE_SIZE: usize = 1000;
+const MAX_CONDITION_SIZE: usize = 64 * 1024;
+const DUPLICATE_GROUP_METADATA_KEY: &str = "dtvault_duplicate_group";
 
 pub struct ProgramService {
+    config: Arc<Config>,
     store: Arc<ProgramStore>,
+    storages: Vec<Arc<IStorage>>,
+    thumbnails: ThumbnailCache,
 }
 
 impl ProgramService {
-    pub fn new(store: Arc<ProgramStore>) -> Self {
-        ProgramService { store }
+    pub fn new(config: Arc<Config>, store: Arc<ProgramStore>, storages: Vec<Arc<IStorage>>) -> Self {
+        ProgramService {
+            config,
+            store,
+            storages,
+            thumbnails: ThumbnailCache::new(),
+        }
     }
 
     fn assign_thumbnail(&self, sp: Arc<Program>, xp: &mut dtvault_types::shibafu528::dtvault::Program) {
@@ -30,21 +82,136 @@ impl ProgramService {
 
         for video in videos {
             let video = match video {
-                Some(video) if !video.thumbnail.is_empty() => video,
+                Some(video) if video.thumbnail_blob.is_some() => video,
                 _ => continue,
             };
 
-            xp.thumbnail = video.thumbnail.clone();
-            xp.thumbnail_mime_type = video
-                .thumbnail_mime_type
-                .as_ref()
-                .map_or_else(|| "", |v| v.essence_str())
-                .to_string();
+            xp.thumbnail_id = video.stringify_id();
             break;
         }
     }
 }
 
+fn map_persist_error(e: PersistError) -> Status {
+    match e {
+        PersistError::IoError(e) => Status::internal(format!("IO error: {}", e)),
+        PersistError::Poisoned(e) => Status::aborted(format!("{}", e)),
+    }
+}
+
+fn exchangeable_change(change: &Change) -> WatchChangesResponse {
+    WatchChangesResponse {
+        change: Some(ChangeEvent {
+            revision: change.revision,
+            kind: change.kind as i32,
+            program_id: Some(change.program_key.exchangeable()),
+            program: change.program.as_ref().map(|p| p.exchangeable()),
+            video: change.video.as_ref().map(|v| v.exchangeable()),
+        }),
+    }
+}
+
+fn exchangeable_series(series: &ProgramSeries) -> Series {
+    Series {
+        title: series.title.clone(),
+        program_count: series.programs.len() as u32,
+        first_start_at: series.programs.first().map(|(sp, _)| sp.start_at.to_timestamp()),
+        last_start_at: series.programs.last().map(|(sp, _)| sp.start_at.to_timestamp()),
+    }
+}
+
+fn exchangeable_service_entry(summary: &ServiceSummary) -> ServiceEntry {
+    ServiceEntry {
+        service: Some(match &summary.service {
+            Some(service) => service.exchangeable(),
+            None => dtvault_types::shibafu528::dtvault::Service {
+                network_id: summary.id.0 as u32,
+                service_id: summary.id.1 as u32,
+                ..Default::default()
+            },
+        }),
+        display_name: summary.display_name().to_string(),
+        sort_order: summary.setting.sort_order,
+        program_count: summary.coverage.program_count as u32,
+        first_start_at: summary.coverage.first_start_at.map(|t| t.to_timestamp()),
+        last_start_at: summary.coverage.last_start_at.map(|t| t.to_timestamp()),
+    }
+}
+
+fn exchangeable_channel_entry(summary: &ChannelSummary) -> ChannelEntry {
+    ChannelEntry {
+        channel: Some(summary.channel.exchangeable()),
+        display_name: summary.display_name().to_string(),
+        sort_order: summary.setting.sort_order,
+        services: summary
+            .services
+            .iter()
+            .map(|(nid, sid)| ServiceIdentity {
+                network_id: *nid as u32,
+                service_id: *sid as u32,
+            })
+            .collect(),
+        program_count: summary.coverage.program_count as u32,
+        first_start_at: summary.coverage.first_start_at.map(|t| t.to_timestamp()),
+        last_start_at: summary.coverage.last_start_at.map(|t| t.to_timestamp()),
+    }
+}
+
+fn exchangeable_usage(key: String, label: String, usage: &Usage) -> UsageEntry {
+    UsageEntry {
+        key,
+        label,
+        video_count: usage.video_count as u32,
+        total_bytes: usage.total_bytes,
+    }
+}
+
+/// 前後の空白を取り除いてから検証する。タグを受け取る全ての経路で使う。
+fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    tags.into_iter()
+        .map(|tag| {
+            let tag = tag.trim().to_string();
+            validate_tag(&tag)?;
+            Ok(tag)
+        })
+        .collect()
+}
+
+fn validate_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    if tags.is_empty() {
+        return Err("Missing value: tags".to_string());
+    }
+    normalize_tags(tags)
+}
+
+fn parse_order(order_by: i32) -> Option<ProgramOrder> {
+    match OrderBy::from_i32(order_by)? {
+        OrderBy::StartAt => Some(ProgramOrder::StartAt),
+        OrderBy::Name => Some(ProgramOrder::Name),
+        OrderBy::Service => Some(ProgramOrder::Service),
+        OrderBy::CreatedAt => Some(ProgramOrder::CreatedAt),
+    }
+}
+
+fn parse_condition(condition: &str, format: ConditionFormat) -> Result<Condition, String> {
+    if condition.len() > MAX_CONDITION_SIZE {
+        return Err("condition is too large".to_string());
+    }
+    let condition: Condition = match format {
+        ConditionFormat::Toml => toml::from_str(condition).map_err(|e| e.to_string())?,
+        ConditionFormat::Json => serde_json::from_str(condition).map_err(|e| e.to_string())?,
+    };
+    condition.validate()?;
+    if condition.requires_video() {
+        return Err("conditions about video cannot be used".to_string());
+    }
+    Ok(condition)
+}
+
+fn parse_program_fields(fields: &[i32]) -> Option<Vec<ProgramField>> {
+    fields.iter().map(|f| ProgramField::from_i32(*f)).collect()
+}
+
 #[tonic::async_trait]
 impl ProgramServiceTrait for ProgramService {
     async fn get_program(&self, request: Request<GetProgramRequest>) -> Result<Response<GetProgramResponse>, Status> {
@@ -59,7 +226,7 @@ impl ProgramServiceTrait for ProgramService {
         }?;
 
         let program_key = ProgramKey::from_program_id(&program_id);
-        match self.store.find(&program_key) {
+        match self.store.find_with_alias(&program_key) {
             Ok(result) => match result {
                 Some(sp) => {
                     let mut xp = sp.exchangeable();
@@ -74,10 +241,192 @@ impl ProgramServiceTrait for ProgramService {
 
     async fn list_programs(
         &self,
-        _request: Request<ListProgramsRequest>,
+        request: Request<ListProgramsRequest>,
     ) -> Result<Response<ListProgramsResponse>, Status> {
-        let programs = self.store.all().map_err(|e| Status::aborted(format!("{}", e)))?;
+        let msg = request.into_inner();
+
+        if msg.page_size < 0 {
+            return Err(Status::invalid_argument("Invalid value: page_size"));
+        }
+        let order = match parse_order(msg.order_by) {
+            Some(order) => order,
+            None => return Err(Status::invalid_argument("Invalid value: order_by")),
+        };
+        let after = if msg.page_token.is_empty() {
+            None
+        } else {
+            match PageCursor::decode(&msg.page_token, order, msg.descending) {
+                Ok(cursor) => Some(cursor),
+                Err(e) => return Err(Status::invalid_argument(format!("Invalid value: page_token ({})", e))),
+            }
+        };
+        let mut channel_types = vec![];
+        for ct in msg.channel_types {
+            match ChannelType::from_exchanged(ct) {
+                Some(ct) => channel_types.push(ct),
+                None => return Err(Status::invalid_argument("Invalid value: channel_types")),
+            }
+        }
+
+        let query = ProgramQuery {
+            filter: ProgramFilter {
+                services: msg
+                    .services
+                    .iter()
+                    .map(|s| (s.network_id as u16, s.service_id as u16))
+                    .collect(),
+                channel_types,
+                start_at_from: msg.start_at_from.map(|t| t.to_utc()),
+                start_at_to: msg.start_at_to.map(|t| t.to_utc()),
+                title: msg.title,
+                tags: msg.tags,
+                condition: None,
+                trashed: msg.trashed,
+            },
+            order,
+            descending: msg.descending,
+            after,
+            limit: match msg.page_size as usize {
+                0 => None,
+                n => Some(n.min(MAX_PAGE_SIZE)),
+            },
+        };
+
+        let page = self
+            .store
+            .query(&query)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
         let res = ListProgramsResponse {
+            programs: page
+                .programs
+                .iter()
+                .map(|sp| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    xp
+                })
+                .collect(),
+            next_page_token: page.next_cursor.map_or_else(String::new, |c| c.encode()),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn query_programs(
+        &self,
+        request: Request<QueryProgramsRequest>,
+    ) -> Result<Response<QueryProgramsResponse>, Status> {
+        let msg = request.into_inner();
+
+        let condition = match msg.query {
+            Some(Query::Condition(condition)) => {
+                let format = match ConditionFormat::from_i32(msg.format) {
+                    Some(f) => f,
+                    None => return Err(Status::invalid_argument("Invalid value: format")),
+                };
+                match parse_condition(&condition, format) {
+                    Ok(c) => Arc::new(c),
+                    Err(e) => return Err(Status::invalid_argument(format!("Invalid value: condition ({})", e))),
+                }
+            }
+            Some(Query::SavedSearch(name)) => match self.config.saved_searches.iter().find(|s| s.name == name) {
+                Some(search) => search.condition.clone(),
+                None => return Err(Status::not_found(format!("Saved search not found (name = {})", name))),
+            },
+            None => return Err(Status::invalid_argument("Missing value: query")),
+        };
+        if msg.page_size < 0 {
+            return Err(Status::invalid_argument("Invalid value: page_size"));
+        }
+        let order = match parse_order(msg.order_by) {
+            Some(order) => order,
+            None => return Err(Status::invalid_argument("Invalid value: order_by")),
+        };
+        let after = if msg.page_token.is_empty() {
+            None
+        } else {
+            match PageCursor::decode(&msg.page_token, order, msg.descending) {
+                Ok(cursor) => Some(cursor),
+                Err(e) => return Err(Status::invalid_argument(format!("Invalid value: page_token ({})", e))),
+            }
+        };
+
+        let query = ProgramQuery {
+            filter: ProgramFilter {
+                condition: Some(condition),
+                trashed: msg.trashed,
+                ..Default::default()
+            },
+            order,
+            descending: msg.descending,
+            after,
+            limit: match msg.page_size as usize {
+                0 => None,
+                n => Some(n.min(MAX_PAGE_SIZE)),
+            },
+        };
+
+        let page = self
+            .store
+            .query(&query)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        let res = QueryProgramsResponse {
+            programs: page
+                .programs
+                .iter()
+                .map(|sp| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    xp
+                })
+                .collect(),
+            next_page_token: page.next_cursor.map_or_else(String::new, |c| c.encode()),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn list_saved_searches(
+        &self,
+        _request: Request<ListSavedSearchesRequest>,
+    ) -> Result<Response<ListSavedSearchesResponse>, Status> {
+        let res = ListSavedSearchesResponse {
+            saved_searches: self
+                .config
+                .saved_searches
+                .iter()
+                .map(|s| SavedSearch {
+                    name: s.name.clone(),
+                    description: s.description.clone(),
+                })
+                .collect(),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn search_programs(
+        &self,
+        request: Request<SearchProgramsRequest>,
+    ) -> Result<Response<SearchProgramsResponse>, Status> {
+        let msg = request.into_inner();
+
+        if msg.query.trim().is_empty() {
+            return Err(Status::invalid_argument("Invalid value: query"));
+        }
+        if msg.query.len() > 1024 {
+            return Err(Status::invalid_argument("String too long: query"));
+        }
+        if msg.limit < 0 {
+            return Err(Status::invalid_argument("Invalid value: limit"));
+        }
+        let limit = match msg.limit as usize {
+            0 => None,
+            n => Some(n),
+        };
+
+        let programs = self
+            .store
+            .search(&msg.query, limit)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        let res = SearchProgramsResponse {
             programs: programs
                 .iter()
                 .map(|sp| {
@@ -90,12 +439,98 @@ impl ProgramServiceTrait for ProgramService {
         Ok(Response::new(res))
     }
 
+    async fn list_series(&self, _request: Request<ListSeriesRequest>) -> Result<Response<ListSeriesResponse>, Status> {
+        let series = self
+            .store
+            .list_series()
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(ListSeriesResponse {
+            series: series.iter().map(exchangeable_series).collect(),
+        }))
+    }
+
+    async fn list_programs_by_series(
+        &self,
+        request: Request<ListProgramsBySeriesRequest>,
+    ) -> Result<Response<ListProgramsBySeriesResponse>, Status> {
+        let msg = request.into_inner();
+
+        if msg.series_title.is_empty() {
+            return Err(Status::invalid_argument("Invalid value: series_title"));
+        }
+
+        let series = match self.store.find_series(&msg.series_title) {
+            Ok(Some(series)) => Ok(series),
+            Ok(None) => Err(Status::not_found(format!(
+                "Series not found (title = {})",
+                msg.series_title
+            ))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(ListProgramsBySeriesResponse {
+            series: Some(exchangeable_series(&series)),
+            episodes: series
+                .programs
+                .iter()
+                .map(|(sp, parsed)| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    SeriesEpisode {
+                        program: Some(xp),
+                        number: parsed.number.unwrap_or(0),
+                        subtitle: parsed.subtitle.clone(),
+                    }
+                })
+                .collect(),
+        }))
+    }
+
+    async fn find_duplicate_programs(
+        &self,
+        request: Request<FindDuplicateProgramsRequest>,
+    ) -> Result<Response<FindDuplicateProgramsResponse>, Status> {
+        let msg = request.into_inner();
+
+        let groups = self
+            .store
+            .find_duplicates()
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        let marked_count = if msg.mark_metadata {
+            self.store
+                .mark_duplicates(&groups, DUPLICATE_GROUP_METADATA_KEY)
+                .map_err(map_persist_error)?
+        } else {
+            0
+        };
+
+        let res = FindDuplicateProgramsResponse {
+            groups: groups
+                .iter()
+                .map(|g| DuplicateProgramGroup {
+                    id: g.id(),
+                    reason: g.reason as i32,
+                    programs: g
+                        .programs
+                        .iter()
+                        .map(|sp| {
+                            let mut xp = sp.exchangeable();
+                            self.assign_thumbnail(sp.clone(), &mut xp);
+                            xp
+                        })
+                        .collect(),
+                })
+                .collect(),
+            marked_count: marked_count as u32,
+        };
+        Ok(Response::new(res))
+    }
+
     async fn create_program(
         &self,
         request: Request<CreateProgramRequest>,
     ) -> Result<Response<CreateProgramResponse>, Status> {
         let msg = request.into_inner();
-        let program = match msg.program {
+        let mut program = match msg.program {
             Some(p) => Ok(p),
             None => Err(Status::invalid_argument("Missing value: program".to_string())),
         }?;
@@ -118,21 +553,24 @@ impl ProgramServiceTrait for ProgramService {
             }
         }
 
+        program.tags = normalize_tags(program.tags)
+            .map_err(|msg| Status::invalid_argument(format!("Violation in tags => {}", msg)))?;
+
         println!("Accept => {:#?}", program);
         let res = match self.store.find_or_create(program) {
             Ok((sp, notice)) => Ok(CreateProgramResponse {
                 status: notice.into(),
                 program: Some(sp.exchangeable()),
             }),
-            Err(e) => Err(Status::aborted(format!("{}", e))),
+            Err(e) => Err(map_persist_error(e)),
         }?;
         Ok(Response::new(res))
     }
 
-    async fn get_program_metadata(
+    async fn update_program(
         &self,
-        request: Request<GetProgramMetadataRequest>,
-    ) -> Result<Response<GetProgramMetadataResponse>, Status> {
+        request: Request<UpdateProgramRequest>,
+    ) -> Result<Response<UpdateProgramResponse>, Status> {
         let msg = request.into_inner();
 
         let program_id = match msg.program_id {
@@ -142,70 +580,89 @@ impl ProgramServiceTrait for ProgramService {
             },
             None => Err(Status::invalid_argument("Missing value: program_id")),
         }?;
-        if msg.key.is_empty() {
-            return Err(Status::invalid_argument("Invalid value: key"));
+        let program = match msg.program {
+            Some(p) => Ok(p),
+            None => Err(Status::invalid_argument("Missing value: program")),
+        }?;
+        let fields =
+            parse_program_fields(&msg.fields).ok_or_else(|| Status::invalid_argument("Invalid value: fields"))?;
+        if fields.is_empty() {
+            return Err(Status::invalid_argument("Missing value: fields"));
         }
-        if msg.key.len() > 255 {
-            return Err(Status::invalid_argument("String too long: key"));
+
+        if fields.contains(&ProgramField::Name) && program.name.is_empty() {
+            return Err(Status::invalid_argument("Invalid value: name"));
+        }
+        if fields.contains(&ProgramField::Service) {
+            if let Some(service) = &program.service {
+                if let Err(msg) = validate_service(service) {
+                    return Err(Status::invalid_argument(format!("Violation in service => {}", msg)));
+                }
+                // サービスの識別子は ProgramKey の一部なので変更できない
+                if service.network_id != program_id.network_id || service.service_id != program_id.service_id {
+                    return Err(Status::invalid_argument(
+                        "Violation in service => network_id and service_id must match program_id",
+                    ));
+                }
+            }
         }
+        let values = fields
+            .into_iter()
+            .map(|f| FieldValue::from_exchanged(f, &program))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
 
         let program_key = ProgramKey::from_program_id(&program_id);
-        let response = match self.store.find(&program_key) {
-            Ok(result) => match result {
-                Some(sp) => {
-                    let default = "".to_string();
-                    let value = sp.metadata().get(&msg.key).unwrap_or(&default);
-                    Ok(Response::new(GetProgramMetadataResponse {
-                        program_id: Some(program_id),
-                        key: msg.key,
-                        value: value.to_string(),
-                    }))
-                }
-                None => Err(Status::not_found(format!("Program not found (id = {})", program_key))),
-            },
+        let sp = match self.store.update_program(&program_key, values, Utc::now()) {
+            Ok(sp) => Ok(sp),
+            Err(ProgramUpdateError::ProgramNotFound(key)) => {
+                Err(Status::not_found(format!("Program not found (id = {})", key)))
+            }
+            Err(ProgramUpdateError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
             Err(e) => Err(Status::aborted(format!("{}", e))),
-        };
-        response
+        }?;
+        Ok(Response::new(UpdateProgramResponse {
+            program: Some(sp.exchangeable()),
+        }))
     }
 
-    async fn update_program_metadata(
+    async fn revert_program(
         &self,
-        request: Request<UpdateProgramMetadataRequest>,
-    ) -> Result<Response<UpdateProgramMetadataResponse>, Status> {
+        request: Request<RevertProgramRequest>,
+    ) -> Result<Response<RevertProgramResponse>, Status> {
         let msg = request.into_inner();
 
         let program_id = match msg.program_id {
             Some(program_id) => match validate_program_id(&program_id) {
                 Ok(_) => Ok(program_id),
-                Err(msg) => Err(Status::failed_precondition(format!(
-                    "Violation in program_id => {}",
-                    msg
-                ))),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
             },
             None => Err(Status::invalid_argument("Missing value: program_id")),
         }?;
-        if msg.key.is_empty() {
-            return Err(Status::invalid_argument("Invalid value: key"));
-        }
-        if msg.key.len() > 255 {
-            return Err(Status::invalid_argument("String too long: key"));
-        }
-        if msg.value.len() > 1 * 1024 * 1024 {
-            return Err(Status::invalid_argument("String too long: value"));
+        let mut fields =
+            parse_program_fields(&msg.fields).ok_or_else(|| Status::invalid_argument("Invalid value: fields"))?;
+        if fields.is_empty() {
+            fields = ProgramField::ALL.to_vec();
         }
 
         let program_key = ProgramKey::from_program_id(&program_id);
-        let response = match self.store.update_program_metadata(&program_key, &msg.key, &msg.value) {
-            Ok(_) => Ok(Response::new(UpdateProgramMetadataResponse {})),
+        let sp = match self.store.revert_program(&program_key, &fields, Utc::now()) {
+            Ok(sp) => Ok(sp),
+            Err(ProgramUpdateError::ProgramNotFound(key)) => {
+                Err(Status::not_found(format!("Program not found (id = {})", key)))
+            }
+            Err(ProgramUpdateError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
             Err(e) => Err(Status::aborted(format!("{}", e))),
-        };
-        response
+        }?;
+        Ok(Response::new(RevertProgramResponse {
+            program: Some(sp.exchangeable()),
+        }))
     }
 
-    async fn list_videos_by_program(
+    async fn get_program_history(
         &self,
-        request: Request<ListVideosByProgramRequest>,
-    ) -> Result<Response<ListVideosByProgramResponse>, Status> {
+        request: Request<GetProgramHistoryRequest>,
+    ) -> Result<Response<GetProgramHistoryResponse>, Status> {
         let msg = request.into_inner();
 
         let program_id = match msg.program_id {
@@ -217,15 +674,975 @@ impl ProgramServiceTrait for ProgramService {
         }?;
 
         let program_key = ProgramKey::from_program_id(&program_id);
-        match self.store.find(&program_key) {
-            Ok(Some(sp)) => match self.store.find_videos(sp.video_ids()) {
-                Ok(videos) => Ok(Response::new(ListVideosByProgramResponse {
-                    videos: videos.into_iter().filter_map(|v| v).map(|v| v.exchangeable()).collect(),
-                })),
-                Err(e) => Err(Status::aborted(format!("{}", e))),
+        let sp = match self.store.find(&program_key) {
+            Ok(Some(sp)) => Ok(sp),
+            Ok(None) => Err(Status::not_found(format!("Program not found (id = {})", program_key))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(GetProgramHistoryResponse {
+            original: Some(sp.original().exchangeable()),
+            edits: sp.edits().iter().map(|e| e.exchangeable()).collect(),
+        }))
+    }
+
+    async fn merge_programs(
+        &self,
+        request: Request<MergeProgramsRequest>,
+    ) -> Result<Response<MergeProgramsResponse>, Status> {
+        let msg = request.into_inner();
+
+        let source_program_id = match msg.source_program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!(
+                    "Violation in source_program_id => {}",
+                    msg
+                ))),
+            },
+            None => Err(Status::invalid_argument("Missing value: source_program_id")),
+        }?;
+        let target_program_id = match msg.target_program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!(
+                    "Violation in target_program_id => {}",
+                    msg
+                ))),
+            },
+            None => Err(Status::invalid_argument("Missing value: target_program_id")),
+        }?;
+
+        let source_key = ProgramKey::from_program_id(&source_program_id);
+        let target_key = ProgramKey::from_program_id(&target_program_id);
+        match self.store.merge_programs(&source_key, &target_key) {
+            Ok(sp) => {
+                let mut xp = sp.exchangeable();
+                self.assign_thumbnail(sp.clone(), &mut xp);
+                Ok(Response::new(MergeProgramsResponse { program: Some(xp) }))
+            }
+            Err(e @ ProgramMergeError::ProgramNotFound(_)) => Err(Status::not_found(format!("{}", e))),
+            Err(e @ ProgramMergeError::SameProgram(_)) => Err(Status::invalid_argument(format!("{}", e))),
+            Err(ProgramMergeError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }
+    }
+
+    async fn delete_program(
+        &self,
+        request: Request<DeleteProgramRequest>,
+    ) -> Result<Response<DeleteProgramResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
             },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let sp = match self.store.find(&program_key) {
+            Ok(Some(sp)) => Ok(sp),
             Ok(None) => Err(Status::not_found(format!("Program not found (id = {})", program_key))),
             Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+
+        if msg.dry_run {
+            let videos = self
+                .store
+                .find_videos(sp.video_ids())
+                .map_err(|e| Status::aborted(format!("{}", e)))?;
+            let videos: Vec<_> = videos.into_iter().flatten().collect();
+            if msg.permanent {
+                for video in &videos {
+                    require_storage_by_id(&self.storages, video).await?;
+                }
+            }
+            return Ok(Response::new(DeleteProgramResponse {
+                program: Some(sp.exchangeable()),
+                videos: videos.iter().map(|v| v.exchangeable()).collect(),
+            }));
         }
+
+        let (sp, videos) = if msg.permanent {
+            let (sp, videos) = trash::purge_program(&self.store, &self.storages, &program_key).await?;
+            println!("DeleteProgram finish: {}", program_key);
+            (sp, videos)
+        } else {
+            let sp = self
+                .store
+                .trash_program(&program_key, Utc::now())
+                .map_err(trash::map_trash_error)?;
+            let videos = self
+                .store
+                .find_videos(sp.video_ids())
+                .map_err(|e| Status::aborted(format!("{}", e)))?;
+            println!("DeleteProgram moved to trash: {}", program_key);
+            (sp, videos.into_iter().flatten().collect())
+        };
+
+        Ok(Response::new(DeleteProgramResponse {
+            program: Some(sp.exchangeable()),
+            videos: videos.iter().map(|v| v.exchangeable()).collect(),
+        }))
+    }
+
+    async fn restore_program(
+        &self,
+        request: Request<RestoreProgramRequest>,
+    ) -> Result<Response<RestoreProgramResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let sp = self
+            .store
+            .restore_program(&program_key)
+            .map_err(trash::map_trash_error)?;
+        Ok(Response::new(RestoreProgramResponse {
+            program: Some(sp.exchangeable()),
+        }))
+    }
+
+    async fn empty_trash(&self, request: Request<EmptyTrashRequest>) -> Result<Response<EmptyTrashResponse>, Status> {
+        let msg = request.into_inner();
+
+        let report = trash::empty_trash(&self.store, &self.storages, Utc::now(), msg.dry_run).await?;
+        Ok(Response::new(EmptyTrashResponse {
+            programs: report.programs.iter().map(|p| p.exchangeable()).collect(),
+            videos: report.videos.iter().map(|v| v.exchangeable()).collect(),
+            failures: report.failures,
+        }))
+    }
+
+    async fn get_program_metadata(
+        &self,
+        request: Request<GetProgramMetadataRequest>,
+    ) -> Result<Response<GetProgramMetadataResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+        if msg.key.is_empty() {
+            return Err(Status::invalid_argument("Invalid value: key"));
+        }
+        if msg.key.len() > 255 {
+            return Err(Status::invalid_argument("String too long: key"));
+        }
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let response = match self.store.find(&program_key) {
+            Ok(result) => match result {
+                Some(sp) => {
+                    let default = "".to_string();
+                    let value = sp.metadata().get(&msg.key).unwrap_or(&default);
+                    Ok(Response::new(GetProgramMetadataResponse {
+                        program_id: Some(program_id),
+                        key: msg.key,
+                        value: value.to_string(),
+                    }))
+                }
+                None => Err(Status::not_found(format!("Program not found (id = {})", program_key))),
+            },
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        };
+        response
+    }
+
+    async fn update_program_metadata(
+        &self,
+        request: Request<UpdateProgramMetadataRequest>,
+    ) -> Result<Response<UpdateProgramMetadataResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::failed_precondition(format!(
+                    "Violation in program_id => {}",
+                    msg
+                ))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+        if msg.key.is_empty() {
+            return Err(Status::invalid_argument("Invalid value: key"));
+        }
+        if msg.key.len() > 255 {
+            return Err(Status::invalid_argument("String too long: key"));
+        }
+        if msg.value.len() > 1 * 1024 * 1024 {
+            return Err(Status::invalid_argument("String too long: value"));
+        }
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let response = match self.store.update_program_metadata(&program_key, &msg.key, &msg.value) {
+            Ok(_) => Ok(Response::new(UpdateProgramMetadataResponse {})),
+            Err(MetadataWriteError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        };
+        response
+    }
+
+    async fn list_program_metadata(
+        &self,
+        request: Request<ListProgramMetadataRequest>,
+    ) -> Result<Response<ListProgramMetadataResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let sp = match self.store.find(&program_key) {
+            Ok(Some(sp)) => Ok(sp),
+            Ok(None) => Err(Status::not_found(format!("Program not found (id = {})", program_key))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        let mut keys: Vec<String> = sp.metadata().keys().cloned().collect();
+        keys.sort();
+        Ok(Response::new(ListProgramMetadataResponse {
+            program_id: Some(program_id),
+            keys,
+        }))
+    }
+
+    async fn delete_program_metadata(
+        &self,
+        request: Request<DeleteProgramMetadataRequest>,
+    ) -> Result<Response<DeleteProgramMetadataResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+        validate_metadata_key(&msg.key).map_err(Status::invalid_argument)?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let deleted = match self.store.delete_program_metadata(&program_key, &msg.key) {
+            Ok(deleted) => Ok(deleted),
+            Err(MetadataWriteError::ProgramNotFound(key)) => {
+                Err(Status::not_found(format!("Program not found (id = {})", key)))
+            }
+            Err(MetadataWriteError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(DeleteProgramMetadataResponse { deleted }))
+    }
+
+    async fn batch_update_program_metadata(
+        &self,
+        request: Request<BatchUpdateProgramMetadataRequest>,
+    ) -> Result<Response<BatchUpdateProgramMetadataResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+        if msg.values.is_empty() && msg.delete_keys.is_empty() {
+            return Err(Status::invalid_argument("Missing value: values or delete_keys"));
+        }
+        for (key, value) in &msg.values {
+            validate_metadata_key(key)
+                .map_err(|msg| Status::invalid_argument(format!("Violation in values => {}", msg)))?;
+            validate_metadata_value(value)
+                .map_err(|msg| Status::invalid_argument(format!("Violation in values => {}", msg)))?;
+        }
+        for key in &msg.delete_keys {
+            validate_metadata_key(key)
+                .map_err(|msg| Status::invalid_argument(format!("Violation in delete_keys => {}", msg)))?;
+            if msg.values.contains_key(key) {
+                return Err(Status::invalid_argument(format!(
+                    "Key `{}` is specified in both values and delete_keys",
+                    key
+                )));
+            }
+        }
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        match self
+            .store
+            .batch_update_program_metadata(&program_key, &msg.values, &msg.delete_keys)
+        {
+            Ok(_) => Ok(Response::new(BatchUpdateProgramMetadataResponse {})),
+            Err(MetadataWriteError::ProgramNotFound(key)) => {
+                Err(Status::not_found(format!("Program not found (id = {})", key)))
+            }
+            Err(MetadataWriteError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }
+    }
+
+    async fn find_programs_by_metadata(
+        &self,
+        request: Request<FindProgramsByMetadataRequest>,
+    ) -> Result<Response<FindProgramsByMetadataResponse>, Status> {
+        let msg = request.into_inner();
+
+        validate_metadata_key(&msg.key).map_err(Status::invalid_argument)?;
+        if msg.limit < 0 {
+            return Err(Status::invalid_argument("Invalid value: limit"));
+        }
+        let limit = match msg.limit as usize {
+            0 => None,
+            n => Some(n),
+        };
+
+        let programs = self
+            .store
+            .find_programs_by_metadata(&msg.key, msg.value.as_deref(), limit)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(FindProgramsByMetadataResponse {
+            programs: programs
+                .iter()
+                .map(|sp| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    xp
+                })
+                .collect(),
+        }))
+    }
+
+    async fn add_tags(&self, request: Request<AddTagsRequest>) -> Result<Response<AddTagsResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+        let tags =
+            validate_tags(msg.tags).map_err(|msg| Status::invalid_argument(format!("Violation in tags => {}", msg)))?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let sp = match self.store.add_tags(&program_key, &tags) {
+            Ok(sp) => Ok(sp),
+            Err(ProgramUpdateError::ProgramNotFound(key)) => {
+                Err(Status::not_found(format!("Program not found (id = {})", key)))
+            }
+            Err(ProgramUpdateError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(AddTagsResponse {
+            program: Some(sp.exchangeable()),
+        }))
+    }
+
+    async fn remove_tags(&self, request: Request<RemoveTagsRequest>) -> Result<Response<RemoveTagsResponse>, Status> {
+        let msg = request.into_inner();
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+        let tags =
+            validate_tags(msg.tags).map_err(|msg| Status::invalid_argument(format!("Violation in tags => {}", msg)))?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        let sp = match self.store.remove_tags(&program_key, &tags) {
+            Ok(sp) => Ok(sp),
+            Err(ProgramUpdateError::ProgramNotFound(key)) => {
+                Err(Status::not_found(format!("Program not found (id = {})", key)))
+            }
+            Err(ProgramUpdateError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(RemoveTagsResponse {
+            program: Some(sp.exchangeable()),
+        }))
+    }
+
+    async fn list_tags(&self, _request: Request<ListTagsRequest>) -> Result<Response<ListTagsResponse>, Status> {
+        let tags = self.store.tags().map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(ListTagsResponse {
+            tags: tags
+                .into_iter()
+                .map(|(name, count)| list_tags_response::Tag {
+                    name,
+                    program_count: count as u32,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn list_videos_by_program(
+        &self,
+        request: Request<ListVideosByProgramRequest>,
+    ) -> Result<Response<ListVideosByProgramResponse>, Status> {
+        let msg = request.into_inner();
+        let include_trashed = msg.include_trashed;
+
+        let program_id = match msg.program_id {
+            Some(program_id) => match validate_program_id(&program_id) {
+                Ok(_) => Ok(program_id),
+                Err(msg) => Err(Status::invalid_argument(format!("Violation in program_id => {}", msg))),
+            },
+            None => Err(Status::invalid_argument("Missing value: program_id")),
+        }?;
+
+        let program_key = ProgramKey::from_program_id(&program_id);
+        match self.store.find(&program_key) {
+            Ok(Some(sp)) => match self.store.find_videos(sp.video_ids()) {
+                Ok(videos) => Ok(Response::new(ListVideosByProgramResponse {
+                    videos: videos
+                        .into_iter()
+                        .filter_map(|v| v)
+                        .filter(|v| include_trashed || v.trashed_at.is_none())
+                        .map(|v| v.exchangeable())
+                        .collect(),
+                })),
+                Err(e) => Err(Status::aborted(format!("{}", e))),
+            },
+            Ok(None) => Err(Status::not_found(format!("Program not found (id = {})", program_key))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }
+    }
+
+    async fn find_videos_by_provider_id(
+        &self,
+        request: Request<FindVideosByProviderIdRequest>,
+    ) -> Result<Response<FindVideosByProviderIdResponse>, Status> {
+        let msg = request.into_inner();
+
+        let videos = self
+            .store
+            .find_videos_by_provider_id(&msg.provider_id, msg.include_trashed)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(FindVideosByProviderIdResponse {
+            videos: videos.iter().map(|v| v.exchangeable()).collect(),
+        }))
+    }
+
+    async fn list_videos_by_storage(
+        &self,
+        request: Request<ListVideosByStorageRequest>,
+    ) -> Result<Response<ListVideosByStorageResponse>, Status> {
+        let msg = request.into_inner();
+
+        let storage_id =
+            Uuid::parse_str(&msg.storage_id).map_err(|_| Status::invalid_argument("Invalid value: storage_id"))?;
+        let videos = self
+            .store
+            .find_videos_by_storage_id(&storage_id, msg.include_trashed)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(ListVideosByStorageResponse {
+            videos: videos.iter().map(|v| v.exchangeable()).collect(),
+        }))
+    }
+
+    async fn list_programs_by_service(
+        &self,
+        request: Request<ListProgramsByServiceRequest>,
+    ) -> Result<Response<ListProgramsByServiceResponse>, Status> {
+        let msg = request.into_inner();
+
+        let id = (msg.network_id as u16, msg.service_id as u16);
+        let programs = self
+            .store
+            .find_programs_by_service(id, msg.include_trashed)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(ListProgramsByServiceResponse {
+            programs: programs
+                .iter()
+                .map(|sp| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    xp
+                })
+                .collect(),
+        }))
+    }
+
+    async fn list_programs_by_start_date(
+        &self,
+        request: Request<ListProgramsByStartDateRequest>,
+    ) -> Result<Response<ListProgramsByStartDateResponse>, Status> {
+        let msg = request.into_inner();
+
+        let programs = self
+            .store
+            .find_programs_by_start_at(
+                msg.start_at_from.map(|t| t.to_utc()),
+                msg.start_at_to.map(|t| t.to_utc()),
+                msg.include_trashed,
+            )
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(ListProgramsByStartDateResponse {
+            programs: programs
+                .iter()
+                .map(|sp| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    xp
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_watch_state(
+        &self,
+        request: Request<GetWatchStateRequest>,
+    ) -> Result<Response<GetWatchStateResponse>, Status> {
+        let msg = request.into_inner();
+
+        let video_id =
+            Uuid::parse_str(&msg.video_id).map_err(|_| Status::invalid_argument("Invalid value: video_id"))?;
+        let video = match self.store.find_video(&video_id) {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => Err(Status::not_found("Video not found")),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(GetWatchStateResponse {
+            watch_state: Some(video.watch_state.exchangeable()),
+        }))
+    }
+
+    async fn update_watch_state(
+        &self,
+        request: Request<UpdateWatchStateRequest>,
+    ) -> Result<Response<UpdateWatchStateResponse>, Status> {
+        let msg = request.into_inner();
+
+        let video_id =
+            Uuid::parse_str(&msg.video_id).map_err(|_| Status::invalid_argument("Invalid value: video_id"))?;
+        let position = match msg.position {
+            Some(position) if position.seconds < 0 || position.nanos < 0 => {
+                return Err(Status::invalid_argument("Invalid value: position"))
+            }
+            Some(position) => Some(position.to_duration()),
+            None => None,
+        };
+        let watched = match Watched::from_i32(msg.watched) {
+            Some(Watched::Unchanged) => None,
+            Some(Watched::Watched) => Some(true),
+            Some(Watched::Unwatched) => Some(false),
+            None => return Err(Status::invalid_argument("Invalid value: watched")),
+        };
+
+        let update = WatchStateUpdate {
+            started: msg.started,
+            position,
+            watched,
+        };
+        let video = match self.store.update_watch_state(&video_id, &update, Utc::now()) {
+            Ok(v) => Ok(v),
+            Err(WatchStateUpdateError::VideoNotFound(_)) => Err(Status::not_found("Video not found")),
+            Err(WatchStateUpdateError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+        Ok(Response::new(UpdateWatchStateResponse {
+            watch_state: Some(video.watch_state.exchangeable()),
+        }))
+    }
+
+    async fn list_unwatched_programs(
+        &self,
+        request: Request<ListUnwatchedProgramsRequest>,
+    ) -> Result<Response<ListUnwatchedProgramsResponse>, Status> {
+        let msg = request.into_inner();
+
+        if msg.limit < 0 {
+            return Err(Status::invalid_argument("Invalid value: limit"));
+        }
+        let limit = match msg.limit as usize {
+            0 => None,
+            n => Some(n),
+        };
+
+        let programs = self
+            .store
+            .unwatched_programs(limit)
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        Ok(Response::new(ListUnwatchedProgramsResponse {
+            programs: programs
+                .iter()
+                .map(|sp| {
+                    let mut xp = sp.exchangeable();
+                    self.assign_thumbnail(sp.clone(), &mut xp);
+                    xp
+                })
+                .collect(),
+        }))
+    }
+
+    type WatchChangesStream = ReceiverStream<Result<WatchChangesResponse, Status>>;
+
+    async fn watch_changes(
+        &self,
+        request: Request<WatchChangesRequest>,
+    ) -> Result<Response<Self::WatchChangesStream>, Status> {
+        let msg = request.into_inner();
+
+        let (backlog, mut receiver) = match self.store.change_feed().subscribe(msg.after_revision) {
+            Ok(r) => Ok(r),
+            Err(e @ ResumeError::TooOld(_)) => Err(Status::out_of_range(format!("{}", e))),
+            Err(e @ ResumeError::Future(_)) => Err(Status::invalid_argument(format!("{}", e))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            for change in backlog {
+                if tx.send(Ok(exchangeable_change(&change))).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let res = match receiver.recv().await {
+                    Ok(change) => Ok(exchangeable_change(&change)),
+                    Err(RecvError::Lagged(_)) => Err(Status::data_loss(
+                        "Client is too slow to receive changes, resume from the last received revision",
+                    )),
+                    Err(RecvError::Closed) => return,
+                };
+                let is_err = res.is_err();
+                if tx.send(res).await.is_err() || is_err {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn list_services(
+        &self,
+        request: Request<ListServicesRequest>,
+    ) -> Result<Response<ListServicesResponse>, Status> {
+        let msg = request.into_inner();
+
+        let mut channel_types = vec![];
+        for ct in msg.channel_types {
+            match ChannelType::from_exchanged(ct) {
+                Some(ct) => channel_types.push(ct),
+                None => return Err(Status::invalid_argument("Invalid value: channel_types")),
+            }
+        }
+
+        let services = self
+            .store
+            .list_services()
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        let res = ListServicesResponse {
+            services: services
+                .iter()
+                .filter(|s| channel_types.is_empty() || s.channel_type().is_some_and(|ct| channel_types.contains(&ct)))
+                .map(exchangeable_service_entry)
+                .collect(),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn get_service(&self, request: Request<GetServiceRequest>) -> Result<Response<GetServiceResponse>, Status> {
+        let msg = request.into_inner();
+
+        let id = (msg.network_id as u16, msg.service_id as u16);
+        match self.store.find_service(id) {
+            Ok(Some(service)) => Ok(Response::new(GetServiceResponse {
+                service: Some(exchangeable_service_entry(&service)),
+            })),
+            Ok(None) => Err(Status::not_found(format!(
+                "Service not found (network_id = {}, service_id = {})",
+                msg.network_id, msg.service_id
+            ))),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }
+    }
+
+    async fn update_service(
+        &self,
+        request: Request<UpdateServiceRequest>,
+    ) -> Result<Response<UpdateServiceResponse>, Status> {
+        let msg = request.into_inner();
+
+        let id = (msg.network_id as u16, msg.service_id as u16);
+        let setting = DisplaySetting {
+            display_name: msg.display_name.trim().to_string(),
+            sort_order: msg.sort_order,
+        };
+        match self.store.update_service(id, setting) {
+            Ok(Some(service)) => Ok(Response::new(UpdateServiceResponse {
+                service: Some(exchangeable_service_entry(&service)),
+            })),
+            Ok(None) => Err(Status::not_found(format!(
+                "Service not found (network_id = {}, service_id = {})",
+                msg.network_id, msg.service_id
+            ))),
+            Err(e) => Err(map_persist_error(e)),
+        }
+    }
+
+    async fn list_channels(
+        &self,
+        _request: Request<ListChannelsRequest>,
+    ) -> Result<Response<ListChannelsResponse>, Status> {
+        let channels = self
+            .store
+            .list_channels()
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        let res = ListChannelsResponse {
+            channels: channels.iter().map(exchangeable_channel_entry).collect(),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn update_channel(
+        &self,
+        request: Request<UpdateChannelRequest>,
+    ) -> Result<Response<UpdateChannelResponse>, Status> {
+        let msg = request.into_inner();
+
+        let channel_type = match ChannelType::from_exchanged(msg.channel_type) {
+            Some(ct) => ct,
+            None => return Err(Status::invalid_argument("Invalid value: channel_type")),
+        };
+        if msg.channel.is_empty() {
+            return Err(Status::invalid_argument("Missing value: channel"));
+        }
+
+        let setting = DisplaySetting {
+            display_name: msg.display_name.trim().to_string(),
+            sort_order: msg.sort_order,
+        };
+        match self.store.update_channel((channel_type, msg.channel.clone()), setting) {
+            Ok(Some(channel)) => Ok(Response::new(UpdateChannelResponse {
+                channel: Some(exchangeable_channel_entry(&channel)),
+            })),
+            Ok(None) => Err(Status::not_found(format!(
+                "Channel not found (channel_type = {}, channel = {})",
+                channel_type, msg.channel
+            ))),
+            Err(e) => Err(map_persist_error(e)),
+        }
+    }
+
+    async fn get_statistics(
+        &self,
+        _request: Request<GetStatisticsRequest>,
+    ) -> Result<Response<GetStatisticsResponse>, Status> {
+        let stats = self.store.statistics().map_err(|e| Status::aborted(format!("{}", e)))?;
+        let services = self
+            .store
+            .list_services()
+            .map_err(|e| Status::aborted(format!("{}", e)))?;
+        let mut storage_labels = HashMap::new();
+        for storage in &self.storages {
+            if let Ok(id) = storage.storage_id().await {
+                storage_labels.insert(id, storage.label().to_string());
+            }
+        }
+
+        let res = GetStatisticsResponse {
+            program_count: stats.program_count as u32,
+            video_count: stats.total.video_count as u32,
+            total_bytes: stats.total.total_bytes,
+            videos_without_thumbnail: stats.videos_without_thumbnail as u32,
+            trashed: Some(exchangeable_usage(String::new(), String::new(), &stats.trashed)),
+            by_storage: stats
+                .by_storage
+                .iter()
+                .map(|(id, usage)| {
+                    let label = storage_labels.get(id).cloned().unwrap_or_default();
+                    exchangeable_usage(id.to_string(), label, usage)
+                })
+                .collect(),
+            by_channel_type: stats
+                .by_channel_type
+                .iter()
+                .map(|(ct, usage)| {
+                    let key = ct.map_or_else(String::new, |ct| ct.to_string());
+                    exchangeable_usage(key, String::new(), usage)
+                })
+                .collect(),
+            by_service: stats
+                .by_service
+                .iter()
+                .map(|((nid, sid), usage)| {
+                    let label = services
+                        .iter()
+                        .find(|s| s.id == (*nid, *sid))
+                        .map_or_else(String::new, |s| s.display_name().to_string());
+                    exchangeable_usage(format!("{}-{}", nid, sid), label, usage)
+                })
+                .collect(),
+            by_provider: stats
+                .by_provider
+                .iter()
+                .map(|(provider_id, usage)| exchangeable_usage(provider_id.clone(), String::new(), usage))
+                .collect(),
+            by_month: stats
+                .by_month
+                .iter()
+                .map(|((year, month), usage)| {
+                    exchangeable_usage(format!("{:04}-{:02}", year, month), String::new(), usage)
+                })
+                .collect(),
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn get_thumbnail(
+        &self,
+        request: Request<GetThumbnailRequest>,
+    ) -> Result<Response<GetThumbnailResponse>, Status> {
+        let msg = request.into_inner();
+
+        let video_id = match Uuid::parse_str(&msg.thumbnail_id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid value: thumbnail_id")),
+        };
+        let size = match ThumbnailSize::from_exchanged(msg.size) {
+            Some(s) => s,
+            None => return Err(Status::invalid_argument("Invalid value: size")),
+        };
+
+        let video = match self.store.find_video(&video_id) {
+            Ok(Some(video)) if video.thumbnail_blob.is_some() => video,
+            Ok(_) => {
+                return Err(Status::not_found(format!(
+                    "Thumbnail not found (id = {})",
+                    msg.thumbnail_id
+                )))
+            }
+            Err(e) => return Err(Status::aborted(format!("{}", e))),
+        };
+        let mime_type = video
+            .thumbnail_mime_type
+            .as_ref()
+            .map_or_else(|| "", |v| v.essence_str())
+            .to_string();
+        // blob のキーは内容のダイジェストなので、そのまま ETag に使える
+        let original_digest = video.thumbnail_blob.clone().unwrap_or_default();
+
+        let mut served_size = size;
+        let mut etag = size.etag(&original_digest);
+        let data = if msg.if_none_match == etag {
+            None
+        } else if let Some(data) = self.thumbnails.get(video_id, size, &etag) {
+            Some((*data).clone())
+        } else {
+            let original = match self.store.read_blob(&original_digest) {
+                Ok(Some(data)) => data,
+                Ok(None) => {
+                    return Err(Status::internal(format!(
+                        "Thumbnail blob not found (key = {})",
+                        original_digest
+                    )))
+                }
+                Err(e) => return Err(Status::internal(format!("IO error: {}", e))),
+            };
+            let resized = match (size, self.config.outlet.encoder_url()) {
+                (ThumbnailSize::Original, _) | (_, None) => None,
+                (_, Some(encoder_url)) => match thumbnail::resize(encoder_url, &original, size).await {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        eprintln!("Failed to resize thumbnail (id = {}): {}", video_id, e);
+                        None
+                    }
+                },
+            };
+            match resized {
+                Some(data) => {
+                    self.thumbnails
+                        .insert(video_id, size, etag.clone(), Arc::new(data.clone()));
+                    Some(data)
+                }
+                None => {
+                    // 縮小できない場合は元のサイズで返す
+                    served_size = ThumbnailSize::Original;
+                    etag = original_digest;
+                    if msg.if_none_match == etag {
+                        None
+                    } else {
+                        Some(original)
+                    }
+                }
+            }
+        };
+
+        let res = GetThumbnailResponse {
+            not_modified: data.is_none(),
+            data: data.unwrap_or_default(),
+            mime_type,
+            etag,
+            size: served_size.exchangeable() as i32,
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn create_backup(
+        &self,
+        request: Request<CreateBackupRequest>,
+    ) -> Result<Response<CreateBackupResponse>, Status> {
+        let msg = request.into_inner();
+
+        let config = self.config.clone();
+        let store = self.store.clone();
+        let info = tokio::task::spawn_blocking(move || backup::create_backup(&config, &store, msg.include_thumbnails))
+            .await
+            .map_err(|e| Status::internal(format!("{}", e)))?
+            .map_err(map_backup_error)?;
+        Ok(Response::new(CreateBackupResponse {
+            name: info.name,
+            revision: info.revision,
+            program_count: info.program_count as u32,
+            video_count: info.video_count as u32,
+            thumbnail_count: info.thumbnail_count as u32,
+            size: info.size,
+        }))
+    }
+
+    async fn restore_backup(
+        &self,
+        request: Request<RestoreBackupRequest>,
+    ) -> Result<Response<RestoreBackupResponse>, Status> {
+        let msg = request.into_inner();
+        if msg.name.is_empty() {
+            return Err(Status::invalid_argument("Missing value: name"));
+        }
+
+        let config = self.config.clone();
+        let store = self.store.clone();
+        let report = tokio::task::spawn_blocking(move || backup::restore_backup(&config, &store, &msg.name))
+            .await
+            .map_err(|e| Status::internal(format!("{}", e)))?
+            .map_err(map_backup_error)?;
+        Ok(Response::new(RestoreBackupResponse {
+            revision: report.revision,
+            program_count: report.program_count as u32,
+            video_count: report.video_count as u32,
+        }))
     }
 }
@@ -0,0 +1,70 @@
+use crate::config::{BackendKind, Database};
+use crate::program::InitializeError;
+use dtvault_types::shibafu528::dtvault::central::persist_log_entry::Entry;
+use dtvault_types::shibafu528::dtvault::central::{PersistLogEntry, PersistStore};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+mod file;
+mod sqlite;
+
+pub use self::file::FileBackend;
+pub use self::sqlite::SqliteBackend;
+
+/// バックエンドから読み込んだ内容
+pub struct LoadedData {
+    /// 保存されている全体。まだ何も保存されていない場合は None
+    pub snapshot: Option<PersistStore>,
+    /// snapshot より後に記録された変更
+    pub journal: Vec<PersistLogEntry>,
+}
+
+/// ProgramStore の内容を永続化する方法。検索や索引はメモリ上の ProgramStore が受け持つ。
+pub trait DatabaseBackend: Send + Sync {
+    /// 保存されている内容を読み込む。起動時に一度だけ呼ぶ。
+    fn load(&self) -> Result<LoadedData, InitializeError>;
+
+    /// 変更を記録する。flush を呼ぶまで書き込まない。
+    fn record(&self, revision: u64, entry: Entry);
+
+    /// 記録した変更をまとめて書き込む。失敗した場合、変更は次の flush で再試行する。
+    fn flush(&self) -> io::Result<()>;
+
+    /// compact で書き直すべきかどうか
+    fn needs_compaction(&self) -> io::Result<bool>;
+
+    /// 保存されている内容の複製を、label を含む名前で作る。複製の場所を返す。
+    fn backup(&self, label: &str) -> io::Result<PathBuf>;
+
+    /// 全体を store の内容で書き直す。記録したまま書き込んでいない変更は store に含まれているものとして捨てる。
+    fn compact(&self, store: &PersistStore) -> io::Result<()>;
+
+    /// 古い世代や backup で作った複製など、残してある内容が参照している blob のキーを返す。
+    fn retained_blob_keys(&self) -> io::Result<HashSet<String>>;
+}
+
+pub fn open_backend(database: &Database, kind: BackendKind) -> Result<Box<dyn DatabaseBackend>, InitializeError> {
+    match kind {
+        BackendKind::File => Ok(Box::new(FileBackend::open(database)?)),
+        BackendKind::Sqlite => Ok(Box::new(SqliteBackend::open(&database.sqlite_file_path())?)),
+    }
+}
+
+/// backup で path の複製として作ったファイルを探す。
+fn backup_paths(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let prefix = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(vec![]),
+    };
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(path.parent().unwrap_or_else(|| Path::new(".")))? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if name.starts_with(&prefix) && name.ends_with(".bak") {
+                paths.push(entry.path());
+            }
+        }
+    }
+    Ok(paths)
+}
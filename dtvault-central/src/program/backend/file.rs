@@ -0,0 +1,131 @@
+use crate::config::Database;
+use crate::program::backend::{backup_paths, DatabaseBackend, LoadedData};
+use crate::program::{InitializeError, Journal};
+use dtvault_types::shibafu528::dtvault::central::persist_log_entry::Entry;
+use dtvault_types::shibafu528::dtvault::central::{PersistLogEntry, PersistStore};
+use prost::Message;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// スナップショット (programs.pb) と変更ログ (programs.log) に保存する。
+pub struct FileBackend {
+    data_dir: PathBuf,
+    snapshot_path: PathBuf,
+    temp_path: PathBuf,
+    /// 古いスナップショットの置き場所。新しい順
+    generation_paths: Vec<PathBuf>,
+    compaction_threshold: u64,
+    journal: Journal,
+    /// 開いた時点で変更ログに記録されていた変更。load で取り出す
+    opened_entries: Mutex<Vec<PersistLogEntry>>,
+}
+
+impl FileBackend {
+    pub fn open(database: &Database) -> io::Result<Self> {
+        let (journal, entries) = Journal::open(&database.journal_file_path())?;
+        Ok(FileBackend {
+            data_dir: database.data_dir().to_path_buf(),
+            snapshot_path: database.programs_file_path(),
+            temp_path: database.programs_temp_file_path(),
+            generation_paths: (1..=database.snapshot_generations())
+                .map(|g| database.programs_generation_path(g))
+                .collect(),
+            compaction_threshold: database.journal_compaction_threshold(),
+            journal,
+            opened_entries: Mutex::new(entries),
+        })
+    }
+
+    /// スナップショットを一時ファイルに書き出してから置き換える。置き換えられたスナップショットは世代として残す。
+    fn write_snapshot(&self, buf: &[u8]) -> io::Result<()> {
+        let mut file = File::create(&self.temp_path)?;
+        file.write_all(buf)?;
+        file.sync_all()?;
+        drop(file);
+
+        if self.snapshot_path.is_file() && !self.generation_paths.is_empty() {
+            for pair in self.generation_paths.windows(2).rev() {
+                if pair[0].is_file() {
+                    std::fs::rename(&pair[0], &pair[1])?;
+                }
+            }
+            std::fs::rename(&self.snapshot_path, &self.generation_paths[0])?;
+        }
+        std::fs::rename(&self.temp_path, &self.snapshot_path)?;
+
+        // rename を永続化する
+        File::open(&self.data_dir)?.sync_all()
+    }
+}
+
+impl DatabaseBackend for FileBackend {
+    fn load(&self) -> Result<LoadedData, InitializeError> {
+        let snapshot = if self.snapshot_path.is_file() {
+            let bin = std::fs::read(&self.snapshot_path)?;
+            Some(PersistStore::decode(&bin[..])?)
+        } else {
+            None
+        };
+        let journal = match self.opened_entries.lock() {
+            Ok(mut entries) => std::mem::take(&mut *entries),
+            Err(_) => vec![],
+        };
+        Ok(LoadedData { snapshot, journal })
+    }
+
+    fn record(&self, revision: u64, entry: Entry) {
+        self.journal.record(revision, entry);
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.journal.flush()
+    }
+
+    fn needs_compaction(&self) -> io::Result<bool> {
+        Ok(self.journal.size()? >= self.compaction_threshold)
+    }
+
+    fn backup(&self, label: &str) -> io::Result<PathBuf> {
+        let backup_path = self.snapshot_path.with_extension(format!("pb.{}.bak", label));
+        std::fs::copy(&self.snapshot_path, &backup_path)?;
+        File::open(&backup_path)?.sync_all()?;
+        Ok(backup_path)
+    }
+
+    fn compact(&self, store: &PersistStore) -> io::Result<()> {
+        let mut journal = self.journal.lock()?;
+        let mut buf: Vec<u8> = vec![];
+        store.encode(&mut buf).unwrap();
+        self.write_snapshot(&buf)?;
+        journal.reset()
+    }
+
+    fn retained_blob_keys(&self) -> io::Result<HashSet<String>> {
+        // 書き直している間は世代の名前が入れ替わるため、書き直しと同じロックを持ったまま読む
+        let _journal = self.journal.lock()?;
+        let mut paths = vec![self.snapshot_path.clone()];
+        paths.extend(self.generation_paths.iter().cloned());
+        paths.extend(backup_paths(&self.snapshot_path)?);
+
+        let mut keys = HashSet::new();
+        for path in paths {
+            let bin = match std::fs::read(&path) {
+                Ok(bin) => bin,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let store = PersistStore::decode(&bin[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            keys.extend(
+                store
+                    .videos
+                    .into_iter()
+                    .map(|v| v.thumbnail_blob)
+                    .filter(|k| !k.is_empty()),
+            );
+        }
+        Ok(keys)
+    }
+}
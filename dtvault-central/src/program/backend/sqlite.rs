@@ -0,0 +1,375 @@
+use crate::program::backend::{backup_paths, DatabaseBackend, LoadedData};
+use crate::program::{InitializeError, PERSIST_MAGIC, SCHEMA_VERSION};
+use dtvault_types::shibafu528::dtvault::central::persist_log_entry::Entry;
+use dtvault_types::shibafu528::dtvault::central::{
+    PersistChannelSetting, PersistLogEntry, PersistProgram, PersistProgramAlias, PersistProgramKey,
+    PersistServiceSetting, PersistStore, PersistVideo,
+};
+use prost::Message;
+use rusqlite::{params, Connection, OpenFlags, Transaction};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS programs (
+    network_id INTEGER NOT NULL,
+    service_id INTEGER NOT NULL,
+    event_id INTEGER NOT NULL,
+    start_at INTEGER NOT NULL,
+    duration INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    trashed_at INTEGER,
+    data BLOB NOT NULL,
+    PRIMARY KEY (network_id, service_id, event_id, start_at)
+);
+CREATE INDEX IF NOT EXISTS programs_service ON programs (network_id, service_id, start_at);
+CREATE INDEX IF NOT EXISTS programs_start_at ON programs (start_at);
+CREATE TABLE IF NOT EXISTS videos (
+    video_id TEXT PRIMARY KEY,
+    provider_id TEXT NOT NULL,
+    storage_id TEXT NOT NULL,
+    network_id INTEGER NOT NULL,
+    service_id INTEGER NOT NULL,
+    event_id INTEGER NOT NULL,
+    start_at INTEGER NOT NULL,
+    total_length INTEGER NOT NULL,
+    trashed_at INTEGER,
+    data BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS videos_provider_id ON videos (provider_id);
+CREATE INDEX IF NOT EXISTS videos_program ON videos (network_id, service_id, event_id, start_at);
+CREATE TABLE IF NOT EXISTS aliases (
+    data BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS service_settings (
+    network_id INTEGER NOT NULL,
+    service_id INTEGER NOT NULL,
+    display_name TEXT NOT NULL,
+    sort_order INTEGER NOT NULL,
+    PRIMARY KEY (network_id, service_id)
+);
+CREATE TABLE IF NOT EXISTS channel_settings (
+    channel_type INTEGER NOT NULL,
+    channel TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    sort_order INTEGER NOT NULL,
+    PRIMARY KEY (channel_type, channel)
+);
+";
+
+fn to_io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("poisoned lock: another task failed inside")
+}
+
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+    let mut buf = vec![];
+    message.encode(&mut buf).unwrap();
+    buf
+}
+
+fn seconds(timestamp: &Option<prost_types::Timestamp>) -> i64 {
+    timestamp.as_ref().map_or(0, |t| t.seconds)
+}
+
+/// 番組と動画を行として SQLite に保存する。外部のツールからも列を使って検索できる。
+pub struct SqliteBackend {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+    /// まだ書き込んでいない変更
+    pending: Mutex<Vec<PersistLogEntry>>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self, InitializeError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        connection.execute_batch(SCHEMA)?;
+        // 新しく作ったデータベースは現在の形式で始まる
+        connection.execute(
+            "INSERT OR IGNORE INTO meta (key, value) VALUES ('schema_version', ?1)",
+            params![SCHEMA_VERSION],
+        )?;
+        Ok(SqliteBackend {
+            path: path.to_path_buf(),
+            connection: Mutex::new(connection),
+            pending: Mutex::new(vec![]),
+        })
+    }
+}
+
+fn put_program(tx: &Transaction, p: &PersistProgram) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO programs (network_id, service_id, event_id, start_at, duration, name, trashed_at, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            p.network_id,
+            p.service_id,
+            p.event_id,
+            seconds(&p.start_at),
+            p.duration.as_ref().map_or(0, |d| d.seconds),
+            p.name,
+            p.trashed_at.as_ref().map(|t| t.seconds),
+            encode(p),
+        ],
+    )?;
+    Ok(())
+}
+
+fn delete_program(tx: &Transaction, key: &PersistProgramKey) -> rusqlite::Result<()> {
+    tx.execute(
+        "DELETE FROM programs WHERE network_id = ?1 AND service_id = ?2 AND event_id = ?3 AND start_at = ?4",
+        params![key.network_id, key.service_id, key.event_id, seconds(&key.start_at)],
+    )?;
+    Ok(())
+}
+
+fn put_video(tx: &Transaction, v: &PersistVideo) -> rusqlite::Result<()> {
+    let key = v.program_id.clone().unwrap_or_default();
+    tx.execute(
+        "INSERT OR REPLACE INTO videos (video_id, provider_id, storage_id, network_id, service_id, event_id, start_at, total_length, trashed_at, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            v.video_id,
+            v.provider_id,
+            v.storage_id,
+            key.network_id,
+            key.service_id,
+            key.event_id,
+            seconds(&key.start_at),
+            v.total_length as i64,
+            v.trashed_at.as_ref().map(|t| t.seconds),
+            encode(v),
+        ],
+    )?;
+    Ok(())
+}
+
+fn put_aliases(tx: &Transaction, aliases: &[PersistProgramAlias]) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM aliases", [])?;
+    for alias in aliases {
+        tx.execute("INSERT INTO aliases (data) VALUES (?1)", params![encode(alias)])?;
+    }
+    Ok(())
+}
+
+/// 既定値の設定は保存しない (Registry と同じ扱い)
+fn put_service_setting(tx: &Transaction, s: &PersistServiceSetting) -> rusqlite::Result<()> {
+    if s.display_name.is_empty() && s.sort_order == 0 {
+        tx.execute(
+            "DELETE FROM service_settings WHERE network_id = ?1 AND service_id = ?2",
+            params![s.network_id, s.service_id],
+        )?;
+    } else {
+        tx.execute(
+            "INSERT OR REPLACE INTO service_settings (network_id, service_id, display_name, sort_order) VALUES (?1, ?2, ?3, ?4)",
+            params![s.network_id, s.service_id, s.display_name, s.sort_order],
+        )?;
+    }
+    Ok(())
+}
+
+/// 既定値の設定は保存しない (Registry と同じ扱い)
+fn put_channel_setting(tx: &Transaction, c: &PersistChannelSetting) -> rusqlite::Result<()> {
+    if c.display_name.is_empty() && c.sort_order == 0 {
+        tx.execute(
+            "DELETE FROM channel_settings WHERE channel_type = ?1 AND channel = ?2",
+            params![c.channel_type, c.channel],
+        )?;
+    } else {
+        tx.execute(
+            "INSERT OR REPLACE INTO channel_settings (channel_type, channel, display_name, sort_order) VALUES (?1, ?2, ?3, ?4)",
+            params![c.channel_type, c.channel, c.display_name, c.sort_order],
+        )?;
+    }
+    Ok(())
+}
+
+fn put_meta(tx: &Transaction, key: &str, value: i64) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+fn get_meta(connection: &Connection, key: &str) -> rusqlite::Result<Option<i64>> {
+    match connection.query_row("SELECT value FROM meta WHERE key = ?1", params![key], |row| row.get(0)) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn apply(tx: &Transaction, entry: &PersistLogEntry) -> rusqlite::Result<()> {
+    match &entry.entry {
+        Some(Entry::PutProgram(p)) => put_program(tx, p),
+        Some(Entry::DeleteProgram(key)) => delete_program(tx, key),
+        Some(Entry::PutVideo(v)) => put_video(tx, v),
+        Some(Entry::DeleteVideo(id)) => tx
+            .execute("DELETE FROM videos WHERE video_id = ?1", params![id])
+            .map(|_| ()),
+        Some(Entry::Aliases(a)) => put_aliases(tx, &a.aliases),
+        Some(Entry::ServiceSetting(s)) => put_service_setting(tx, s),
+        Some(Entry::ChannelSetting(c)) => put_channel_setting(tx, c),
+        None => Ok(()),
+    }
+}
+
+fn decode_rows<M: Message + Default>(connection: &Connection, sql: &str) -> Result<Vec<M>, InitializeError> {
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map([], |row| row.get::<_, Vec<u8>>(0))?;
+    let mut messages = vec![];
+    for row in rows {
+        messages.push(M::decode(&row?[..])?);
+    }
+    Ok(messages)
+}
+
+impl DatabaseBackend for SqliteBackend {
+    fn load(&self) -> Result<LoadedData, InitializeError> {
+        let connection = self.connection.lock().map_err(|_| poisoned())?;
+        let revision = match get_meta(&connection, "revision")? {
+            Some(r) => r as u64,
+            None => {
+                return Ok(LoadedData {
+                    snapshot: None,
+                    journal: vec![],
+                })
+            }
+        };
+
+        let service_settings = connection
+            .prepare("SELECT network_id, service_id, display_name, sort_order FROM service_settings")?
+            .query_map([], |row| {
+                Ok(PersistServiceSetting {
+                    network_id: row.get(0)?,
+                    service_id: row.get(1)?,
+                    display_name: row.get(2)?,
+                    sort_order: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let channel_settings = connection
+            .prepare("SELECT channel_type, channel, display_name, sort_order FROM channel_settings")?
+            .query_map([], |row| {
+                Ok(PersistChannelSetting {
+                    channel_type: row.get(0)?,
+                    channel: row.get(1)?,
+                    display_name: row.get(2)?,
+                    sort_order: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(LoadedData {
+            snapshot: Some(PersistStore {
+                magic: PERSIST_MAGIC,
+                schema_version: get_meta(&connection, "schema_version")?.unwrap_or_default() as u32,
+                programs: decode_rows(&connection, "SELECT data FROM programs")?,
+                videos: decode_rows(&connection, "SELECT data FROM videos")?,
+                revision,
+                aliases: decode_rows(&connection, "SELECT data FROM aliases")?,
+                service_settings,
+                channel_settings,
+            }),
+            journal: vec![],
+        })
+    }
+
+    fn record(&self, revision: u64, entry: Entry) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.push(PersistLogEntry {
+                revision,
+                entry: Some(entry),
+            });
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut connection = self.connection.lock().map_err(|_| poisoned())?;
+        let mut pending = self.pending.lock().map_err(|_| poisoned())?;
+        let revision = match pending.iter().map(|e| e.revision).max() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let tx = connection.transaction().map_err(to_io_error)?;
+        for entry in pending.iter() {
+            apply(&tx, entry).map_err(to_io_error)?;
+        }
+        put_meta(&tx, "revision", revision as i64).map_err(to_io_error)?;
+        tx.commit().map_err(to_io_error)?;
+        pending.clear();
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn backup(&self, label: &str) -> io::Result<PathBuf> {
+        let connection = self.connection.lock().map_err(|_| poisoned())?;
+        let backup_path = self.path.with_extension(format!("sqlite3.{}.bak", label));
+        if backup_path.exists() {
+            std::fs::remove_file(&backup_path)?;
+        }
+        connection
+            .execute("VACUUM INTO ?1", params![backup_path.to_string_lossy()])
+            .map_err(to_io_error)?;
+        Ok(backup_path)
+    }
+
+    fn compact(&self, store: &PersistStore) -> io::Result<()> {
+        let mut connection = self.connection.lock().map_err(|_| poisoned())?;
+        let mut pending = self.pending.lock().map_err(|_| poisoned())?;
+
+        let tx = connection.transaction().map_err(to_io_error)?;
+        let result = (|| {
+            for table in &["programs", "videos", "service_settings", "channel_settings"] {
+                tx.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+            for p in &store.programs {
+                put_program(&tx, p)?;
+            }
+            for v in &store.videos {
+                put_video(&tx, v)?;
+            }
+            put_aliases(&tx, &store.aliases)?;
+            for s in &store.service_settings {
+                put_service_setting(&tx, s)?;
+            }
+            for c in &store.channel_settings {
+                put_channel_setting(&tx, c)?;
+            }
+            put_meta(&tx, "schema_version", store.schema_version as i64)?;
+            put_meta(&tx, "revision", store.revision as i64)
+        })();
+        result.map_err(to_io_error)?;
+        tx.commit().map_err(to_io_error)?;
+        pending.clear();
+        Ok(())
+    }
+
+    fn retained_blob_keys(&self) -> io::Result<HashSet<String>> {
+        let mut keys = HashSet::new();
+        for path in backup_paths(&self.path)? {
+            let connection =
+                Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+            let videos: Vec<PersistVideo> =
+                decode_rows(&connection, "SELECT data FROM videos").map_err(io::Error::other)?;
+            keys.extend(videos.into_iter().map(|v| v.thumbnail_blob).filter(|k| !k.is_empty()));
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,198 @@
+use crate::program::{Program, ProgramKey, Video};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// 再接続時の再開のために保持しておく変更の数
+const BACKLOG_SIZE: usize = 1024;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ChangeKind {
+    ProgramCreated = 1,
+    ProgramUpdated = 2,
+    ProgramDeleted = 3,
+    VideoCreated = 4,
+    VideoUpdated = 5,
+    VideoDeleted = 6,
+    ThumbnailUpdated = 7,
+}
+
+/// ProgramStore に対する1件の変更
+pub struct Change {
+    pub revision: u64,
+    pub kind: ChangeKind,
+    pub program_key: ProgramKey,
+    /// 変更後の番組。削除の場合は削除前の番組
+    pub program: Option<Arc<Program>>,
+    /// 変更後の動画。削除の場合は削除前の動画
+    pub video: Option<Arc<Video>>,
+}
+
+/// 保持されていた変更と、それ以降の変更を受け取るための Receiver
+pub type Subscription = (Vec<Arc<Change>>, broadcast::Receiver<Arc<Change>>);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ResumeError {
+    #[error("revision {0} is too old to resume, resync required")]
+    TooOld(u64),
+    #[error("revision {0} has not been issued yet")]
+    Future(u64),
+    #[error("poisoned lock: another task failed inside")]
+    Poisoned,
+}
+
+struct Backlog {
+    revision: u64,
+    changes: VecDeque<Arc<Change>>,
+}
+
+/// 変更にリビジョン番号を振って購読者に配信する。
+pub struct ChangeFeed {
+    backlog: Mutex<Backlog>,
+    sender: broadcast::Sender<Arc<Change>>,
+}
+
+impl ChangeFeed {
+    pub fn new(revision: u64) -> Self {
+        let (sender, _) = broadcast::channel(BACKLOG_SIZE);
+        ChangeFeed {
+            backlog: Mutex::new(Backlog {
+                revision,
+                changes: VecDeque::with_capacity(BACKLOG_SIZE),
+            }),
+            sender,
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.backlog.lock().map_or(0, |b| b.revision)
+    }
+
+    pub fn publish_program(&self, kind: ChangeKind, program: &Arc<Program>) {
+        self.publish(
+            kind,
+            ProgramKey::from_stored_program(program),
+            Some(program.clone()),
+            None,
+        );
+    }
+
+    pub fn publish_video(&self, kind: ChangeKind, video: &Arc<Video>) {
+        self.publish(kind, video.program_key().clone(), None, Some(video.clone()));
+    }
+
+    fn publish(
+        &self,
+        kind: ChangeKind,
+        program_key: ProgramKey,
+        program: Option<Arc<Program>>,
+        video: Option<Arc<Video>>,
+    ) {
+        let mut backlog = match self.backlog.lock() {
+            Ok(b) => b,
+            Err(_) => return,
+        };
+        backlog.revision += 1;
+        let change = Arc::new(Change {
+            revision: backlog.revision,
+            kind,
+            program_key,
+            program,
+            video,
+        });
+        if backlog.changes.len() == BACKLOG_SIZE {
+            backlog.changes.pop_front();
+        }
+        backlog.changes.push_back(change.clone());
+        // 購読者がいない場合はエラーになるが、問題ない
+        let _ = self.sender.send(change);
+    }
+
+    /// 保持していた変更を捨ててリビジョンを revision にする。
+    /// それより前のリビジョンからの再開は TooOld になり、購読者に全体の再取得を促す。
+    pub fn reset(&self, revision: u64) {
+        if let Ok(mut backlog) = self.backlog.lock() {
+            backlog.revision = revision;
+            backlog.changes.clear();
+        }
+    }
+
+    /// after より後の変更を購読する。after が 0 の場合は購読を始めた後の変更だけを受け取る。
+    pub fn subscribe(&self, after: u64) -> Result<Subscription, ResumeError> {
+        let backlog = self.backlog.lock().map_err(|_| ResumeError::Poisoned)?;
+        // 取りこぼしが出ないよう、バックログを読むのと同じロックの中で購読を開始する
+        let receiver = self.sender.subscribe();
+        if after == 0 {
+            return Ok((vec![], receiver));
+        }
+        if after > backlog.revision {
+            return Err(ResumeError::Future(after));
+        }
+        let oldest = backlog.changes.front().map_or(backlog.revision + 1, |c| c.revision);
+        if after + 1 < oldest {
+            return Err(ResumeError::TooOld(after));
+        }
+
+        let changes = backlog.changes.iter().filter(|c| c.revision > after).cloned().collect();
+        Ok((changes, receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtvault_types::shibafu528::dtvault as types;
+
+    fn program(event_id: u32) -> Arc<Program> {
+        let p = types::Program {
+            network_id: 32736,
+            service_id: 1024,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: event_id as i64 * 100,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            name: "ニュース".to_string(),
+            ..Default::default()
+        };
+        Arc::new(Program::from_exchanged(p).unwrap())
+    }
+
+    #[test]
+    fn test_resume() {
+        let feed = ChangeFeed::new(10);
+        feed.publish_program(ChangeKind::ProgramCreated, &program(1));
+        feed.publish_program(ChangeKind::ProgramUpdated, &program(1));
+        feed.publish_program(ChangeKind::ProgramCreated, &program(2));
+        assert_eq!(13, feed.revision());
+
+        let (changes, _) = feed.subscribe(11).unwrap();
+        assert_eq!(vec![12, 13], changes.iter().map(|c| c.revision).collect::<Vec<_>>());
+
+        let (changes, _) = feed.subscribe(10).unwrap();
+        assert_eq!(3, changes.len());
+
+        assert!(matches!(feed.subscribe(9), Err(ResumeError::TooOld(9))));
+        assert!(matches!(feed.subscribe(14), Err(ResumeError::Future(14))));
+
+        feed.reset(20);
+        assert!(matches!(feed.subscribe(13), Err(ResumeError::TooOld(13))));
+        assert!(feed.subscribe(20).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let feed = ChangeFeed::new(0);
+        let (changes, mut receiver) = feed.subscribe(0).unwrap();
+        assert!(changes.is_empty());
+
+        feed.publish_program(ChangeKind::ProgramCreated, &program(1));
+        let change = receiver.try_recv().unwrap();
+        assert_eq!(1, change.revision);
+        assert_eq!(ChangeKind::ProgramCreated, change.kind);
+    }
+}
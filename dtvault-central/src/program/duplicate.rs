@@ -0,0 +1,241 @@
+use crate::program::search_index::ngrams;
+use crate::program::{normalize, parse_title, Program, ProgramKey};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+// 放送時間の差の許容範囲 (長い方の放送時間に対する割合)
+const DURATION_TOLERANCE: f64 = 0.1;
+// 概要が似ているとみなす類似度 (bigram の Jaccard 係数)
+const DESCRIPTION_SIMILARITY: f64 = 0.6;
+
+/// 重複とみなした理由。値が小さいほど確度が高い。
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum DuplicateReason {
+    /// 同じイベントを別のチューナーや別の network_id で録画した
+    SameEvent = 1,
+    /// シリーズ名と話数が一致する
+    SameEpisode = 2,
+    /// 番組名が一致し、概要と放送時間が似ている (再放送など)
+    SimilarContent = 3,
+}
+
+/// 同じ内容だと思われる番組のまとまり
+pub struct DuplicateGroup {
+    /// グループ内の番組のうち、最も確度の高い理由
+    pub reason: DuplicateReason,
+    /// 放送日時順
+    pub programs: Vec<Arc<Program>>,
+}
+
+impl DuplicateGroup {
+    /// グループの識別子。最初に放送された番組から作るため、グループに番組が増えても変わりにくい。
+    pub fn id(&self) -> String {
+        let first = &self.programs[0];
+        format!(
+            "{}-{}-{}-{}",
+            first.network_id,
+            first.service_id,
+            first.event_id,
+            first.start_at.timestamp()
+        )
+    }
+}
+
+struct Features {
+    event: (u16, u16, i64),
+    episode: Option<(String, u32)>,
+    title: String,
+    description: BTreeSet<String>,
+    duration: Duration,
+}
+
+impl Features {
+    fn new(program: &Program) -> Self {
+        let parsed = parse_title(&program.name);
+        let series_title = normalize(&parsed.series_title);
+        Features {
+            event: (program.service_id, program.event_id, program.start_at.timestamp()),
+            episode: parsed.number.map(|n| (series_title.clone(), n)),
+            title: format!(
+                "{}\u{0}{}\u{0}{}",
+                series_title,
+                parsed.number.unwrap_or(0),
+                normalize(&parsed.subtitle)
+            ),
+            description: ngrams(&normalize(&program.description)).into_iter().collect(),
+            duration: program.duration,
+        }
+    }
+
+    fn similar_duration(&self, other: &Features) -> bool {
+        let (a, b) = (self.duration.as_secs_f64(), other.duration.as_secs_f64());
+        (a - b).abs() <= a.max(b) * DURATION_TOLERANCE
+    }
+
+    fn similar_description(&self, other: &Features) -> bool {
+        if self.description.is_empty() && other.description.is_empty() {
+            return true;
+        }
+        let intersection = self.description.intersection(&other.description).count();
+        let union = self.description.len() + other.description.len() - intersection;
+        intersection as f64 / union as f64 >= DESCRIPTION_SIMILARITY
+    }
+}
+
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let parent = self.parents[i];
+        if parent == i {
+            return i;
+        }
+        let root = self.find(parent);
+        self.parents[i] = root;
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[b] = a;
+        }
+    }
+}
+
+/// 同じ内容だと思われる番組をまとめる。ゴミ箱の中の番組は対象にしない。
+pub fn find_duplicates<'a, I: IntoIterator<Item = &'a Arc<Program>>>(programs: I) -> Vec<DuplicateGroup> {
+    let programs: Vec<&Arc<Program>> = programs.into_iter().filter(|p| p.trashed_at.is_none()).collect();
+    let features: Vec<Features> = programs.iter().map(|p| Features::new(p)).collect();
+
+    let mut by_event: HashMap<_, Vec<usize>> = HashMap::new();
+    let mut by_episode: HashMap<_, Vec<usize>> = HashMap::new();
+    let mut by_title: HashMap<_, Vec<usize>> = HashMap::new();
+    for (i, f) in features.iter().enumerate() {
+        by_event.entry(f.event).or_default().push(i);
+        if let Some(episode) = &f.episode {
+            by_episode.entry(episode).or_default().push(i);
+        }
+        by_title.entry(&f.title).or_default().push(i);
+    }
+
+    let mut edges = vec![];
+    for indices in by_event.values() {
+        for pair in indices.windows(2) {
+            edges.push((pair[0], pair[1], DuplicateReason::SameEvent));
+        }
+    }
+    let mut link = |indices: &[usize], reason: DuplicateReason, matches: &dyn Fn(&Features, &Features) -> bool| {
+        for (n, &a) in indices.iter().enumerate() {
+            for &b in &indices[n + 1..] {
+                if matches(&features[a], &features[b]) {
+                    edges.push((a, b, reason));
+                }
+            }
+        }
+    };
+    for indices in by_episode.values() {
+        link(indices, DuplicateReason::SameEpisode, &|a, b| a.similar_duration(b));
+    }
+    for indices in by_title.values() {
+        link(indices, DuplicateReason::SimilarContent, &|a, b| {
+            a.similar_duration(b) && a.similar_description(b)
+        });
+    }
+
+    let mut uf = UnionFind::new(programs.len());
+    for (a, b, _) in &edges {
+        uf.union(*a, *b);
+    }
+    let mut groups: HashMap<usize, (DuplicateReason, BTreeSet<usize>)> = HashMap::new();
+    for (a, b, reason) in edges {
+        let group = groups.entry(uf.find(a)).or_insert((reason, BTreeSet::new()));
+        group.0 = group.0.min(reason);
+        group.1.insert(a);
+        group.1.insert(b);
+    }
+
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_values()
+        .map(|(reason, indices)| {
+            let mut members: Vec<Arc<Program>> = indices.into_iter().map(|i| programs[i].clone()).collect();
+            members.sort_by_key(|p| (p.start_at, ProgramKey::from_stored_program(p)));
+            DuplicateGroup {
+                reason,
+                programs: members,
+            }
+        })
+        .collect();
+    groups.sort_by_key(|g| (g.programs[0].start_at, ProgramKey::from_stored_program(&g.programs[0])));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtvault_types::shibafu528::dtvault as types;
+
+    fn program(
+        network_id: u32,
+        event_id: u32,
+        start_at: i64,
+        duration: i64,
+        name: &str,
+        description: &str,
+    ) -> Arc<Program> {
+        let p = types::Program {
+            network_id,
+            service_id: 1024,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: start_at,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: duration,
+                nanos: 0,
+            }),
+            name: name.to_string(),
+            description: description.to_string(),
+            ..Default::default()
+        };
+        Arc::new(Program::from_exchanged(p).unwrap())
+    }
+
+    fn event_ids(group: &DuplicateGroup) -> Vec<u16> {
+        group.programs.iter().map(|p| p.event_id).collect()
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let programs = vec![
+            // 同じイベント (network_id だけが異なる)
+            program(0, 1, 1000, 1800, "ニュース", "今日の出来事"),
+            program(32736, 1, 1000, 1800, "ニュース", "今日の出来事"),
+            // 再放送
+            program(32736, 2, 10000, 1800, "アニメ #5「出会い」", "主人公が旅に出る"),
+            program(32736, 3, 90000, 1800, "アニメ #5「出会い」[再]", "主人公が旅に出る。"),
+            // 同じ話数だが放送時間が大きく異なる
+            program(32736, 4, 200000, 5400, "アニメ #5「出会い」", "総集編"),
+            // 番組名が同じでも概要が異なる
+            program(32736, 5, 300000, 1800, "ニュース", "明日の天気"),
+        ];
+
+        let groups = find_duplicates(&programs);
+        assert_eq!(2, groups.len());
+        assert_eq!(DuplicateReason::SameEvent, groups[0].reason);
+        assert_eq!(vec![1, 1], event_ids(&groups[0]));
+        assert_eq!(DuplicateReason::SameEpisode, groups[1].reason);
+        assert_eq!(vec![2, 3], event_ids(&groups[1]));
+        assert_eq!("32736-1024-2-10000", groups[1].id());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::program::prost_convert::{ToDateTimeExt, ToTimestampExt};
+use crate::program::{ExtendedEvent, MessageConversionError, Persistence, Service};
+use chrono::{DateTime, Utc};
+use dtvault_types::shibafu528::dtvault as types;
+use dtvault_types::shibafu528::dtvault::central::{PersistProgramEdit, PersistProgramFields};
+use serde::{Deserialize, Serialize};
+
+/// 番組の編集可能なフィールド
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ProgramField {
+    Name = 1,
+    Description = 2,
+    Extended = 3,
+    Service = 4,
+}
+
+impl ProgramField {
+    pub const ALL: [ProgramField; 4] = [
+        ProgramField::Name,
+        ProgramField::Description,
+        ProgramField::Extended,
+        ProgramField::Service,
+    ];
+
+    pub fn from_i32(value: i32) -> Option<Self> {
+        Self::ALL.iter().copied().find(|field| *field as i32 == value)
+    }
+}
+
+/// 番組の1フィールド分の値
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum FieldValue {
+    Name(String),
+    Description(String),
+    Extended(Vec<ExtendedEvent>),
+    Service(Option<Service>),
+}
+
+impl FieldValue {
+    pub fn field(&self) -> ProgramField {
+        match self {
+            FieldValue::Name(_) => ProgramField::Name,
+            FieldValue::Description(_) => ProgramField::Description,
+            FieldValue::Extended(_) => ProgramField::Extended,
+            FieldValue::Service(_) => ProgramField::Service,
+        }
+    }
+
+    /// program から field に対応する値を取り出す。
+    pub fn from_exchanged(field: ProgramField, program: &types::Program) -> Result<Self, MessageConversionError> {
+        Ok(match field {
+            ProgramField::Name => FieldValue::Name(program.name.clone()),
+            ProgramField::Description => FieldValue::Description(program.description.clone()),
+            ProgramField::Extended => FieldValue::Extended(
+                program
+                    .extended
+                    .iter()
+                    .map(|e| ExtendedEvent::from_exchanged(e.clone()))
+                    .collect(),
+            ),
+            ProgramField::Service => FieldValue::Service(match &program.service {
+                Some(service) => Some(Service::from_exchanged(service.clone())?),
+                None => None,
+            }),
+        })
+    }
+
+    /// field に対応する要素だけを設定した Program を作る。
+    pub fn exchangeable(&self) -> types::Program {
+        let mut program = types::Program::default();
+        match self {
+            FieldValue::Name(v) => program.name = v.clone(),
+            FieldValue::Description(v) => program.description = v.clone(),
+            FieldValue::Extended(v) => program.extended = v.iter().map(|e| e.exchangeable()).collect(),
+            FieldValue::Service(v) => program.service = v.as_ref().map(|s| s.exchangeable()),
+        }
+        program
+    }
+
+    fn from_persisted(field: ProgramField, persisted: PersistProgramFields) -> Result<Self, MessageConversionError> {
+        Ok(match field {
+            ProgramField::Name => FieldValue::Name(persisted.name),
+            ProgramField::Description => FieldValue::Description(persisted.description),
+            ProgramField::Extended => FieldValue::Extended(
+                persisted
+                    .extended
+                    .into_iter()
+                    .map(ExtendedEvent::from_persisted)
+                    .collect::<Result<_, _>>()?,
+            ),
+            ProgramField::Service => FieldValue::Service(match persisted.service {
+                Some(service) => Some(Service::from_persisted(service)?),
+                None => None,
+            }),
+        })
+    }
+
+    fn persist(&self) -> PersistProgramFields {
+        let mut persisted = PersistProgramFields::default();
+        match self {
+            FieldValue::Name(v) => persisted.name = v.clone(),
+            FieldValue::Description(v) => persisted.description = v.clone(),
+            FieldValue::Extended(v) => persisted.extended = v.iter().map(|e| e.persist()).collect(),
+            FieldValue::Service(v) => persisted.service = v.as_ref().map(|s| s.persist()),
+        }
+        persisted
+    }
+}
+
+/// 編集可能なフィールドの値一式
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProgramFields {
+    pub name: String,
+    pub description: String,
+    pub extended: Vec<ExtendedEvent>,
+    pub service: Option<Service>,
+}
+
+impl ProgramFields {
+    pub fn get(&self, field: ProgramField) -> FieldValue {
+        match field {
+            ProgramField::Name => FieldValue::Name(self.name.clone()),
+            ProgramField::Description => FieldValue::Description(self.description.clone()),
+            ProgramField::Extended => FieldValue::Extended(self.extended.clone()),
+            ProgramField::Service => FieldValue::Service(self.service.clone()),
+        }
+    }
+
+    pub fn exchangeable(&self) -> types::Program {
+        types::Program {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            extended: self.extended.iter().map(|e| e.exchangeable()).collect(),
+            service: self.service.as_ref().map(|s| s.exchangeable()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Persistence<PersistProgramFields> for ProgramFields {
+    fn from_persisted(persisted: PersistProgramFields) -> Result<Self, MessageConversionError> {
+        Ok(ProgramFields {
+            name: persisted.name,
+            description: persisted.description,
+            extended: persisted
+                .extended
+                .into_iter()
+                .map(ExtendedEvent::from_persisted)
+                .collect::<Result<_, _>>()?,
+            service: match persisted.service {
+                Some(service) => Some(Service::from_persisted(service)?),
+                None => None,
+            },
+        })
+    }
+
+    fn persist(&self) -> PersistProgramFields {
+        PersistProgramFields {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            extended: self.extended.iter().map(|e| e.persist()).collect(),
+            service: self.service.as_ref().map(|s| s.persist()),
+        }
+    }
+}
+
+/// 1フィールドに対する1回分の編集履歴
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FieldEdit {
+    pub edited_at: DateTime<Utc>,
+    pub before: FieldValue,
+    pub after: FieldValue,
+}
+
+impl FieldEdit {
+    pub fn exchangeable(&self) -> types::central::ProgramEdit {
+        types::central::ProgramEdit {
+            edited_at: Some(self.edited_at.to_timestamp()),
+            field: self.after.field() as i32,
+            before: Some(self.before.exchangeable()),
+            after: Some(self.after.exchangeable()),
+        }
+    }
+}
+
+impl Persistence<PersistProgramEdit> for FieldEdit {
+    fn from_persisted(persisted: PersistProgramEdit) -> Result<Self, MessageConversionError> {
+        let field = ProgramField::from_i32(persisted.field)
+            .ok_or_else(|| MessageConversionError::MissingRequiredField("field".to_string()))?;
+        let edited_at = persisted
+            .edited_at
+            .ok_or_else(|| MessageConversionError::MissingRequiredField("edited_at".to_string()))?;
+        Ok(FieldEdit {
+            edited_at: edited_at.to_utc(),
+            before: FieldValue::from_persisted(field, persisted.before.unwrap_or_default())?,
+            after: FieldValue::from_persisted(field, persisted.after.unwrap_or_default())?,
+        })
+    }
+
+    fn persist(&self) -> PersistProgramEdit {
+        PersistProgramEdit {
+            edited_at: Some(self.edited_at.to_timestamp()),
+            field: self.after.field() as i32,
+            before: Some(self.before.persist()),
+            after: Some(self.after.persist()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+    use chrono::TimeZone;
+
+    fn program() -> Program {
+        let p = types::Program {
+            network_id: 32736,
+            service_id: 1024,
+            event_id: 1,
+            start_at: Some(prost_types::Timestamp { seconds: 100, nanos: 0 }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            name: "番組名未定".to_string(),
+            ..Default::default()
+        };
+        Program::from_exchanged(p).unwrap()
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut p = program();
+        let at = Utc.timestamp(1000, 0);
+
+        assert!(!p.edit(FieldValue::Name("番組名未定".to_string()), at));
+        assert!(p.edits().is_empty());
+
+        assert!(p.edit(FieldValue::Name("ニュース".to_string()), at));
+        assert!(p.edit(FieldValue::Name("夜のニュース".to_string()), at));
+        assert_eq!("夜のニュース", p.name);
+        assert_eq!("番組名未定", p.original().name);
+        assert_eq!(2, p.edits().len());
+        assert!(p.edits()[1].before == FieldValue::Name("ニュース".to_string()));
+    }
+
+    #[test]
+    fn test_persist_edits() {
+        let mut p = program();
+        p.edit(FieldValue::Description("概要".to_string()), Utc.timestamp(1000, 0));
+
+        let p = Program::from_persisted(p.persist()).unwrap();
+        assert_eq!("概要", p.description);
+        assert_eq!("", p.original().description);
+        assert_eq!(1, p.edits().len());
+        assert_eq!(ProgramField::Description, p.edits()[0].after.field());
+    }
+}
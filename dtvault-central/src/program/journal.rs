@@ -0,0 +1,182 @@
+use crate::program::{PersistError, ProgramStore};
+use dtvault_types::shibafu528::dtvault::central::persist_log_entry::Entry;
+use dtvault_types::shibafu528::dtvault::central::PersistLogEntry;
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+
+const COMPACTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+fn poisoned() -> io::Error {
+    io::Error::other("poisoned lock: another task failed inside")
+}
+
+/// ProgramStore への変更を追記していくログ
+pub struct Journal {
+    file: Mutex<File>,
+    /// まだ書き込んでいない変更
+    pending: Mutex<Vec<PersistLogEntry>>,
+}
+
+impl Journal {
+    /// ログを開き、記録されている変更を返す。書き込みの途中で終わっている末尾の変更は切り捨てる。
+    pub fn open(path: &Path) -> io::Result<(Self, Vec<PersistLogEntry>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+
+        let mut entries = vec![];
+        let mut rest = &buf[..];
+        let mut valid_len = 0;
+        while !rest.is_empty() {
+            match PersistLogEntry::decode_length_delimited(&mut rest) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    valid_len = buf.len() - rest.len();
+                }
+                Err(_) => break,
+            }
+        }
+        if valid_len < buf.len() {
+            eprintln!(
+                "Discarding {} bytes of broken entry at the end of {}",
+                buf.len() - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len as u64)?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+
+        Ok((
+            Journal {
+                file: Mutex::new(file),
+                pending: Mutex::new(vec![]),
+            },
+            entries,
+        ))
+    }
+
+    /// 変更を記録する。ファイルには flush を呼ぶまで書き込まない。
+    pub fn record(&self, revision: u64, entry: Entry) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.push(PersistLogEntry {
+                revision,
+                entry: Some(entry),
+            });
+        }
+    }
+
+    /// 記録した変更をファイルに追記する。失敗した場合は書きかけの内容を取り除き、変更は次の flush で再試行する。
+    pub fn flush(&self) -> io::Result<()> {
+        let mut file = self.file.lock().map_err(|_| poisoned())?;
+        let mut pending = self.pending.lock().map_err(|_| poisoned())?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = vec![];
+        for entry in pending.iter() {
+            entry.encode_length_delimited(&mut buf)?;
+        }
+        let position = file.stream_position()?;
+        if let Err(e) = file.write_all(&buf).and_then(|_| file.sync_data()) {
+            let _ = file
+                .set_len(position)
+                .and_then(|_| file.seek(SeekFrom::Start(position)));
+            return Err(e);
+        }
+        pending.clear();
+        Ok(())
+    }
+
+    /// 書き込み済みのログの大きさ (バイト数)
+    pub fn size(&self) -> io::Result<u64> {
+        let file = self.file.lock().map_err(|_| poisoned())?;
+        Ok(file.metadata()?.len())
+    }
+
+    /// スナップショットを作る間、追記を止める。
+    pub fn lock(&self) -> io::Result<JournalGuard<'_>> {
+        Ok(JournalGuard {
+            journal: self,
+            file: self.file.lock().map_err(|_| poisoned())?,
+        })
+    }
+}
+
+pub struct JournalGuard<'a> {
+    journal: &'a Journal,
+    file: MutexGuard<'a, File>,
+}
+
+impl JournalGuard<'_> {
+    /// スナップショットに含まれる変更を捨て、ログを空にする。
+    /// スナップショットを作るのと同じロックの中で呼ぶこと。
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.journal.pending.lock().map_err(|_| poisoned())?.clear();
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()
+    }
+}
+
+/// バックエンドが必要とする場合に、定期的に内容を書き直す。
+pub fn spawn_compactor(store: Arc<ProgramStore>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let store = store.clone();
+            let result = tokio::task::spawn_blocking(move || match store.needs_compaction() {
+                Ok(true) => store.compact().map(|_| true),
+                Ok(false) => Ok(false),
+                Err(e) => Err(PersistError::from(e)),
+            })
+            .await;
+            match result {
+                Ok(Ok(true)) => println!("[Journal] Compacted"),
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => eprintln!("[Journal] error: {}", e),
+                Err(e) => eprintln!("[Journal] error: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_discards_torn_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("programs.log");
+        {
+            let (journal, entries) = Journal::open(&path).unwrap();
+            assert!(entries.is_empty());
+            journal.record(1, Entry::DeleteVideo("a".to_string()));
+            journal.record(2, Entry::DeleteVideo("b".to_string()));
+            journal.flush().unwrap();
+        }
+        // 書き込みの途中で終了した状態を再現する
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0x10, 0x08]).unwrap();
+        drop(file);
+
+        let (journal, entries) = Journal::open(&path).unwrap();
+        assert_eq!(vec![1, 2], entries.iter().map(|e| e.revision).collect::<Vec<_>>());
+        assert_eq!(len, journal.size().unwrap());
+
+        journal.lock().unwrap().reset().unwrap();
+        assert_eq!(0, journal.size().unwrap());
+    }
+}
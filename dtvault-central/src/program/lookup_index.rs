@@ -0,0 +1,133 @@
+use crate::program::{Program, ProgramKey, ServiceId, Video};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// ProgramKey や動画 ID 以外から番組と動画を引くための索引。
+/// 放送開始日時での絞り込みは、ProgramKey が start_at から始まるため番組のマップ自体の順序で行える。
+#[derive(Default)]
+pub struct LookupIndex {
+    programs_by_service: BTreeMap<ServiceId, BTreeSet<ProgramKey>>,
+    videos_by_provider_id: HashMap<String, BTreeSet<Uuid>>,
+    videos_by_storage_id: HashMap<Uuid, BTreeSet<Uuid>>,
+}
+
+impl LookupIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert_program(&mut self, program: &Program) {
+        self.programs_by_service
+            .entry((program.network_id, program.service_id))
+            .or_default()
+            .insert(ProgramKey::from_stored_program(program));
+    }
+
+    pub fn remove_program(&mut self, program: &Program) {
+        let service = (program.network_id, program.service_id);
+        if let Some(keys) = self.programs_by_service.get_mut(&service) {
+            keys.remove(&ProgramKey::from_stored_program(program));
+            if keys.is_empty() {
+                self.programs_by_service.remove(&service);
+            }
+        }
+    }
+
+    pub fn insert_video(&mut self, video: &Video) {
+        self.videos_by_provider_id
+            .entry(video.provider_id.clone())
+            .or_default()
+            .insert(video.id);
+        self.videos_by_storage_id
+            .entry(video.storage_id)
+            .or_default()
+            .insert(video.id);
+    }
+
+    pub fn remove_video(&mut self, video: &Video) {
+        if let Some(ids) = self.videos_by_provider_id.get_mut(&video.provider_id) {
+            ids.remove(&video.id);
+            if ids.is_empty() {
+                self.videos_by_provider_id.remove(&video.provider_id);
+            }
+        }
+        if let Some(ids) = self.videos_by_storage_id.get_mut(&video.storage_id) {
+            ids.remove(&video.id);
+            if ids.is_empty() {
+                self.videos_by_storage_id.remove(&video.storage_id);
+            }
+        }
+    }
+
+    /// サービスの番組を放送開始日時の順に返す。
+    pub fn programs_of_service(&self, service: ServiceId) -> impl Iterator<Item = &ProgramKey> {
+        self.programs_by_service.get(&service).into_iter().flatten()
+    }
+
+    pub fn videos_of_provider_id(&self, provider_id: &str) -> impl Iterator<Item = &Uuid> {
+        self.videos_by_provider_id.get(provider_id).into_iter().flatten()
+    }
+
+    pub fn videos_of_storage_id(&self, storage_id: &Uuid) -> impl Iterator<Item = &Uuid> {
+        self.videos_by_storage_id.get(storage_id).into_iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtvault_types::shibafu528::dtvault as types;
+
+    fn program(service_id: u32, event_id: u32) -> Program {
+        Program::from_exchanged(types::Program {
+            network_id: 32736,
+            service_id,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: 1617202800 + event_id as i64 * 1800,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut index = LookupIndex::new();
+        let a = program(1024, 1);
+        let b = program(1024, 2);
+        let c = program(1025, 3);
+        for p in &[&a, &b, &c] {
+            index.insert_program(p);
+        }
+        assert_eq!(2, index.programs_of_service((32736, 1024)).count());
+        index.remove_program(&a);
+        assert_eq!(
+            vec![&ProgramKey::from_stored_program(&b)],
+            index.programs_of_service((32736, 1024)).collect::<Vec<_>>()
+        );
+
+        let video = Video::from_exchanged(
+            &c,
+            types::storage::create_video_request::Header {
+                provider_id: "chinachu:1".to_string(),
+                mime_type: "video/mp2t".to_string(),
+                ..Default::default()
+            },
+        );
+        index.insert_video(&video);
+        assert_eq!(
+            vec![&video.id],
+            index.videos_of_provider_id("chinachu:1").collect::<Vec<_>>()
+        );
+        assert_eq!(1, index.videos_of_storage_id(&Uuid::nil()).count());
+        index.remove_video(&video);
+        assert_eq!(0, index.videos_of_provider_id("chinachu:1").count());
+        assert_eq!(0, index.videos_of_storage_id(&Uuid::nil()).count());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::blob_store::BlobStore;
+use crate::program::InitializeError;
+use dtvault_types::shibafu528::dtvault::central::{PersistStore, PersistVideo};
+use std::io;
+
+/// 番組データベースであることを示す値 ("DTVS")
+pub const PERSIST_MAGIC: u32 = 0x4454_5653;
+/// 現在のスキーマバージョン。形式を変更する場合は MIGRATIONS に移行処理を追加してから上げる。
+pub const SCHEMA_VERSION: u32 = 1;
+
+struct Migration {
+    /// 移行前のバージョン。移行後は from + 1 になる
+    from: u32,
+    description: &'static str,
+    apply: fn(&mut PersistStore, &BlobStore) -> io::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "move inline thumbnails to blob store",
+    apply: move_inline_thumbnails,
+}];
+
+/// 保存されている形式のバージョンを返す。マジックナンバーのない形式はバージョン 0 として扱う。
+pub fn schema_version(store: &PersistStore) -> Result<u32, InitializeError> {
+    let version = match store.magic {
+        0 => 0,
+        PERSIST_MAGIC => store.schema_version,
+        magic => return Err(InitializeError::UnknownFormat(magic)),
+    };
+    if version > SCHEMA_VERSION {
+        return Err(InitializeError::UnsupportedVersion {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    Ok(version)
+}
+
+/// 古い形式を1バージョンずつ現在の形式に移行する。
+pub fn migrate(store: &mut PersistStore, blobs: &BlobStore) -> Result<(), InitializeError> {
+    let mut version = schema_version(store)?;
+    while version < SCHEMA_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .expect("missing migration step");
+        println!(
+            "Migrating database from version {} to {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+        (migration.apply)(store, blobs)?;
+        version += 1;
+    }
+    store.magic = PERSIST_MAGIC;
+    store.schema_version = SCHEMA_VERSION;
+    Ok(())
+}
+
+/// 番組データベースに直接保存されていたサムネイルを blob に移す。
+pub fn migrate_inline_thumbnail(blobs: &BlobStore, persisted: &mut PersistVideo) -> io::Result<bool> {
+    if persisted.thumbnail.is_empty() {
+        return Ok(false);
+    }
+    persisted.thumbnail_blob = blobs.put(&std::mem::take(&mut persisted.thumbnail))?;
+    Ok(true)
+}
+
+fn move_inline_thumbnails(store: &mut PersistStore, blobs: &BlobStore) -> io::Result<()> {
+    for video in &mut store.videos {
+        migrate_inline_thumbnail(blobs, video)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = BlobStore::open(dir.path().to_path_buf()).unwrap();
+
+        let mut store = PersistStore {
+            videos: vec![PersistVideo {
+                thumbnail: vec![0xff, 0xd8],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(0, schema_version(&store).unwrap());
+        migrate(&mut store, &blobs).unwrap();
+        assert_eq!(SCHEMA_VERSION, schema_version(&store).unwrap());
+        assert!(store.videos[0].thumbnail.is_empty());
+        assert!(!store.videos[0].thumbnail_blob.is_empty());
+
+        store.schema_version = SCHEMA_VERSION + 1;
+        assert!(matches!(
+            schema_version(&store),
+            Err(InitializeError::UnsupportedVersion { .. })
+        ));
+        store.magic = 0x1234;
+        assert!(matches!(
+            schema_version(&store),
+            Err(InitializeError::UnknownFormat(0x1234))
+        ));
+    }
+}
@@ -1,5 +1,5 @@
-use crate::program::prost_convert::{ToDateTimeExt, ToDurationExt};
-use crate::program::ProgramKey;
+use crate::program::prost_convert::{ToDateTimeExt, ToDurationExt, ToTimestampExt};
+use crate::program::{FieldEdit, FieldValue, ProgramField, ProgramFields, ProgramKey, WatchState};
 use chrono::{DateTime, Utc};
 use dtvault_types::shibafu528::dtvault as types;
 use dtvault_types::shibafu528::dtvault::central::persist_program::ExtendedEvent as PersistExtendedEvent;
@@ -9,7 +9,7 @@ use mime::Mime;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -30,7 +30,7 @@ pub trait Persistence<T> {
     fn persist(&self) -> T;
 }
 
-#[derive(FromPrimitive, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Debug)]
+#[derive(FromPrimitive, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize, Debug)]
 pub enum ChannelType {
     GR = 1,
     BS = 2,
@@ -38,6 +38,18 @@ pub enum ChannelType {
     Sky = 4,
 }
 
+impl ChannelType {
+    pub fn from_exchanged(channel_type: i32) -> Option<Self> {
+        match types::ChannelType::from_i32(channel_type) {
+            Some(types::ChannelType::Gr) => Some(ChannelType::GR),
+            Some(types::ChannelType::Bs) => Some(ChannelType::BS),
+            Some(types::ChannelType::Cs) => Some(ChannelType::CS),
+            Some(types::ChannelType::Sky) => Some(ChannelType::Sky),
+            Some(_) | None => None,
+        }
+    }
+}
+
 impl std::fmt::Display for ChannelType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -49,7 +61,7 @@ impl std::fmt::Display for ChannelType {
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Channel {
     pub channel_type: ChannelType,
     channel: String,
@@ -59,18 +71,18 @@ pub struct Channel {
 impl Channel {
     pub fn from_exchanged(channel: types::Channel) -> Result<Self, MessageConversionError> {
         Ok(Channel {
-            channel_type: match types::ChannelType::from_i32(channel.channel_type) {
-                Some(types::ChannelType::Gr) => Ok(ChannelType::GR),
-                Some(types::ChannelType::Bs) => Ok(ChannelType::BS),
-                Some(types::ChannelType::Cs) => Ok(ChannelType::CS),
-                Some(types::ChannelType::Sky) => Ok(ChannelType::Sky),
-                Some(_) | None => Err(MessageConversionError::MissingRequiredField("channel_type".to_string())),
-            }?,
+            channel_type: ChannelType::from_exchanged(channel.channel_type)
+                .ok_or_else(|| MessageConversionError::MissingRequiredField("channel_type".to_string()))?,
             channel: channel.channel,
             name: channel.name,
         })
     }
 
+    /// 物理チャンネル
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
     pub fn exchangeable(&self) -> types::Channel {
         types::Channel {
             channel_type: self.channel_type as i32,
@@ -101,7 +113,7 @@ impl Persistence<PersistChannel> for Channel {
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Service {
     network_id: u16,
     service_id: u16,
@@ -155,7 +167,7 @@ impl Persistence<PersistService> for Service {
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Program {
     #[serde(with = "crate::serde::uuid")]
     pub id: Uuid,
@@ -172,6 +184,16 @@ pub struct Program {
     metadata: HashMap<String, String>,
     #[serde(skip)]
     video_ids: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+    /// ゴミ箱に移動された日時
+    #[serde(default)]
+    pub trashed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    original: Option<ProgramFields>,
+    #[serde(default)]
+    edits: Vec<FieldEdit>,
+    #[serde(default)]
+    tags: BTreeSet<String>,
 }
 
 impl Program {
@@ -203,6 +225,11 @@ impl Program {
             },
             metadata: HashMap::new(),
             video_ids: Vec::new(),
+            created_at: Utc::now(),
+            trashed_at: None,
+            original: None,
+            edits: Vec::new(),
+            tags: program.tags.into_iter().collect(),
         })
     }
 
@@ -223,11 +250,16 @@ impl Program {
                 Some(service) => Some(service.exchangeable()),
                 None => None,
             },
-            thumbnail: Vec::new(),
-            thumbnail_mime_type: String::new(),
+            trashed_at: self.trashed_at.map(|t| t.to_timestamp()),
+            tags: self.tags.iter().cloned().collect(),
+            thumbnail_id: String::new(),
         }
     }
 
+    pub fn extended(&self) -> &Vec<ExtendedEvent> {
+        &self.extended
+    }
+
     pub fn metadata(&self) -> &HashMap<String, String> {
         &self.metadata
     }
@@ -236,6 +268,14 @@ impl Program {
         &mut self.metadata
     }
 
+    pub fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    pub fn tags_mut(&mut self) -> &mut BTreeSet<String> {
+        &mut self.tags
+    }
+
     pub fn video_ids(&self) -> &Vec<Uuid> {
         &self.video_ids
     }
@@ -243,6 +283,56 @@ impl Program {
     pub fn video_ids_mut(&mut self) -> &mut Vec<Uuid> {
         &mut self.video_ids
     }
+
+    pub fn value_of(&self, field: ProgramField) -> FieldValue {
+        match field {
+            ProgramField::Name => FieldValue::Name(self.name.clone()),
+            ProgramField::Description => FieldValue::Description(self.description.clone()),
+            ProgramField::Extended => FieldValue::Extended(self.extended.clone()),
+            ProgramField::Service => FieldValue::Service(self.service.clone()),
+        }
+    }
+
+    /// 放送時の値
+    pub fn original(&self) -> ProgramFields {
+        match &self.original {
+            Some(original) => original.clone(),
+            None => ProgramFields {
+                name: self.name.clone(),
+                description: self.description.clone(),
+                extended: self.extended.clone(),
+                service: self.service.clone(),
+            },
+        }
+    }
+
+    pub fn edits(&self) -> &Vec<FieldEdit> {
+        &self.edits
+    }
+
+    /// フィールドの値を書き換え、編集履歴に記録する。値が変化しなかった場合は false を返す。
+    pub fn edit(&mut self, value: FieldValue, at: DateTime<Utc>) -> bool {
+        let before = self.value_of(value.field());
+        if before == value {
+            return false;
+        }
+        if self.original.is_none() {
+            self.original = Some(self.original());
+        }
+
+        match value.clone() {
+            FieldValue::Name(v) => self.name = v,
+            FieldValue::Description(v) => self.description = v,
+            FieldValue::Extended(v) => self.extended = v,
+            FieldValue::Service(v) => self.service = v,
+        }
+        self.edits.push(FieldEdit {
+            edited_at: at,
+            before,
+            after: value,
+        });
+        true
+    }
 }
 
 impl Persistence<PersistProgram> for Program {
@@ -278,6 +368,19 @@ impl Persistence<PersistProgram> for Program {
                 .into_iter()
                 .map(|v| Uuid::parse_str(&v).unwrap())
                 .collect(),
+            // created_at が存在しない古いデータは、放送開始時刻で代用する
+            created_at: persisted.created_at.map_or_else(|| start_at.to_utc(), |t| t.to_utc()),
+            trashed_at: persisted.trashed_at.map(|t| t.to_utc()),
+            original: match persisted.original {
+                Some(original) => Some(ProgramFields::from_persisted(original)?),
+                None => None,
+            },
+            edits: persisted
+                .edits
+                .into_iter()
+                .map(FieldEdit::from_persisted)
+                .collect::<Result<_, _>>()?,
+            tags: persisted.tags.into_iter().collect(),
         })
     }
 
@@ -306,11 +409,16 @@ impl Persistence<PersistProgram> for Program {
                 .iter()
                 .map(|id| id.to_hyphenated().encode_lower(&mut Uuid::encode_buffer()).to_string())
                 .collect(),
+            created_at: Some(self.created_at.to_timestamp()),
+            trashed_at: self.trashed_at.map(|t| t.to_timestamp()),
+            original: self.original.as_ref().map(|o| o.persist()),
+            edits: self.edits.iter().map(|e| e.persist()).collect(),
+            tags: self.tags.iter().cloned().collect(),
         }
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct ExtendedEvent {
     key: String,
     value: String,
@@ -324,6 +432,10 @@ impl ExtendedEvent {
         }
     }
 
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
     pub fn exchangeable(&self) -> types::ExtendedEvent {
         types::ExtendedEvent {
             key: self.key.clone(),
@@ -362,10 +474,17 @@ pub struct Video {
     #[serde(with = "crate::serde::uuid")]
     pub storage_id: Uuid,
     pub storage_prefix: String,
+    /// サムネイルの blob のキー
     #[serde(skip)]
-    pub thumbnail: Vec<u8>,
-    #[serde(skip)]
+    pub thumbnail_blob: Option<String>,
+    /// サムネイルは動画と一緒に保存するので、形式だけを書き出す
+    #[serde(default, with = "crate::serde::mime_option")]
     pub thumbnail_mime_type: Option<Mime>,
+    /// ゴミ箱に移動された日時
+    #[serde(default)]
+    pub trashed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub watch_state: WatchState,
 }
 
 impl Video {
@@ -380,8 +499,10 @@ impl Video {
             mime_type: video_header.mime_type.parse().unwrap(),
             storage_id: Uuid::nil(),
             storage_prefix: "".to_string(),
-            thumbnail: Vec::new(),
+            thumbnail_blob: None,
             thumbnail_mime_type: None,
+            trashed_at: None,
+            watch_state: WatchState::default(),
         }
     }
 
@@ -403,9 +524,20 @@ impl Video {
                 .encode_lower(&mut Uuid::encode_buffer())
                 .to_string(),
             prefix: self.storage_prefix.clone(),
+            trashed_at: self.trashed_at.map(|t| t.to_timestamp()),
+            watch_state: Some(self.watch_state.exchangeable()),
         }
     }
 
+    pub fn program_key(&self) -> &ProgramKey {
+        &self.program_id
+    }
+
+    /// 動画を別の番組に付け替える。
+    pub fn move_to(&mut self, program: &Program) {
+        self.program_id = ProgramKey::from_stored_program(program);
+    }
+
     pub fn stringify_id(&self) -> String {
         self.id
             .to_hyphenated()
@@ -429,8 +561,13 @@ impl Persistence<PersistVideo> for Video {
             mime_type: persisted.mime_type.parse()?,
             storage_id: Uuid::parse_str(&persisted.storage_id)?,
             storage_prefix: persisted.storage_prefix,
-            thumbnail: persisted.thumbnail,
+            thumbnail_blob: Some(persisted.thumbnail_blob).filter(|k| !k.is_empty()),
             thumbnail_mime_type: persisted.thumbnail_mime_type.parse().ok(),
+            trashed_at: persisted.trashed_at.map(|t| t.to_utc()),
+            watch_state: match persisted.watch_state {
+                Some(watch_state) => WatchState::from_persisted(watch_state)?,
+                None => WatchState::default(),
+            },
         })
     }
 
@@ -453,12 +590,15 @@ impl Persistence<PersistVideo> for Video {
                 .encode_lower(&mut Uuid::encode_buffer())
                 .to_string(),
             storage_prefix: self.storage_prefix.clone(),
-            thumbnail: self.thumbnail.clone(),
+            thumbnail: vec![],
+            thumbnail_blob: self.thumbnail_blob.clone().unwrap_or_default(),
             thumbnail_mime_type: self
                 .thumbnail_mime_type
                 .as_ref()
                 .map_or_else(|| "", |v| v.essence_str())
                 .to_string(),
+            trashed_at: self.trashed_at.map(|t| t.to_timestamp()),
+            watch_state: Some(self.watch_state.persist()),
         }
     }
 }
@@ -43,6 +43,16 @@ impl ProgramKey {
         }
     }
 
+    /// 放送開始日時が start_at の番組のうち、最も小さいキー。番組の範囲検索の境界に使う。
+    pub fn start_bound(start_at: DateTime<Utc>) -> Self {
+        ProgramKey {
+            start_at,
+            network_id: 0,
+            service_id: 0,
+            event_id: 0,
+        }
+    }
+
     pub fn exchangeable(&self) -> ProgramIdentity {
         ProgramIdentity {
             start_at: Some(prost_types::Timestamp {
@@ -1,28 +1,80 @@
+use crate::backup;
+use crate::blob_store::BlobStore;
+use crate::config::BackendKind;
 use crate::config::Config;
-use crate::program::{Persistence, Program as StoredProgram};
-use crate::program::{ProgramKey, Video as StoredVideo};
+use crate::program::{
+    find_duplicates, open_backend, ChangeFeed, ChangeKind, DatabaseBackend, DuplicateGroup, LoadedData,
+};
+use crate::program::{migrate, migrate_inline_thumbnail, schema_version, PERSIST_MAGIC, SCHEMA_VERSION};
+use crate::program::{
+    ChannelId, ChannelSummary, DisplaySetting, LookupIndex, Registry, ServiceId, ServiceSummary, Statistics,
+};
+use crate::program::{
+    FieldValue, Persistence, Program as StoredProgram, ProgramField, ProgramPage, ProgramQuery, SearchIndex,
+};
+use crate::program::{ProgramKey, ProgramSeries, SeriesIndex, Video as StoredVideo, WatchStateUpdate};
+use chrono::{DateTime, Utc};
 use dtvault_types::shibafu528::dtvault::central::create_program_response::Status as ResponseStatus;
-use dtvault_types::shibafu528::dtvault::central::PersistStore;
+use dtvault_types::shibafu528::dtvault::central::persist_log_entry::Entry;
+use dtvault_types::shibafu528::dtvault::central::{
+    PersistBlob, PersistChannelSetting, PersistProgramAlias, PersistProgramAliases, PersistServiceSetting, PersistStore,
+};
 use dtvault_types::shibafu528::dtvault::Program;
 use fs2::FileExt;
 use mime::Mime;
-use prost::Message;
-use std::collections::BTreeMap;
-use std::io::Write;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 use uuid::Uuid;
 
 type ProgramStoreBackend = BTreeMap<ProgramKey, Arc<StoredProgram>>;
 type VideoStoreBackend = BTreeMap<Uuid, Arc<StoredVideo>>;
+/// 削除した番組と、一緒に削除した動画
+pub type DeletedProgram = (Arc<StoredProgram>, Vec<Arc<StoredVideo>>);
 
 #[derive(thiserror::Error, Debug)]
 #[error("poisoned lock: another task failed inside")]
 pub struct MutexPoisonError;
 
+#[derive(thiserror::Error, Debug)]
+pub enum PersistError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum MetadataWriteError<'a> {
     #[error("Program not found (id = {0})")]
     ProgramNotFound(&'a ProgramKey),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProgramUpdateError<'a> {
+    #[error("Program not found (id = {0})")]
+    ProgramNotFound(&'a ProgramKey),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProgramMergeError<'a> {
+    #[error("Program not found (id = {0})")]
+    ProgramNotFound(&'a ProgramKey),
+    #[error("Cannot merge a program into itself (id = {0})")]
+    SameProgram(&'a ProgramKey),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
     #[error(transparent)]
     Poisoned(#[from] MutexPoisonError),
 }
@@ -33,6 +85,8 @@ pub enum VideoWriteError<'a> {
     ProgramNotFound(&'a ProgramKey),
     #[error("Provider ID `{0}` already exists")]
     AlreadyExists(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
     #[error(transparent)]
     Poisoned(#[from] MutexPoisonError),
 }
@@ -41,10 +95,62 @@ pub enum VideoWriteError<'a> {
 pub enum VideoThumbnailUpdateError {
     #[error("Video not found (id = {0})")]
     VideoNotFound(Uuid),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WatchStateUpdateError {
+    #[error("Video not found (id = {0})")]
+    VideoNotFound(Uuid),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProgramDeleteError<'a> {
+    #[error("Program not found (id = {0})")]
+    ProgramNotFound(&'a ProgramKey),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum VideoDeleteError {
+    #[error("Video not found (id = {0})")]
+    VideoNotFound(Uuid),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TrashError {
+    #[error("Program not found (id = {0})")]
+    ProgramNotFound(ProgramKey),
+    #[error("Video not found (id = {0})")]
+    VideoNotFound(Uuid),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
     #[error(transparent)]
     Poisoned(#[from] MutexPoisonError),
 }
 
+/// ゴミ箱の中身
+pub struct TrashedItems {
+    /// ゴミ箱に移動された番組
+    pub programs: Vec<Arc<StoredProgram>>,
+    /// ゴミ箱に移動された動画のうち、番組がゴミ箱に移動されていないもの
+    pub videos: Vec<Arc<StoredVideo>>,
+}
+
 pub enum FindOrCreateNotice {
     Created,
     AlreadyExists,
@@ -59,10 +165,30 @@ impl Into<i32> for FindOrCreateNotice {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum RestoreError {
+    #[error("Broken backup: {0}")]
+    Broken(#[from] InitializeError),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum InitializeError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("data_dir is already in use by another process ({})", .0.display())]
+    Locked(PathBuf),
+    #[error("Persist error: {0}")]
+    PersistError(#[from] PersistError),
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("Unknown database format (magic = {0:#010x})")]
+    UnknownFormat(u32),
+    #[error("Database schema version {found} is newer than supported version {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
     #[error("Protobuf decode error: {0}")]
     DecodeError(#[from] prost::DecodeError),
     #[error("Protobuf decode error: broken message ({}) in field {}[{}]", .description, .field_name, .index)]
@@ -73,51 +199,341 @@ pub enum InitializeError {
     },
 }
 
+/// PersistStore から復元した内容
+struct Contents {
+    programs: ProgramStoreBackend,
+    videos: VideoStoreBackend,
+    aliases: BTreeMap<ProgramKey, ProgramKey>,
+    registry: Registry,
+    revision: u64,
+}
+
+impl Contents {
+    fn from_persisted(store: PersistStore) -> Result<Self, InitializeError> {
+        let mut programs = ProgramStoreBackend::new();
+        let mut videos = VideoStoreBackend::new();
+        let mut aliases = BTreeMap::new();
+        for (index, persisted) in store.programs.into_iter().enumerate() {
+            let sp = StoredProgram::from_persisted(persisted).map_err(|err| InitializeError::BrokenMessage {
+                field_name: "programs".to_string(),
+                index,
+                description: format!("{}", err),
+            })?;
+            programs.insert(ProgramKey::from_stored_program(&sp), Arc::new(sp));
+        }
+        for (index, persisted) in store.videos.into_iter().enumerate() {
+            let sv = StoredVideo::from_persisted(persisted).map_err(|err| InitializeError::BrokenMessage {
+                field_name: "videos".to_string(),
+                index,
+                description: format!("{}", err),
+            })?;
+            videos.insert(sv.id, Arc::new(sv));
+        }
+        for (index, persisted) in store.aliases.into_iter().enumerate() {
+            let broken = |field: &str| InitializeError::BrokenMessage {
+                field_name: "aliases".to_string(),
+                index,
+                description: format!("missing {}", field),
+            };
+            let alias = persisted.alias.ok_or_else(|| broken("alias"))?;
+            let program_id = persisted.program_id.ok_or_else(|| broken("program_id"))?;
+            let convert = |key| {
+                ProgramKey::from_persisted(key).map_err(|err| InitializeError::BrokenMessage {
+                    field_name: "aliases".to_string(),
+                    index,
+                    description: format!("{}", err),
+                })
+            };
+            aliases.insert(convert(alias)?, convert(program_id)?);
+        }
+
+        Ok(Contents {
+            programs,
+            videos,
+            aliases,
+            registry: Registry::from_persisted(store.service_settings, store.channel_settings),
+            revision: store.revision,
+        })
+    }
+}
+
+/// ある時点の ProgramStore の内容。変換や書き出しはロックを外してから行う。
+pub struct StoreSnapshot {
+    pub revision: u64,
+    pub programs: Vec<Arc<StoredProgram>>,
+    pub videos: Vec<Arc<StoredVideo>>,
+    aliases: Vec<PersistProgramAlias>,
+    service_settings: Vec<PersistServiceSetting>,
+    channel_settings: Vec<PersistChannelSetting>,
+}
+
+impl StoreSnapshot {
+    pub fn persist(&self) -> PersistStore {
+        PersistStore {
+            magic: PERSIST_MAGIC,
+            schema_version: SCHEMA_VERSION,
+            programs: self.programs.iter().map(|p| p.persist()).collect(),
+            videos: self.videos.iter().map(|v| v.persist()).collect(),
+            revision: self.revision,
+            aliases: self.aliases.clone(),
+            service_settings: self.service_settings.clone(),
+            channel_settings: self.channel_settings.clone(),
+        }
+    }
+}
+
 pub struct ProgramStore {
     config: Arc<Config>,
     programs: RwLock<ProgramStoreBackend>,
     videos: RwLock<VideoStoreBackend>,
+    /// 統合によって消えた番組の識別子から、統合先の番組の識別子への対応
+    aliases: RwLock<BTreeMap<ProgramKey, ProgramKey>>,
+    registry: RwLock<Registry>,
+    search_index: RwLock<SearchIndex>,
+    series_index: RwLock<SeriesIndex>,
+    lookup_index: RwLock<LookupIndex>,
+    change_feed: ChangeFeed,
+    backend: Box<dyn DatabaseBackend>,
+    blobs: BlobStore,
+    /// data_dir を他のプロセスと共有しないためのロック。ProgramStore が破棄されるまで保持する。
+    _data_dir_lock: File,
 }
 
 impl ProgramStore {
     pub fn new(config: Arc<Config>) -> Result<Self, InitializeError> {
-        let mut programs = ProgramStoreBackend::new();
-        let mut videos = VideoStoreBackend::new();
+        let kind = config.database.backend();
+        Self::open(config, kind)
+    }
 
-        let path = config.database.programs_file_path();
-        if path.is_file() {
-            let bin = std::fs::read(path)?;
-            let store = PersistStore::decode(&bin[..])?;
-            for (index, persisted) in store.programs.into_iter().enumerate() {
-                let sp = StoredProgram::from_persisted(persisted).map_err(|err| InitializeError::BrokenMessage {
-                    field_name: "programs".to_string(),
-                    index,
-                    description: format!("{}", err),
-                })?;
-                programs.insert(ProgramKey::from_stored_program(&sp), Arc::new(sp));
+    /// 設定とは異なるバックエンドを指定して開く。
+    pub fn open(config: Arc<Config>, kind: BackendKind) -> Result<Self, InitializeError> {
+        Self::open_with(config, kind, true)
+    }
+
+    /// 保存されている内容を読み込まずに空の状態で開く。壊れたデータベースを作り直す場合に使う。
+    /// 保存されている内容は、compact などで書き込むまではそのまま残る。
+    pub fn open_empty(config: Arc<Config>) -> Result<Self, InitializeError> {
+        let kind = config.database.backend();
+        Self::open_with(config, kind, false)
+    }
+
+    fn open_with(config: Arc<Config>, kind: BackendKind, load: bool) -> Result<Self, InitializeError> {
+        let lock_path = config.database.lock_file_path();
+        let data_dir_lock = File::create(&lock_path)?;
+        if data_dir_lock.try_lock_exclusive().is_err() {
+            return Err(InitializeError::Locked(lock_path));
+        }
+        let blobs = BlobStore::open(config.database.blobs_dir())?;
+        let mut migrated = false;
+        let mut migrated_thumbnails = 0;
+
+        let backend = open_backend(&config.database, kind)?;
+        let loaded = if load {
+            backend.load()?
+        } else {
+            LoadedData {
+                snapshot: None,
+                journal: vec![],
             }
-            for (index, persisted) in store.videos.into_iter().enumerate() {
-                let sv = StoredVideo::from_persisted(persisted).map_err(|err| InitializeError::BrokenMessage {
-                    field_name: "videos".to_string(),
-                    index,
-                    description: format!("{}", err),
-                })?;
-                videos.insert(sv.id, Arc::new(sv));
+        };
+        let contents = if let Some(mut store) = loaded.snapshot {
+            let version = schema_version(&store)?;
+            if version < SCHEMA_VERSION {
+                // 移行前の形式のまま残す
+                let backup_path = backend.backup(&format!("v{}", version))?;
+                println!("Database backed up to {}", backup_path.display());
+                migrate(&mut store, &blobs)?;
+                migrated = true;
+            }
+
+            let loaded = Contents::from_persisted(store)?;
+            println!(
+                "{} programs, {} videos loaded.",
+                loaded.programs.len(),
+                loaded.videos.len()
+            );
+            loaded
+        } else {
+            Contents {
+                programs: ProgramStoreBackend::new(),
+                videos: VideoStoreBackend::new(),
+                aliases: BTreeMap::new(),
+                registry: Registry::new(),
+                revision: 0,
             }
+        };
+        let Contents {
+            mut programs,
+            mut videos,
+            mut aliases,
+            mut registry,
+            mut revision,
+        } = contents;
 
-            println!("{} programs, {} videos loaded.", programs.len(), videos.len());
+        // スナップショットより後の変更を適用する
+        let snapshot_revision = revision;
+        let mut replayed = 0;
+        for (index, entry) in loaded.journal.into_iter().enumerate() {
+            if entry.revision < snapshot_revision {
+                continue;
+            }
+            let broken = |description: String| InitializeError::BrokenMessage {
+                field_name: "journal".to_string(),
+                index,
+                description,
+            };
+            match entry.entry.ok_or_else(|| broken("missing entry".to_string()))? {
+                Entry::PutProgram(persisted) => {
+                    let sp = StoredProgram::from_persisted(persisted).map_err(|err| broken(format!("{}", err)))?;
+                    programs.insert(ProgramKey::from_stored_program(&sp), Arc::new(sp));
+                }
+                Entry::DeleteProgram(persisted) => {
+                    let key = ProgramKey::from_persisted(persisted).map_err(|err| broken(format!("{}", err)))?;
+                    programs.remove(&key);
+                }
+                Entry::PutVideo(mut persisted) => {
+                    if migrate_inline_thumbnail(&blobs, &mut persisted)? {
+                        migrated_thumbnails += 1;
+                    }
+                    let sv = StoredVideo::from_persisted(persisted).map_err(|err| broken(format!("{}", err)))?;
+                    videos.insert(sv.id, Arc::new(sv));
+                }
+                Entry::DeleteVideo(id) => {
+                    let id = Uuid::parse_str(&id).map_err(|err| broken(format!("{}", err)))?;
+                    videos.remove(&id);
+                }
+                Entry::Aliases(persisted) => {
+                    aliases.clear();
+                    for alias in persisted.aliases {
+                        let (alias, program_id) = match (alias.alias, alias.program_id) {
+                            (Some(a), Some(p)) => (a, p),
+                            _ => return Err(broken("missing alias".to_string())),
+                        };
+                        let alias = ProgramKey::from_persisted(alias).map_err(|err| broken(format!("{}", err)))?;
+                        let program_id =
+                            ProgramKey::from_persisted(program_id).map_err(|err| broken(format!("{}", err)))?;
+                        aliases.insert(alias, program_id);
+                    }
+                }
+                Entry::ServiceSetting(persisted) => registry.apply_service(persisted),
+                Entry::ChannelSetting(persisted) => registry.apply_channel(persisted),
+            }
+            revision = revision.max(entry.revision);
+            replayed += 1;
+        }
+        if replayed > 0 {
+            println!("{} changes replayed from journal.", replayed);
         }
 
-        Ok(ProgramStore {
+        let (search_index, series_index, lookup_index) = build_indexes(&programs, &videos, &config);
+
+        let store = ProgramStore {
             config,
             programs: RwLock::new(programs),
             videos: RwLock::new(videos),
-        })
+            aliases: RwLock::new(aliases),
+            registry: RwLock::new(registry),
+            search_index: RwLock::new(search_index),
+            series_index: RwLock::new(series_index),
+            lookup_index: RwLock::new(lookup_index),
+            change_feed: ChangeFeed::new(revision),
+            backend,
+            blobs,
+            _data_dir_lock: data_dir_lock,
+        };
+
+        // 移行した内容を現在の形式で書き直す
+        if migrated || migrated_thumbnails > 0 {
+            store.compact()?;
+        }
+
+        Ok(store)
+    }
+
+    pub fn change_feed(&self) -> &ChangeFeed {
+        &self.change_feed
     }
 
-    pub fn all(&self) -> Result<Vec<Arc<StoredProgram>>, MutexPoisonError> {
+    /// 索引で候補を絞り込めるフィルタの場合は、全件を走査せずに候補だけを評価する。
+    pub fn query(&self, query: &ProgramQuery) -> Result<ProgramPage, MutexPoisonError> {
         let store = self.programs.read().map_err(|_| MutexPoisonError)?;
-        Ok(store.values().cloned().collect())
+        let filter = &query.filter;
+        if !filter.services.is_empty() {
+            let lookup_index = self.lookup_index.read().map_err(|_| MutexPoisonError)?;
+            let keys = filter
+                .services
+                .iter()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .flat_map(|service| lookup_index.programs_of_service(*service));
+            return Ok(query.execute(keys.filter_map(|key| store.get(key))));
+        }
+        if filter.start_at_from.is_some() || filter.start_at_to.is_some() {
+            let range = Self::start_at_range(filter.start_at_from, filter.start_at_to);
+            return Ok(query.execute(store.range(range).map(|(_, sp)| sp)));
+        }
+        Ok(query.execute(store.values()))
+    }
+
+    fn start_at_range(
+        from: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> (Bound<ProgramKey>, Bound<ProgramKey>) {
+        (
+            from.map_or(Bound::Unbounded, |t| Bound::Included(ProgramKey::start_bound(t))),
+            until.map_or(Bound::Unbounded, |t| Bound::Excluded(ProgramKey::start_bound(t))),
+        )
+    }
+
+    /// 全文検索を行い、スコアの高い順に最大 limit 件の番組を返す。
+    pub fn search(&self, query: &str, limit: Option<usize>) -> Result<Vec<Arc<StoredProgram>>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let search_index = self.search_index.read().map_err(|_| MutexPoisonError)?;
+        let hits = search_index.search(query);
+        Ok(hits
+            .iter()
+            .filter_map(|(key, _)| programs.get(key).cloned())
+            .filter(|sp| sp.trashed_at.is_none())
+            .take(limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// シリーズ名順に全てのシリーズを返す。ゴミ箱の中の番組は含まない。
+    pub fn list_series(&self) -> Result<Vec<ProgramSeries>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let series_index = self.series_index.read().map_err(|_| MutexPoisonError)?;
+        Ok(series_index
+            .iter()
+            .map(|(title, keys)| Self::collect_series(&programs, &series_index, title, keys))
+            .filter(|series| !series.programs.is_empty())
+            .collect())
+    }
+
+    pub fn find_series(&self, series_title: &str) -> Result<Option<ProgramSeries>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let series_index = self.series_index.read().map_err(|_| MutexPoisonError)?;
+        Ok(series_index
+            .get(series_title)
+            .map(|keys| Self::collect_series(&programs, &series_index, series_title, keys))
+            .filter(|series| !series.programs.is_empty()))
+    }
+
+    fn collect_series<'a, I: IntoIterator<Item = &'a ProgramKey>>(
+        programs: &ProgramStoreBackend,
+        series_index: &SeriesIndex,
+        title: &str,
+        keys: I,
+    ) -> ProgramSeries {
+        ProgramSeries {
+            title: title.to_string(),
+            programs: keys
+                .into_iter()
+                .filter_map(|key| match (programs.get(key), series_index.parsed_title(key)) {
+                    (Some(sp), Some(parsed)) if sp.trashed_at.is_none() => Some((sp.clone(), parsed.clone())),
+                    _ => None,
+                })
+                .collect(),
+        }
     }
 
     pub fn find(&self, key: &ProgramKey) -> Result<Option<Arc<StoredProgram>>, MutexPoisonError> {
@@ -125,10 +541,17 @@ impl ProgramStore {
         Ok(store.get(key).map(Arc::clone))
     }
 
-    pub fn find_or_create(
-        &self,
-        program: Program,
-    ) -> Result<(Arc<StoredProgram>, FindOrCreateNotice), MutexPoisonError> {
+    /// 番組を探し、見つからない場合は統合前の識別子として統合先の番組を探す。
+    pub fn find_with_alias(&self, key: &ProgramKey) -> Result<Option<Arc<StoredProgram>>, MutexPoisonError> {
+        let store = self.programs.read().map_err(|_| MutexPoisonError)?;
+        if let Some(sp) = store.get(key) {
+            return Ok(Some(sp.clone()));
+        }
+        let aliases = self.aliases.read().map_err(|_| MutexPoisonError)?;
+        Ok(aliases.get(key).and_then(|target| store.get(target)).cloned())
+    }
+
+    pub fn find_or_create(&self, program: Program) -> Result<(Arc<StoredProgram>, FindOrCreateNotice), PersistError> {
         self.mutation(|skip| {
             let mut store = self.programs.write().map_err(|_| MutexPoisonError)?;
             let key = ProgramKey::from_program(&program);
@@ -140,8 +563,17 @@ impl ProgramStore {
                     Arc::new(StoredProgram::from_exchanged(program.clone()).unwrap())
                 })
                 .clone();
-            if let FindOrCreateNotice::AlreadyExists = notice {
-                *skip = true;
+            match notice {
+                FindOrCreateNotice::Created => {
+                    let mut search_index = self.search_index.write().map_err(|_| MutexPoisonError)?;
+                    let mut series_index = self.series_index.write().map_err(|_| MutexPoisonError)?;
+                    let mut lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
+                    search_index.insert(&sp);
+                    series_index.insert(&sp, &self.config.series_rules);
+                    lookup_index.insert_program(&sp);
+                    self.publish_program(ChangeKind::ProgramCreated, &sp);
+                }
+                FindOrCreateNotice::AlreadyExists => *skip = true,
             }
             Ok((sp, notice))
         })
@@ -152,6 +584,75 @@ impl ProgramStore {
         Ok(video.get(id).map(|v| v.clone()))
     }
 
+    /// 録画システム上の ID が provider_id の動画を返す。
+    pub fn find_videos_by_provider_id(
+        &self,
+        provider_id: &str,
+        include_trashed: bool,
+    ) -> Result<Vec<Arc<StoredVideo>>, MutexPoisonError> {
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+        let lookup_index = self.lookup_index.read().map_err(|_| MutexPoisonError)?;
+        Ok(lookup_index
+            .videos_of_provider_id(provider_id)
+            .filter_map(|id| videos.get(id))
+            .filter(|v| include_trashed || v.trashed_at.is_none())
+            .cloned()
+            .collect())
+    }
+
+    /// storage_id のストレージに保存されている動画を返す。
+    pub fn find_videos_by_storage_id(
+        &self,
+        storage_id: &Uuid,
+        include_trashed: bool,
+    ) -> Result<Vec<Arc<StoredVideo>>, MutexPoisonError> {
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+        let lookup_index = self.lookup_index.read().map_err(|_| MutexPoisonError)?;
+        Ok(lookup_index
+            .videos_of_storage_id(storage_id)
+            .filter_map(|id| videos.get(id))
+            .filter(|v| include_trashed || v.trashed_at.is_none())
+            .cloned()
+            .collect())
+    }
+
+    /// サービスの番組を放送日時順に返す。
+    pub fn find_programs_by_service(
+        &self,
+        service: ServiceId,
+        include_trashed: bool,
+    ) -> Result<Vec<Arc<StoredProgram>>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let lookup_index = self.lookup_index.read().map_err(|_| MutexPoisonError)?;
+        Ok(lookup_index
+            .programs_of_service(service)
+            .filter_map(|key| programs.get(key))
+            .filter(|sp| include_trashed || sp.trashed_at.is_none())
+            .cloned()
+            .collect())
+    }
+
+    /// 放送開始日時が from 以降、until より前の番組を放送日時順に返す。None の場合はその方向に制限しない。
+    pub fn find_programs_by_start_at(
+        &self,
+        from: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        include_trashed: bool,
+    ) -> Result<Vec<Arc<StoredProgram>>, MutexPoisonError> {
+        if let (Some(from), Some(until)) = (from, until) {
+            if from >= until {
+                return Ok(vec![]);
+            }
+        }
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        Ok(programs
+            .range(Self::start_at_range(from, until))
+            .map(|(_, sp)| sp)
+            .filter(|sp| include_trashed || sp.trashed_at.is_none())
+            .cloned()
+            .collect())
+    }
+
     pub fn find_videos(&self, ids: &[Uuid]) -> Result<Vec<Option<Arc<StoredVideo>>>, MutexPoisonError> {
         let video = self.videos.read().map_err(|_| MutexPoisonError)?;
         let mut result = vec![];
@@ -173,28 +674,85 @@ impl ProgramStore {
         self.mutation(|_| {
             let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
             let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
             let mut program = match programs.get(key) {
                 Some(p) => (**p).clone(),
                 None => return Err(VideoWriteError::ProgramNotFound(key)),
             };
 
-            for video_id in program.video_ids() {
-                if let Some(v) = videos.get(video_id) {
-                    if v.provider_id == video.provider_id {
-                        return Err(VideoWriteError::AlreadyExists(video.provider_id.clone()));
-                    }
-                }
+            let duplicated = lookup_index
+                .videos_of_provider_id(&video.provider_id)
+                .any(|id| program.video_ids().contains(id));
+            if duplicated {
+                return Err(VideoWriteError::AlreadyExists(video.provider_id.clone()));
             }
 
             let video = Arc::new(video);
             program.video_ids_mut().push(video.id);
-            programs.insert(key.clone(), Arc::new(program));
+            let program = Arc::new(program);
+            programs.insert(key.clone(), program.clone());
             videos.insert(video.id, video.clone()); // TODO: VideoID重複チェック
+            lookup_index.insert_video(&video);
+            self.publish_video(ChangeKind::VideoCreated, &video);
+            self.record(Entry::PutProgram(program.persist()));
 
             Ok(video)
         })
     }
 
+    /// ストレージから見つかった番組と動画 (とサムネイル) を取り込む。
+    /// 同じ番組 (統合済みの場合は統合先) が既にある場合は、その番組に動画だけを追加する。
+    pub fn import_video(
+        &self,
+        program: StoredProgram,
+        mut video: StoredVideo,
+        thumbnail: Option<Vec<u8>>,
+    ) -> Result<(Arc<StoredProgram>, Arc<StoredVideo>), PersistError> {
+        video.thumbnail_blob = match thumbnail {
+            Some(data) => Some(self.blobs.put(&data)?),
+            None => None,
+        };
+        self.mutation(|_| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let aliases = self.aliases.read().map_err(|_| MutexPoisonError)?;
+            let mut lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
+
+            let mut key = ProgramKey::from_stored_program(&program);
+            if let Some(target) = aliases.get(&key) {
+                key = target.clone();
+            }
+            let (mut program, created) = match programs.get(&key) {
+                Some(p) => ((**p).clone(), false),
+                None => (program, true),
+            };
+            video.move_to(&program);
+            if !program.video_ids().contains(&video.id) {
+                program.video_ids_mut().push(video.id);
+            }
+
+            let program = Arc::new(program);
+            let video = Arc::new(video);
+            programs.insert(key, program.clone());
+            videos.insert(video.id, video.clone());
+            lookup_index.insert_video(&video);
+            if created {
+                let mut search_index = self.search_index.write().map_err(|_| MutexPoisonError)?;
+                let mut series_index = self.series_index.write().map_err(|_| MutexPoisonError)?;
+                search_index.insert(&program);
+                series_index.insert(&program, &self.config.series_rules);
+                lookup_index.insert_program(&program);
+                self.publish_program(ChangeKind::ProgramCreated, &program);
+                self.publish_video(ChangeKind::VideoCreated, &video);
+            } else {
+                self.publish_video(ChangeKind::VideoCreated, &video);
+                self.record(Entry::PutProgram(program.persist()));
+            }
+
+            Ok((program, video))
+        })
+    }
+
     pub fn update_program_metadata<'a>(
         &'a self,
         key: &'a ProgramKey,
@@ -208,7 +766,9 @@ impl ProgramStore {
                     let mut sp = (**sp).clone();
                     sp.metadata_mut()
                         .insert(metadata_key.to_string(), metadata_value.to_string());
-                    store.insert(key.clone(), Arc::new(sp));
+                    let sp = Arc::new(sp);
+                    store.insert(key.clone(), sp.clone());
+                    self.publish_program(ChangeKind::ProgramUpdated, &sp);
                     Ok(())
                 }
                 None => Err(MetadataWriteError::ProgramNotFound(key)),
@@ -216,56 +776,1200 @@ impl ProgramStore {
         })
     }
 
-    pub fn update_video_thumbnail(
-        &self,
-        id: &Uuid,
-        bin: Vec<u8>,
-        mime_type: Mime,
-    ) -> Result<(), VideoThumbnailUpdateError> {
-        self.mutation(|_| {
-            let mut store = self.videos.write().map_err(|_| MutexPoisonError)?;
-            match store.get(id) {
-                Some(video) => {
-                    let mut video = (**video).clone();
-                    video.thumbnail = bin;
-                    video.thumbnail_mime_type = Some(mime_type);
-                    store.insert(id.clone(), Arc::new(video));
-                    Ok(())
-                }
-                None => Err(VideoThumbnailUpdateError::VideoNotFound(id.clone())),
+    /// 番組のフィールドを書き換え、編集履歴に記録する。
+    pub fn update_program<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        values: Vec<FieldValue>,
+        at: DateTime<Utc>,
+    ) -> Result<Arc<StoredProgram>, ProgramUpdateError<'a>> {
+        self.edit_program(key, |_| values, at)
+    }
+
+    /// 番組のフィールドを放送時の値に戻す。戻した操作も編集履歴に記録する。
+    pub fn revert_program<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        fields: &[ProgramField],
+        at: DateTime<Utc>,
+    ) -> Result<Arc<StoredProgram>, ProgramUpdateError<'a>> {
+        self.edit_program(
+            key,
+            |sp| {
+                let original = sp.original();
+                fields.iter().map(|f| original.get(*f)).collect()
+            },
+            at,
+        )
+    }
+
+    fn edit_program<'a, F: FnOnce(&StoredProgram) -> Vec<FieldValue>>(
+        &'a self,
+        key: &'a ProgramKey,
+        values: F,
+        at: DateTime<Utc>,
+    ) -> Result<Arc<StoredProgram>, ProgramUpdateError<'a>> {
+        self.mutation(|skip| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut search_index = self.search_index.write().map_err(|_| MutexPoisonError)?;
+            let mut series_index = self.series_index.write().map_err(|_| MutexPoisonError)?;
+            let sp = match programs.get(key) {
+                Some(sp) => sp.clone(),
+                None => return Err(ProgramUpdateError::ProgramNotFound(key)),
+            };
+
+            let mut edited = (*sp).clone();
+            let mut changed = false;
+            for value in values(&sp) {
+                changed |= edited.edit(value, at);
+            }
+            if !changed {
+                *skip = true;
+                return Ok(sp);
             }
+
+            let edited = Arc::new(edited);
+            programs.insert(key.clone(), edited.clone());
+            search_index.insert(&edited);
+            series_index.insert(&edited, &self.config.series_rules);
+            self.publish_program(ChangeKind::ProgramUpdated, &edited);
+
+            Ok(edited)
         })
     }
 
-    fn mutation<F: FnOnce(&mut bool) -> Result<T, U>, T, U: From<MutexPoisonError>>(&self, op: F) -> Result<T, U> {
-        let mut skip = false;
-        let result = op(&mut skip)?;
-        if !skip {
-            self.persist()?;
-        }
-        Ok(result)
+    /// 番組にタグを追加する。
+    pub fn add_tags<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        tags: &[String],
+    ) -> Result<Arc<StoredProgram>, ProgramUpdateError<'a>> {
+        self.update_tags(key, |current| {
+            let mut changed = false;
+            for tag in tags {
+                changed |= current.insert(tag.clone());
+            }
+            changed
+        })
     }
 
-    // TODO: 非同期化する
-    fn persist(&self) -> Result<(), MutexPoisonError> {
-        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
-        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+    /// 番組からタグを取り除く。
+    pub fn remove_tags<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        tags: &[String],
+    ) -> Result<Arc<StoredProgram>, ProgramUpdateError<'a>> {
+        self.update_tags(key, |current| {
+            let mut changed = false;
+            for tag in tags {
+                changed |= current.remove(tag);
+            }
+            changed
+        })
+    }
 
-        let path = self.config.database.programs_file_path();
-        let file = std::fs::File::create(path).unwrap();
-        file.lock_exclusive().unwrap();
+    fn update_tags<'a, F: FnOnce(&mut BTreeSet<String>) -> bool>(
+        &'a self,
+        key: &'a ProgramKey,
+        op: F,
+    ) -> Result<Arc<StoredProgram>, ProgramUpdateError<'a>> {
+        self.mutation(|skip| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let sp = match programs.get(key) {
+                Some(sp) => sp.clone(),
+                None => return Err(ProgramUpdateError::ProgramNotFound(key)),
+            };
 
-        let mut writer = std::io::BufWriter::new(&file);
-        let persisted = PersistStore {
-            programs: programs.values().map(|p| p.persist()).collect(),
-            videos: videos.values().map(|v| v.persist()).collect(),
-        };
-        let mut buf: Vec<u8> = vec![];
-        persisted.encode(&mut buf).unwrap();
-        writer.write(&buf).unwrap();
-        writer.flush().unwrap();
+            let mut updated = (*sp).clone();
+            if !op(updated.tags_mut()) {
+                *skip = true;
+                return Ok(sp);
+            }
+            let updated = Arc::new(updated);
+            programs.insert(key.clone(), updated.clone());
+            self.publish_program(ChangeKind::ProgramUpdated, &updated);
+
+            Ok(updated)
+        })
+    }
+
+    /// 使われている全てのタグと、そのタグが付いている番組の数を返す。ゴミ箱の中の番組は数えない。
+    pub fn tags(&self) -> Result<BTreeMap<String, usize>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let mut tags = BTreeMap::new();
+        for sp in programs.values().filter(|sp| sp.trashed_at.is_none()) {
+            for tag in sp.tags() {
+                *tags.entry(tag.clone()).or_insert(0) += 1;
+            }
+        }
+        Ok(tags)
+    }
+
+    /// 複数のメタデータの更新と削除をまとめて行う。
+    pub fn batch_update_program_metadata<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        values: &HashMap<String, String>,
+        delete_keys: &[String],
+    ) -> Result<(), MetadataWriteError<'a>> {
+        self.mutation(|_| {
+            let mut store = self.programs.write().map_err(|_| MutexPoisonError)?;
+            match store.get(key) {
+                Some(sp) => {
+                    let mut sp = (**sp).clone();
+                    let metadata = sp.metadata_mut();
+                    for delete_key in delete_keys {
+                        metadata.remove(delete_key);
+                    }
+                    metadata.extend(values.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    let sp = Arc::new(sp);
+                    store.insert(key.clone(), sp.clone());
+                    self.publish_program(ChangeKind::ProgramUpdated, &sp);
+                    Ok(())
+                }
+                None => Err(MetadataWriteError::ProgramNotFound(key)),
+            }
+        })
+    }
+
+    /// メタデータを削除する。キーが存在しなかった場合は false を返す。
+    pub fn delete_program_metadata<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        metadata_key: &str,
+    ) -> Result<bool, MetadataWriteError<'a>> {
+        self.mutation(|skip| {
+            let mut store = self.programs.write().map_err(|_| MutexPoisonError)?;
+            match store.get(key) {
+                Some(sp) if !sp.metadata().contains_key(metadata_key) => {
+                    *skip = true;
+                    Ok(false)
+                }
+                Some(sp) => {
+                    let mut sp = (**sp).clone();
+                    sp.metadata_mut().remove(metadata_key);
+                    let sp = Arc::new(sp);
+                    store.insert(key.clone(), sp.clone());
+                    self.publish_program(ChangeKind::ProgramUpdated, &sp);
+                    Ok(true)
+                }
+                None => Err(MetadataWriteError::ProgramNotFound(key)),
+            }
+        })
+    }
+
+    /// 指定したキーのメタデータを持つ番組を放送日時順に返す。value を指定した場合は値も一致するものだけを返す。
+    pub fn find_programs_by_metadata(
+        &self,
+        metadata_key: &str,
+        value: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Arc<StoredProgram>>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        Ok(programs
+            .values()
+            .filter(|sp| sp.trashed_at.is_none())
+            .filter(|sp| match (sp.metadata().get(metadata_key), value) {
+                (Some(v), Some(value)) => v == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            })
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    pub fn update_video_thumbnail(
+        &self,
+        id: &Uuid,
+        bin: Vec<u8>,
+        mime_type: Mime,
+    ) -> Result<(), VideoThumbnailUpdateError> {
+        self.mutation(|_| {
+            let mut store = self.videos.write().map_err(|_| MutexPoisonError)?;
+            match store.get(id) {
+                Some(video) => {
+                    let mut video = (**video).clone();
+                    video.thumbnail_blob = Some(self.blobs.put(&bin)?);
+                    video.thumbnail_mime_type = Some(mime_type);
+                    let video = Arc::new(video);
+                    store.insert(*id, video.clone());
+                    self.publish_video(ChangeKind::ThumbnailUpdated, &video);
+                    Ok(())
+                }
+                None => Err(VideoThumbnailUpdateError::VideoNotFound(*id)),
+            }
+        })
+    }
+
+    pub fn update_watch_state(
+        &self,
+        id: &Uuid,
+        update: &WatchStateUpdate,
+        at: DateTime<Utc>,
+    ) -> Result<Arc<StoredVideo>, WatchStateUpdateError> {
+        self.mutation(|_| {
+            let mut store = self.videos.write().map_err(|_| MutexPoisonError)?;
+            match store.get(id) {
+                Some(video) => {
+                    let mut video = (**video).clone();
+                    video.watch_state.apply(update, at);
+                    let video = Arc::new(video);
+                    store.insert(*id, video.clone());
+                    self.publish_video(ChangeKind::VideoUpdated, &video);
+                    Ok(video)
+                }
+                None => Err(WatchStateUpdateError::VideoNotFound(*id)),
+            }
+        })
+    }
+
+    /// 動画があり、どの動画も視聴済みになっていない番組を放送日時順に返す。ゴミ箱の中の番組と動画は対象外。
+    pub fn unwatched_programs(&self, limit: Option<usize>) -> Result<Vec<Arc<StoredProgram>>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+        Ok(programs
+            .values()
+            .filter(|sp| sp.trashed_at.is_none())
+            .filter(|sp| {
+                let mut available = sp
+                    .video_ids()
+                    .iter()
+                    .filter_map(|id| videos.get(id))
+                    .filter(|v| v.trashed_at.is_none())
+                    .peekable();
+                available.peek().is_some() && available.all(|v| !v.watch_state.watched)
+            })
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    /// 同じ内容だと思われる番組のグループを返す。ゴミ箱の中の番組は対象外。
+    pub fn find_duplicates(&self) -> Result<Vec<DuplicateGroup>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        Ok(find_duplicates(programs.values()))
+    }
+
+    /// groups に含まれる番組のメタデータ key にグループの識別子を書き込み、
+    /// どのグループにも含まれなくなった番組からは key を削除する。更新した番組の数を返す。
+    pub fn mark_duplicates(&self, groups: &[DuplicateGroup], key: &str) -> Result<usize, PersistError> {
+        let marks: BTreeMap<ProgramKey, String> = groups
+            .iter()
+            .flat_map(|g| {
+                let id = g.id();
+                g.programs
+                    .iter()
+                    .map(move |p| (ProgramKey::from_stored_program(p), id.clone()))
+            })
+            .collect();
+
+        self.mutation(|skip| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let updates: Vec<(ProgramKey, Option<&String>)> = programs
+                .iter()
+                .filter_map(|(pk, sp)| {
+                    let mark = marks.get(pk);
+                    if sp.metadata().get(key) == mark {
+                        None
+                    } else {
+                        Some((pk.clone(), mark))
+                    }
+                })
+                .collect();
+
+            for (pk, mark) in &updates {
+                let mut sp = (*programs[pk]).clone();
+                match mark {
+                    Some(id) => sp.metadata_mut().insert(key.to_string(), id.to_string()),
+                    None => sp.metadata_mut().remove(key),
+                };
+                let sp = Arc::new(sp);
+                programs.insert(pk.clone(), sp.clone());
+                self.publish_program(ChangeKind::ProgramUpdated, &sp);
+            }
+            *skip = updates.is_empty();
+            Ok(updates.len())
+        })
+    }
+
+    /// source の動画・メタデータ・タグを target に移動して source を削除し、source から target への別名を残す。
+    /// メタデータのキーが重複する場合は target の値を残す。
+    pub fn merge_programs<'a>(
+        &'a self,
+        source: &'a ProgramKey,
+        target: &'a ProgramKey,
+    ) -> Result<Arc<StoredProgram>, ProgramMergeError<'a>> {
+        if source == target {
+            return Err(ProgramMergeError::SameProgram(source));
+        }
+
+        self.mutation(|_| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut aliases = self.aliases.write().map_err(|_| MutexPoisonError)?;
+            let mut search_index = self.search_index.write().map_err(|_| MutexPoisonError)?;
+            let mut series_index = self.series_index.write().map_err(|_| MutexPoisonError)?;
+            let mut lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
+            let mut tp = match programs.get(target) {
+                Some(tp) => (**tp).clone(),
+                None => return Err(ProgramMergeError::ProgramNotFound(target)),
+            };
+            let sp = match programs.remove(source) {
+                Some(sp) => sp,
+                None => return Err(ProgramMergeError::ProgramNotFound(source)),
+            };
+
+            for video_id in sp.video_ids() {
+                if let Some(video) = videos.get(video_id) {
+                    let mut video = (**video).clone();
+                    video.move_to(&tp);
+                    let video = Arc::new(video);
+                    videos.insert(video.id, video.clone());
+                    self.publish_video(ChangeKind::VideoUpdated, &video);
+                }
+                if !tp.video_ids().contains(video_id) {
+                    tp.video_ids_mut().push(*video_id);
+                }
+            }
+            for (key, value) in sp.metadata() {
+                tp.metadata_mut().entry(key.clone()).or_insert_with(|| value.clone());
+            }
+            tp.tags_mut().extend(sp.tags().iter().cloned());
+            search_index.remove(source);
+            series_index.remove(source);
+            lookup_index.remove_program(&sp);
+            self.publish_program(ChangeKind::ProgramDeleted, &sp);
+
+            // source を指していた別名も target に向け直し、別名を辿るのが常に1回で済むようにする
+            for alias_target in aliases.values_mut() {
+                if alias_target == source {
+                    *alias_target = target.clone();
+                }
+            }
+            aliases.remove(target);
+            aliases.insert(source.clone(), target.clone());
+            self.record(Entry::Aliases(PersistProgramAliases {
+                aliases: persist_aliases(&aliases),
+            }));
+
+            let tp = Arc::new(tp);
+            programs.insert(target.clone(), tp.clone());
+            self.publish_program(ChangeKind::ProgramUpdated, &tp);
+
+            Ok(tp)
+        })
+    }
+
+    pub fn statistics(&self) -> Result<Statistics, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+        Ok(Statistics::compute(programs.iter(), videos.values()))
+    }
+
+    /// 並び順に全てのサービスを返す。
+    pub fn list_services(&self) -> Result<Vec<ServiceSummary>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let registry = self.registry.read().map_err(|_| MutexPoisonError)?;
+        Ok(registry.services(programs.values()))
+    }
+
+    pub fn find_service(&self, id: ServiceId) -> Result<Option<ServiceSummary>, PersistError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let registry = self.registry.read().map_err(|_| MutexPoisonError)?;
+        Ok(registry
+            .services(programs.values().filter(|p| (p.network_id, p.service_id) == id))
+            .pop())
+    }
+
+    /// サービスの表示設定を変更する。サービスが存在しない場合は何もせずに None を返す。
+    pub fn update_service(
+        &self,
+        id: ServiceId,
+        setting: DisplaySetting,
+    ) -> Result<Option<ServiceSummary>, PersistError> {
+        self.mutation(|skip| {
+            let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+            let mut registry = self.registry.write().map_err(|_| MutexPoisonError)?;
+            let filter = |p: &&Arc<StoredProgram>| (p.network_id, p.service_id) == id;
+            if registry.services(programs.values().filter(filter)).is_empty() {
+                *skip = true;
+                return Ok(None);
+            }
+            registry.set_service(id, setting);
+            self.record(Entry::ServiceSetting(registry.persist_service(id)));
+            Ok(registry.services(programs.values().filter(filter)).pop())
+        })
+    }
 
-        file.unlock().unwrap();
+    /// 並び順に全てのチャンネルを返す。
+    pub fn list_channels(&self) -> Result<Vec<ChannelSummary>, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let registry = self.registry.read().map_err(|_| MutexPoisonError)?;
+        Ok(registry.channels(programs.values()))
+    }
+
+    /// チャンネルの表示設定を変更する。チャンネルが存在しない場合は何もせずに None を返す。
+    pub fn update_channel(
+        &self,
+        id: ChannelId,
+        setting: DisplaySetting,
+    ) -> Result<Option<ChannelSummary>, PersistError> {
+        self.mutation(|skip| {
+            let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+            let mut registry = self.registry.write().map_err(|_| MutexPoisonError)?;
+            let filter = |p: &&Arc<StoredProgram>| {
+                p.service
+                    .as_ref()
+                    .and_then(|s| s.channel.as_ref())
+                    .is_some_and(|c| c.channel_type == id.0 && c.channel() == id.1)
+            };
+            if registry.channels(programs.values().filter(filter)).is_empty() {
+                *skip = true;
+                return Ok(None);
+            }
+            registry.set_channel(id.clone(), setting);
+            self.record(Entry::ChannelSetting(registry.persist_channel(&id)));
+            Ok(registry.channels(programs.values().filter(filter)).pop())
+        })
+    }
+
+    /// 番組と、番組に紐付く全ての動画を削除する。ストレージ上のファイルは削除しない。
+    pub fn delete_program<'a>(&'a self, key: &'a ProgramKey) -> Result<Arc<StoredProgram>, ProgramDeleteError<'a>> {
+        match self.delete_program_if(key, |_| true)? {
+            Some((program, _)) => Ok(program),
+            None => Err(ProgramDeleteError::ProgramNotFound(key)),
+        }
+    }
+
+    /// ゴミ箱に移動された日時が until 以前の場合だけ、番組と番組に紐付く全ての動画を削除する。
+    /// ゴミ箱から元に戻されていた場合は何もせず None を返す。ストレージ上のファイルは削除しない。
+    pub fn delete_trashed_program<'a>(
+        &'a self,
+        key: &'a ProgramKey,
+        until: DateTime<Utc>,
+    ) -> Result<Option<DeletedProgram>, ProgramDeleteError<'a>> {
+        self.delete_program_if(key, |p| is_trashed_until(&p.trashed_at, until))
+    }
+
+    fn delete_program_if<'a, F>(
+        &'a self,
+        key: &'a ProgramKey,
+        cond: F,
+    ) -> Result<Option<DeletedProgram>, ProgramDeleteError<'a>>
+    where
+        F: FnOnce(&StoredProgram) -> bool,
+    {
+        self.mutation(|skip| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut search_index = self.search_index.write().map_err(|_| MutexPoisonError)?;
+            let mut series_index = self.series_index.write().map_err(|_| MutexPoisonError)?;
+            let mut lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
+            let program = match programs.get(key) {
+                Some(p) if cond(p) => p.clone(),
+                Some(_) => {
+                    *skip = true;
+                    return Ok(None);
+                }
+                None => return Err(ProgramDeleteError::ProgramNotFound(key)),
+            };
+            programs.remove(key);
+            let mut removed = vec![];
+            for video_id in program.video_ids() {
+                if let Some(video) = videos.remove(video_id) {
+                    lookup_index.remove_video(&video);
+                    self.publish_video(ChangeKind::VideoDeleted, &video);
+                    removed.push(video);
+                }
+            }
+            search_index.remove(key);
+            series_index.remove(key);
+            lookup_index.remove_program(&program);
+            self.publish_program(ChangeKind::ProgramDeleted, &program);
+
+            Ok(Some((program, removed)))
+        })
+    }
+
+    /// 動画を削除し、番組との紐付けを解除する。ストレージ上のファイルは削除しない。
+    pub fn delete_video(&self, id: &Uuid) -> Result<Arc<StoredVideo>, VideoDeleteError> {
+        match self.delete_video_if(id, |_| true)? {
+            Some(video) => Ok(video),
+            None => Err(VideoDeleteError::VideoNotFound(*id)),
+        }
+    }
+
+    /// ゴミ箱に移動された日時が until 以前の場合だけ、動画を削除する。
+    /// ゴミ箱から元に戻されていた場合は何もせず None を返す。ストレージ上のファイルは削除しない。
+    pub fn delete_trashed_video(
+        &self,
+        id: &Uuid,
+        until: DateTime<Utc>,
+    ) -> Result<Option<Arc<StoredVideo>>, VideoDeleteError> {
+        self.delete_video_if(id, |v| is_trashed_until(&v.trashed_at, until))
+    }
+
+    fn delete_video_if<F>(&self, id: &Uuid, cond: F) -> Result<Option<Arc<StoredVideo>>, VideoDeleteError>
+    where
+        F: FnOnce(&StoredVideo) -> bool,
+    {
+        self.mutation(|skip| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
+            let video = match videos.get(id) {
+                Some(v) if cond(v) => v.clone(),
+                Some(_) => {
+                    *skip = true;
+                    return Ok(None);
+                }
+                None => return Err(VideoDeleteError::VideoNotFound(*id)),
+            };
+            videos.remove(id);
+            lookup_index.remove_video(&video);
+            if let Some(program) = programs.get(video.program_key()) {
+                let mut program = (**program).clone();
+                program.video_ids_mut().retain(|v| v != id);
+                let program = Arc::new(program);
+                programs.insert(video.program_key().clone(), program.clone());
+                self.record(Entry::PutProgram(program.persist()));
+            }
+            self.publish_video(ChangeKind::VideoDeleted, &video);
+
+            Ok(Some(video))
+        })
+    }
+
+    /// 番組と、番組に紐付く全ての動画をゴミ箱に移動する。
+    pub fn trash_program(&self, key: &ProgramKey, at: DateTime<Utc>) -> Result<Arc<StoredProgram>, TrashError> {
+        self.mutation(|_| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut program = match programs.get(key) {
+                Some(p) => (**p).clone(),
+                None => return Err(TrashError::ProgramNotFound(key.clone())),
+            };
+            // 既にゴミ箱の中にある場合は、完全に削除されるまでの期間が延びないよう移動した日時を変えない
+            if program.trashed_at.is_none() {
+                program.trashed_at = Some(at);
+            }
+            for video_id in program.video_ids() {
+                if let Some(video) = videos.get(video_id) {
+                    if video.trashed_at.is_none() {
+                        let mut video = (**video).clone();
+                        video.trashed_at = Some(at);
+                        let video = Arc::new(video);
+                        videos.insert(video.id, video.clone());
+                        self.publish_video(ChangeKind::VideoUpdated, &video);
+                    }
+                }
+            }
+            let program = Arc::new(program);
+            programs.insert(key.clone(), program.clone());
+            self.publish_program(ChangeKind::ProgramUpdated, &program);
+
+            Ok(program)
+        })
+    }
+
+    /// 動画をゴミ箱に移動する。
+    pub fn trash_video(&self, id: &Uuid, at: DateTime<Utc>) -> Result<Arc<StoredVideo>, TrashError> {
+        self.mutation(|_| {
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut video = match videos.get(id) {
+                Some(v) => (**v).clone(),
+                None => return Err(TrashError::VideoNotFound(*id)),
+            };
+            if video.trashed_at.is_none() {
+                video.trashed_at = Some(at);
+            }
+            let video = Arc::new(video);
+            videos.insert(*id, video.clone());
+            self.publish_video(ChangeKind::VideoUpdated, &video);
+
+            Ok(video)
+        })
+    }
+
+    /// 番組と、番組に紐付く全ての動画をゴミ箱から元に戻す。
+    pub fn restore_program(&self, key: &ProgramKey) -> Result<Arc<StoredProgram>, TrashError> {
+        self.mutation(|_| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut program = match programs.get(key) {
+                Some(p) => (**p).clone(),
+                None => return Err(TrashError::ProgramNotFound(key.clone())),
+            };
+            program.trashed_at = None;
+            for video_id in program.video_ids() {
+                if let Some(video) = videos.get(video_id) {
+                    if video.trashed_at.is_some() {
+                        let mut video = (**video).clone();
+                        video.trashed_at = None;
+                        let video = Arc::new(video);
+                        videos.insert(video.id, video.clone());
+                        self.publish_video(ChangeKind::VideoUpdated, &video);
+                    }
+                }
+            }
+            let program = Arc::new(program);
+            programs.insert(key.clone(), program.clone());
+            self.publish_program(ChangeKind::ProgramUpdated, &program);
+
+            Ok(program)
+        })
+    }
+
+    /// 動画をゴミ箱から元に戻す。番組もゴミ箱に移動されている場合は、番組も元に戻す。
+    pub fn restore_video(&self, id: &Uuid) -> Result<Arc<StoredVideo>, TrashError> {
+        self.mutation(|_| {
+            let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+            let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+            let mut video = match videos.get(id) {
+                Some(v) => (**v).clone(),
+                None => return Err(TrashError::VideoNotFound(*id)),
+            };
+            video.trashed_at = None;
+            if let Some(program) = programs.get(video.program_key()) {
+                if program.trashed_at.is_some() {
+                    let mut program = (**program).clone();
+                    program.trashed_at = None;
+                    let program = Arc::new(program);
+                    programs.insert(video.program_key().clone(), program.clone());
+                    self.publish_program(ChangeKind::ProgramUpdated, &program);
+                }
+            }
+            let video = Arc::new(video);
+            videos.insert(*id, video.clone());
+            self.publish_video(ChangeKind::VideoUpdated, &video);
+
+            Ok(video)
+        })
+    }
+
+    /// ゴミ箱に移動された日時が until 以前のものを返す。
+    pub fn trashed_items(&self, until: DateTime<Utc>) -> Result<TrashedItems, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+
+        Ok(TrashedItems {
+            programs: programs
+                .values()
+                .filter(|p| is_trashed_until(&p.trashed_at, until))
+                .cloned()
+                .collect(),
+            videos: videos
+                .values()
+                .filter(|v| is_trashed_until(&v.trashed_at, until))
+                .filter(|v| programs.get(v.program_key()).is_none_or(|p| p.trashed_at.is_none()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// blob を読み込む。存在しない場合は None を返す。
+    pub fn read_blob(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+        self.blobs.get(key)
+    }
+
+    /// 現在の内容を取り出す。書き込みを止めるのは Arc を複製する間だけで済む。
+    pub fn snapshot(&self) -> Result<StoreSnapshot, MutexPoisonError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+        let aliases = self.aliases.read().map_err(|_| MutexPoisonError)?;
+        let registry = self.registry.read().map_err(|_| MutexPoisonError)?;
+
+        Ok(StoreSnapshot {
+            revision: self.change_feed.revision(),
+            programs: programs.values().cloned().collect(),
+            videos: videos.values().cloned().collect(),
+            aliases: persist_aliases(&aliases),
+            service_settings: registry.persist_services(),
+            channel_settings: registry.persist_channels(),
+        })
+    }
+
+    /// 全体を store の内容で置き換え、置き換えた後のリビジョンを返す。
+    /// blobs に含まれるサムネイルは blob store に取り込み、既存の blob と内容が同じであればそちらを参照させる。
+    /// 置き換える前の変更からは WatchChanges を再開できなくなる。
+    pub fn restore(&self, mut store: PersistStore, blobs: Vec<PersistBlob>) -> Result<u64, RestoreError> {
+        migrate(&mut store, &self.blobs)?;
+        let mut renamed = HashMap::new();
+        for blob in blobs {
+            let key = self.blobs.put(&blob.data)?;
+            if key != blob.key {
+                renamed.insert(blob.key, key);
+            }
+        }
+        for video in &mut store.videos {
+            if let Some(key) = renamed.get(&video.thumbnail_blob) {
+                video.thumbnail_blob = key.clone();
+            }
+        }
+
+        let mut persisted = store.clone();
+        let contents = Contents::from_persisted(store)?;
+        let (search_index, series_index, lookup_index) =
+            build_indexes(&contents.programs, &contents.videos, &self.config);
+
+        let mut programs = self.programs.write().map_err(|_| MutexPoisonError)?;
+        let mut videos = self.videos.write().map_err(|_| MutexPoisonError)?;
+        let mut aliases = self.aliases.write().map_err(|_| MutexPoisonError)?;
+        let mut registry = self.registry.write().map_err(|_| MutexPoisonError)?;
+        let mut current_search_index = self.search_index.write().map_err(|_| MutexPoisonError)?;
+        let mut current_series_index = self.series_index.write().map_err(|_| MutexPoisonError)?;
+        let mut current_lookup_index = self.lookup_index.write().map_err(|_| MutexPoisonError)?;
+
+        // 購読者が復元前の続きとして扱わないよう、どちらよりも新しいリビジョンにする
+        let revision = self.change_feed.revision().max(contents.revision) + 1;
+        persisted.revision = revision;
+        // 書き込みに失敗した場合は何も置き換えない
+        self.backend.compact(&persisted)?;
+
+        *programs = contents.programs;
+        *videos = contents.videos;
+        *aliases = contents.aliases;
+        *registry = contents.registry;
+        *current_search_index = search_index;
+        *current_series_index = series_index;
+        *current_lookup_index = lookup_index;
+        self.change_feed.reset(revision);
+        Ok(revision)
+    }
+
+    pub fn needs_compaction(&self) -> std::io::Result<bool> {
+        self.backend.needs_compaction()
+    }
+
+    /// 現在の状態でバックエンドの内容を書き直す。
+    pub fn compact(&self) -> Result<(), PersistError> {
+        self.compact_into(self.backend.as_ref())?;
         Ok(())
     }
+
+    /// どこからも参照されていない blob を削除し、削除した数を返す。
+    /// 現在の動画に加えて、残してある古い世代やバックアップから参照されているものも残す。
+    /// 保存してから動画に登録するまでの間に削除しないよう、grace より新しいものも残す。
+    pub fn collect_blobs(&self, grace: std::time::Duration) -> Result<usize, PersistError> {
+        let older_than = SystemTime::now() - grace;
+        // 後から作られた世代やバックアップは、ここで読んだ動画か older_than より新しい blob しか参照しない
+        let mut keys: HashSet<String> = {
+            let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+            videos.values().filter_map(|v| v.thumbnail_blob.clone()).collect()
+        };
+        keys.extend(self.backend.retained_blob_keys()?);
+        keys.extend(backup::retained_blob_keys(&self.config)?);
+        Ok(self.blobs.retain(&keys, older_than)?)
+    }
+
+    /// 保存されている内容をそのままバックアップし、書き出した場所を返す。
+    /// 読み込まずに開いた場合は形式が分からないため、バックアップの名前には日時を使う。
+    pub fn backup_database(&self) -> std::io::Result<PathBuf> {
+        self.backend
+            .backup(&format!("rebuild-{}", Utc::now().format("%Y%m%dT%H%M%SZ")))
+    }
+
+    /// 現在の状態を別のバックエンドに書き出す。
+    pub fn export(&self, kind: BackendKind) -> Result<(), InitializeError> {
+        let backend = open_backend(&self.config.database, kind)?;
+        self.compact_into(backend.as_ref())?;
+        Ok(())
+    }
+
+    fn compact_into(&self, backend: &dyn DatabaseBackend) -> Result<(), PersistError> {
+        let programs = self.programs.read().map_err(|_| MutexPoisonError)?;
+        let videos = self.videos.read().map_err(|_| MutexPoisonError)?;
+        let aliases = self.aliases.read().map_err(|_| MutexPoisonError)?;
+        let registry = self.registry.read().map_err(|_| MutexPoisonError)?;
+
+        let persisted = PersistStore {
+            magic: PERSIST_MAGIC,
+            schema_version: SCHEMA_VERSION,
+            programs: programs.values().map(|p| p.persist()).collect(),
+            videos: videos.values().map(|v| v.persist()).collect(),
+            revision: self.change_feed.revision(),
+            aliases: persist_aliases(&aliases),
+            service_settings: registry.persist_services(),
+            channel_settings: registry.persist_channels(),
+        };
+        // 書き出した変更を二重に書き込まないよう、マップのロックを持ったまま書き出す
+        backend.compact(&persisted)?;
+        Ok(())
+    }
+
+    /// 変更を適用してバックエンドに書き込む。書き込みに失敗した場合、変更はメモリ上には残り、次の書き込みで再試行する。
+    fn mutation<F, T, U>(&self, op: F) -> Result<T, U>
+    where
+        F: FnOnce(&mut bool) -> Result<T, U>,
+        U: From<MutexPoisonError> + From<std::io::Error>,
+    {
+        let mut skip = false;
+        let result = op(&mut skip)?;
+        if !skip {
+            self.backend.flush()?;
+        }
+        Ok(result)
+    }
+
+    fn record(&self, entry: Entry) {
+        self.backend.record(self.change_feed.revision(), entry);
+    }
+
+    fn publish_program(&self, kind: ChangeKind, program: &Arc<StoredProgram>) {
+        self.change_feed.publish_program(kind, program);
+        match kind {
+            ChangeKind::ProgramDeleted => {
+                self.record(Entry::DeleteProgram(ProgramKey::from_stored_program(program).persist()))
+            }
+            _ => self.record(Entry::PutProgram(program.persist())),
+        }
+    }
+
+    fn publish_video(&self, kind: ChangeKind, video: &Arc<StoredVideo>) {
+        self.change_feed.publish_video(kind, video);
+        match kind {
+            ChangeKind::VideoDeleted => self.record(Entry::DeleteVideo(video.stringify_id())),
+            _ => self.record(Entry::PutVideo(video.persist())),
+        }
+    }
+}
+
+/// ゴミ箱に移動された日時が until 以前であれば true を返す。
+fn is_trashed_until(trashed_at: &Option<DateTime<Utc>>, until: DateTime<Utc>) -> bool {
+    trashed_at.is_some_and(|t| t <= until)
+}
+
+fn build_indexes(
+    programs: &ProgramStoreBackend,
+    videos: &VideoStoreBackend,
+    config: &Config,
+) -> (SearchIndex, SeriesIndex, LookupIndex) {
+    let mut search_index = SearchIndex::new();
+    let mut series_index = SeriesIndex::new();
+    let mut lookup_index = LookupIndex::new();
+    for sp in programs.values() {
+        search_index.insert(sp);
+        series_index.insert(sp, &config.series_rules);
+        lookup_index.insert_program(sp);
+    }
+    for sv in videos.values() {
+        lookup_index.insert_video(sv);
+    }
+    (search_index, series_index, lookup_index)
+}
+
+fn persist_aliases(aliases: &BTreeMap<ProgramKey, ProgramKey>) -> Vec<PersistProgramAlias> {
+    aliases
+        .iter()
+        .map(|(alias, program_id)| PersistProgramAlias {
+            alias: Some(alias.persist()),
+            program_id: Some(program_id.persist()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::ProgramFilter;
+    use prost::Message;
+
+    fn config(data_dir: &std::path::Path) -> Arc<Config> {
+        Config::for_test(data_dir, "snapshot_generations = 2\n")
+    }
+
+    fn program() -> Program {
+        Program {
+            network_id: 32736,
+            service_id: 1024,
+            event_id: 1,
+            start_at: Some(prost_types::Timestamp {
+                seconds: 1617202800,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_data_dir_lock_and_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let store = ProgramStore::new(config.clone()).unwrap();
+        assert!(matches!(
+            ProgramStore::new(config.clone()),
+            Err(InitializeError::Locked(_))
+        ));
+
+        for _ in 0..4 {
+            store.compact().unwrap();
+        }
+        assert!(config.database.programs_file_path().is_file());
+        assert!(config.database.programs_generation_path(1).is_file());
+        assert!(config.database.programs_generation_path(2).is_file());
+        assert!(!config.database.programs_generation_path(3).exists());
+        assert!(!config.database.programs_temp_file_path().exists());
+
+        drop(store);
+        assert!(ProgramStore::new(config).is_ok());
+    }
+
+    #[test]
+    fn test_collect_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let store = ProgramStore::new(config.clone()).unwrap();
+        let (sp, _) = store.find_or_create(program()).unwrap();
+        let key = ProgramKey::from_stored_program(&sp);
+        let video = store
+            .create_video(&key, video(&sp, "chinachu:1", Uuid::new_v4()))
+            .unwrap();
+        let thumbnail = |bin: Vec<u8>| {
+            store.update_video_thumbnail(&video.id, bin, mime::IMAGE_JPEG).unwrap();
+            store
+                .find_video(&video.id)
+                .unwrap()
+                .unwrap()
+                .thumbnail_blob
+                .clone()
+                .unwrap()
+        };
+
+        // サムネイルを含めないバックアップからだけ参照される
+        let in_backup = thumbnail(vec![1]);
+        crate::backup::create_backup(&config, &store, false).unwrap();
+        // 古い世代からだけ参照される
+        let in_generation = thumbnail(vec![2]);
+        for _ in 0..3 {
+            store.compact().unwrap();
+        }
+        let current = thumbnail(vec![3]);
+        store.compact().unwrap();
+        let orphan = store.blobs.put(&[4]).unwrap();
+        assert!(store.read_blob(&orphan).unwrap().is_some());
+
+        // 保存したばかりのものは参照されていなくても残す
+        assert_eq!(0, store.collect_blobs(std::time::Duration::from_secs(60 * 60)).unwrap());
+        assert_eq!(1, store.collect_blobs(std::time::Duration::from_secs(0)).unwrap());
+        assert!(store.read_blob(&orphan).unwrap().is_none());
+        for key in &[in_backup, in_generation, current] {
+            assert!(store.read_blob(key).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_rebuild_keeps_thumbnail_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let blob = {
+            let store = ProgramStore::new(config.clone()).unwrap();
+            let (sp, _) = store.find_or_create(program()).unwrap();
+            let key = ProgramKey::from_stored_program(&sp);
+            let video = store
+                .create_video(&key, video(&sp, "chinachu:1", Uuid::new_v4()))
+                .unwrap();
+            store
+                .update_video_thumbnail(&video.id, vec![1], mime::IMAGE_JPEG)
+                .unwrap();
+            store.compact().unwrap();
+            store
+                .find_video(&video.id)
+                .unwrap()
+                .unwrap()
+                .thumbnail_blob
+                .clone()
+                .unwrap()
+        };
+
+        // recover-database --rebuild と同じ手順で空にする
+        let store = ProgramStore::open_empty(config).unwrap();
+        store.backup_database().unwrap();
+        store.compact().unwrap();
+        assert!(store.read_blob(&blob).unwrap().is_some());
+        // 作り直す前のバックアップから参照されている
+        assert_eq!(0, store.collect_blobs(std::time::Duration::from_secs(0)).unwrap());
+        assert!(store.read_blob(&blob).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_sqlite_backend_and_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let key = {
+            let store = ProgramStore::open(config.clone(), BackendKind::Sqlite).unwrap();
+            let (sp, _) = store.find_or_create(program()).unwrap();
+            let key = ProgramKey::from_stored_program(&sp);
+            store.update_program_metadata(&key, "note", "sqlite").unwrap();
+            key
+        };
+
+        let store = ProgramStore::open(config.clone(), BackendKind::Sqlite).unwrap();
+        let sp = store.find(&key).unwrap().unwrap();
+        assert_eq!(Some(&"sqlite".to_string()), sp.metadata().get("note"));
+        store.export(BackendKind::File).unwrap();
+        drop(store);
+
+        let store = ProgramStore::open(config, BackendKind::File).unwrap();
+        assert!(store.find(&key).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_migrate_inline_thumbnail() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let program = StoredProgram::from_exchanged(program()).unwrap();
+        let video = StoredVideo::from_exchanged(
+            &program,
+            dtvault_types::shibafu528::dtvault::storage::create_video_request::Header {
+                mime_type: "video/mp2t".to_string(),
+                ..Default::default()
+            },
+        );
+        let mut persisted_video = video.persist();
+        persisted_video.thumbnail = vec![0xff, 0xd8];
+        let persisted = PersistStore {
+            programs: vec![program.persist()],
+            videos: vec![persisted_video],
+            ..Default::default()
+        };
+        let mut buf = vec![];
+        persisted.encode(&mut buf).unwrap();
+        std::fs::write(config.database.programs_file_path(), buf).unwrap();
+
+        let store = ProgramStore::new(config.clone()).unwrap();
+        let video = store.find_video(&video.id).unwrap().unwrap();
+        let key = video.thumbnail_blob.as_ref().unwrap();
+        assert_eq!(Some(vec![0xff, 0xd8]), store.read_blob(key).unwrap());
+
+        let bin = std::fs::read(config.database.programs_file_path()).unwrap();
+        let persisted = PersistStore::decode(&bin[..]).unwrap();
+        assert!(persisted.videos[0].thumbnail.is_empty());
+        assert_eq!(key, &persisted.videos[0].thumbnail_blob);
+        assert_eq!(SCHEMA_VERSION, persisted.schema_version);
+        assert!(dir.path().join("programs.pb.v0.bak").is_file());
+    }
+
+    #[test]
+    fn test_trash_keeps_trashed_at() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ProgramStore::new(config(dir.path())).unwrap();
+        let (sp, _) = store.find_or_create(program()).unwrap();
+        let key = ProgramKey::from_stored_program(&sp);
+        let first = Utc::now() - chrono::Duration::days(1);
+        store.trash_program(&key, first).unwrap();
+        let sp = store.trash_program(&key, Utc::now()).unwrap();
+        assert_eq!(Some(first), sp.trashed_at);
+    }
+
+    #[test]
+    fn test_delete_trashed_skips_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ProgramStore::new(config(dir.path())).unwrap();
+        let (sp, _) = store.find_or_create(program()).unwrap();
+        let key = ProgramKey::from_stored_program(&sp);
+        let video = store
+            .create_video(&key, video(&sp, "chinachu:1", Uuid::new_v4()))
+            .unwrap();
+        let at = Utc::now() - chrono::Duration::days(1);
+        store.trash_program(&key, at).unwrap();
+
+        // ゴミ箱の中身を取り出した後、削除する前に元に戻された
+        let items = store.trashed_items(Utc::now()).unwrap();
+        assert_eq!(1, items.programs.len());
+        store.restore_program(&key).unwrap();
+        assert!(store.delete_trashed_program(&key, Utc::now()).unwrap().is_none());
+        assert!(store.find(&key).unwrap().is_some());
+        assert!(store.find_video(&video.id).unwrap().is_some());
+
+        store.trash_video(&video.id, at).unwrap();
+        let items = store.trashed_items(Utc::now()).unwrap();
+        assert_eq!(1, items.videos.len());
+        store.restore_video(&video.id).unwrap();
+        assert!(store.delete_trashed_video(&video.id, Utc::now()).unwrap().is_none());
+        assert!(store.find_video(&video.id).unwrap().is_some());
+
+        // ゴミ箱に戻した後は削除される
+        store.trash_program(&key, at).unwrap();
+        let (_, videos) = store.delete_trashed_program(&key, Utc::now()).unwrap().unwrap();
+        assert_eq!(vec![video.id], videos.iter().map(|v| v.id).collect::<Vec<_>>());
+        assert!(store.find(&key).unwrap().is_none());
+        assert!(store.find_video(&video.id).unwrap().is_none());
+    }
+
+    fn video(program: &StoredProgram, provider_id: &str, storage_id: Uuid) -> StoredVideo {
+        let mut video = StoredVideo::from_exchanged(
+            program,
+            dtvault_types::shibafu528::dtvault::storage::create_video_request::Header {
+                provider_id: provider_id.to_string(),
+                mime_type: "video/mp2t".to_string(),
+                ..Default::default()
+            },
+        );
+        video.storage_id = storage_id;
+        video
+    }
+
+    #[test]
+    fn test_lookup_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ProgramStore::new(config(dir.path())).unwrap();
+        let storage_id = Uuid::new_v4();
+        let mut keys = vec![];
+        for event_id in 1..=3 {
+            let mut p = program();
+            p.event_id = event_id;
+            p.service_id = if event_id == 3 { 1025 } else { 1024 };
+            p.start_at.as_mut().unwrap().seconds += event_id as i64 * 1800;
+            let (sp, _) = store.find_or_create(p).unwrap();
+            let key = ProgramKey::from_stored_program(&sp);
+            store.create_video(&key, video(&sp, "chinachu:1", storage_id)).unwrap();
+            keys.push(key);
+        }
+        let sp = store.find(&keys[0]).unwrap().unwrap();
+        assert!(matches!(
+            store.create_video(&keys[0], video(&sp, "chinachu:1", storage_id)),
+            Err(VideoWriteError::AlreadyExists(_))
+        ));
+
+        assert_eq!(3, store.find_videos_by_provider_id("chinachu:1", false).unwrap().len());
+        assert_eq!(3, store.find_videos_by_storage_id(&storage_id, false).unwrap().len());
+        assert_eq!(2, store.find_programs_by_service((32736, 1024), false).unwrap().len());
+        let start_at = |key: &ProgramKey| store.find(key).unwrap().unwrap().start_at;
+        let programs = store
+            .find_programs_by_start_at(Some(start_at(&keys[1])), Some(start_at(&keys[2])), false)
+            .unwrap();
+        assert_eq!(
+            vec![keys[1].clone()],
+            programs
+                .iter()
+                .map(|sp| ProgramKey::from_stored_program(sp))
+                .collect::<Vec<_>>()
+        );
+
+        store.merge_programs(&keys[0], &keys[1]).unwrap();
+        store.trash_program(&keys[2], Utc::now()).unwrap();
+        assert_eq!(1, store.find_programs_by_service((32736, 1024), false).unwrap().len());
+        assert_eq!(2, store.find_videos_by_provider_id("chinachu:1", false).unwrap().len());
+        assert_eq!(3, store.find_videos_by_provider_id("chinachu:1", true).unwrap().len());
+        let page = store
+            .query(&ProgramQuery {
+                filter: ProgramFilter {
+                    services: vec![(32736, 1024), (32736, 1025)],
+                    trashed: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(1, page.programs.len());
+
+        store.delete_program(&keys[2]).unwrap();
+        assert_eq!(0, store.find_programs_by_service((32736, 1025), true).unwrap().len());
+        assert_eq!(2, store.find_videos_by_storage_id(&storage_id, true).unwrap().len());
+    }
 }
@@ -33,3 +33,16 @@ impl ToDateTimeExt for prost_types::Timestamp {
         chrono::DateTime::from_utc(self.to_naive_utc(), chrono::offset::Utc)
     }
 }
+
+pub trait ToTimestampExt {
+    fn to_timestamp(&self) -> prost_types::Timestamp;
+}
+
+impl ToTimestampExt for chrono::DateTime<chrono::Utc> {
+    fn to_timestamp(&self) -> prost_types::Timestamp {
+        prost_types::Timestamp {
+            seconds: self.timestamp(),
+            nanos: self.timestamp_subsec_nanos() as i32,
+        }
+    }
+}
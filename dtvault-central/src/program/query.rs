@@ -0,0 +1,309 @@
+use crate::config::Condition;
+use crate::program::{ChannelType, Program, ProgramKey};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug, Default)]
+pub enum ProgramOrder {
+    #[default]
+    StartAt,
+    Name,
+    Service,
+    CreatedAt,
+}
+
+#[derive(Default, Debug)]
+pub struct ProgramFilter {
+    /// (network_id, service_id) のいずれかに一致
+    pub services: Vec<(u16, u16)>,
+    pub channel_types: Vec<ChannelType>,
+    pub start_at_from: Option<DateTime<Utc>>,
+    pub start_at_to: Option<DateTime<Utc>>,
+    pub title: String,
+    /// 全てのタグが付いている
+    pub tags: Vec<String>,
+    /// 条件式に一致する
+    pub condition: Option<Arc<Condition>>,
+    /// true の場合はゴミ箱の中の番組、false の場合はゴミ箱の外の番組に一致
+    pub trashed: bool,
+}
+
+impl ProgramFilter {
+    pub fn matches(&self, program: &Program) -> bool {
+        if program.trashed_at.is_some() != self.trashed {
+            return false;
+        }
+        if !self.services.is_empty()
+            && !self
+                .services
+                .iter()
+                .any(|(nid, sid)| *nid == program.network_id && *sid == program.service_id)
+        {
+            return false;
+        }
+        if !self.channel_types.is_empty() {
+            let channel_type = program
+                .service
+                .as_ref()
+                .and_then(|s| s.channel.as_ref())
+                .map(|c| c.channel_type);
+            match channel_type {
+                Some(ct) if self.channel_types.contains(&ct) => {}
+                _ => return false,
+            }
+        }
+        if let Some(from) = &self.start_at_from {
+            if program.start_at < *from {
+                return false;
+            }
+        }
+        if let Some(to) = &self.start_at_to {
+            if program.start_at >= *to {
+                return false;
+            }
+        }
+        if !self.title.is_empty() && !program.name.contains(&self.title) {
+            return false;
+        }
+        if !self.tags.iter().all(|t| program.tags().contains(t)) {
+            return false;
+        }
+        if let Some(condition) = &self.condition {
+            if !condition.matches_program(program) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ProgramQuery {
+    pub filter: ProgramFilter,
+    pub order: ProgramOrder,
+    pub descending: bool,
+    /// 前のページの最後の要素を指すカーソル。この要素より後ろから結果を返す。
+    pub after: Option<PageCursor>,
+    /// 最大件数。None の場合は全件
+    pub limit: Option<usize>,
+}
+
+pub struct ProgramPage {
+    pub programs: Vec<Arc<Program>>,
+    pub next_cursor: Option<PageCursor>,
+}
+
+/// ページングの継続位置。並び順の値と ProgramKey の組で位置を表すため、
+/// ページングの途中で番組が追加されても既に返した要素がずれることはない。
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PageCursor {
+    order: ProgramOrder,
+    descending: bool,
+    value: SortValue,
+    key: ProgramKey,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PageCursorError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("token was issued for a different order")]
+    OrderMismatch,
+}
+
+impl PageCursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap();
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(token: &str, order: ProgramOrder, descending: bool) -> Result<Self, PageCursorError> {
+        let json = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| PageCursorError::Malformed)?;
+        let cursor: PageCursor = serde_json::from_slice(&json).map_err(|_| PageCursorError::Malformed)?;
+        if cursor.order != order || cursor.descending != descending {
+            return Err(PageCursorError::OrderMismatch);
+        }
+        Ok(cursor)
+    }
+}
+
+// ProgramKey の前に比較する値。同じクエリ内では常に同じバリアントが使われる。
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Debug)]
+enum SortValue {
+    // ProgramKey 自体が start_at から始まるため、追加の値は不要
+    StartAt,
+    Name(String),
+    Service(u16, u16),
+    CreatedAt(DateTime<Utc>),
+}
+
+impl SortValue {
+    fn of(order: ProgramOrder, program: &Program) -> Self {
+        match order {
+            ProgramOrder::StartAt => SortValue::StartAt,
+            ProgramOrder::Name => SortValue::Name(program.name.clone()),
+            ProgramOrder::Service => SortValue::Service(program.network_id, program.service_id),
+            ProgramOrder::CreatedAt => SortValue::CreatedAt(program.created_at),
+        }
+    }
+}
+
+impl ProgramQuery {
+    pub fn execute<'a, I: Iterator<Item = &'a Arc<Program>>>(&self, programs: I) -> ProgramPage {
+        let direction = |o: Ordering| if self.descending { o.reverse() } else { o };
+
+        let mut hits: Vec<(SortValue, ProgramKey, &Arc<Program>)> = programs
+            .filter(|p| self.filter.matches(p))
+            .map(|p| (SortValue::of(self.order, p), ProgramKey::from_stored_program(p), p))
+            .filter(|(value, key, _)| match &self.after {
+                Some(after) => direction((value, key).cmp(&(&after.value, &after.key))) == Ordering::Greater,
+                None => true,
+            })
+            .collect();
+        hits.sort_by(|a, b| direction((&a.0, &a.1).cmp(&(&b.0, &b.1))));
+
+        let mut next_cursor = None;
+        if let Some(limit) = self.limit {
+            if hits.len() > limit {
+                hits.truncate(limit);
+                if let Some((value, key, _)) = hits.last() {
+                    next_cursor = Some(PageCursor {
+                        order: self.order,
+                        descending: self.descending,
+                        value: value.clone(),
+                        key: key.clone(),
+                    });
+                }
+            }
+        }
+
+        ProgramPage {
+            programs: hits.into_iter().map(|(_, _, p)| p.clone()).collect(),
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtvault_types::shibafu528::dtvault as types;
+
+    fn program(event_id: u32, start_at: i64, name: &str) -> Arc<Program> {
+        let p = types::Program {
+            network_id: 32736,
+            service_id: 1024,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: start_at,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            name: name.to_string(),
+            ..Default::default()
+        };
+        Arc::new(Program::from_exchanged(p).unwrap())
+    }
+
+    fn names(page: &ProgramPage) -> Vec<&str> {
+        page.programs.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_order_by_start_at() {
+        let programs = [program(3, 300, "c"), program(1, 100, "a"), program(2, 200, "b")];
+        let query = ProgramQuery::default();
+        assert_eq!(vec!["a", "b", "c"], names(&query.execute(programs.iter())));
+
+        let query = ProgramQuery {
+            descending: true,
+            ..Default::default()
+        };
+        assert_eq!(vec!["c", "b", "a"], names(&query.execute(programs.iter())));
+    }
+
+    #[test]
+    fn test_order_by_name() {
+        let programs = [program(1, 100, "b"), program(2, 200, "a"), program(3, 300, "a")];
+        let query = ProgramQuery {
+            order: ProgramOrder::Name,
+            ..Default::default()
+        };
+        let page = query.execute(programs.iter());
+        assert_eq!(vec!["a", "a", "b"], names(&page));
+        assert_eq!(200, page.programs[0].start_at.timestamp());
+    }
+
+    #[test]
+    fn test_filter_title() {
+        let programs = [
+            program(1, 100, "ニュース"),
+            program(2, 200, "アニメ"),
+            program(3, 300, "夜のニュース"),
+        ];
+        let query = ProgramQuery {
+            filter: ProgramFilter {
+                title: "ニュース".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(vec!["ニュース", "夜のニュース"], names(&query.execute(programs.iter())));
+    }
+
+    #[test]
+    fn test_filter_tags() {
+        let mut tagged = (*program(1, 100, "a")).clone();
+        tagged.tags_mut().insert("アニメ".to_string());
+        tagged.tags_mut().insert("録画予約".to_string());
+        let programs = [Arc::new(tagged), program(2, 200, "b")];
+        let query = ProgramQuery {
+            filter: ProgramFilter {
+                tags: vec!["アニメ".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(vec!["a"], names(&query.execute(programs.iter())));
+    }
+
+    #[test]
+    fn test_paging_is_stable_on_insert() {
+        let mut programs = vec![program(1, 100, "a"), program(2, 200, "b"), program(3, 300, "c")];
+        let query = ProgramQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = query.execute(programs.iter());
+        assert_eq!(vec!["a", "b"], names(&page));
+
+        let token = page.next_cursor.unwrap().encode();
+        programs.push(program(4, 50, "z"));
+        let query = ProgramQuery {
+            limit: Some(2),
+            after: Some(PageCursor::decode(&token, ProgramOrder::StartAt, false).unwrap()),
+            ..Default::default()
+        };
+        let page = query.execute(programs.iter());
+        assert_eq!(vec!["c"], names(&page));
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_cursor_order_mismatch() {
+        let programs = [program(1, 100, "a"), program(2, 200, "b")];
+        let query = ProgramQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let token = query.execute(programs.iter()).next_cursor.unwrap().encode();
+        assert!(PageCursor::decode(&token, ProgramOrder::Name, false).is_err());
+        assert!(PageCursor::decode(&token, ProgramOrder::StartAt, true).is_err());
+        assert!(PageCursor::decode("!!!", ProgramOrder::StartAt, false).is_err());
+    }
+}
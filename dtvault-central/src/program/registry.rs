@@ -0,0 +1,335 @@
+use crate::program::{Channel, ChannelType, Program, Service};
+use chrono::{DateTime, Utc};
+use dtvault_types::shibafu528::dtvault::central::{PersistChannelSetting, PersistServiceSetting};
+use num_traits::FromPrimitive;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub type ServiceId = (u16, u16);
+pub type ChannelId = (ChannelType, String);
+
+/// 表示名と並び順の設定
+#[derive(Clone, Default, Debug)]
+pub struct DisplaySetting {
+    /// 空の場合は番組から得られた名前を使う
+    pub display_name: String,
+    /// 昇順に並べる。同じ値の場合は識別子順
+    pub sort_order: i32,
+}
+
+/// 放送期間と番組数
+#[derive(Clone, Default)]
+pub struct Coverage {
+    pub program_count: usize,
+    pub first_start_at: Option<DateTime<Utc>>,
+    pub last_start_at: Option<DateTime<Utc>>,
+}
+
+impl Coverage {
+    fn add(&mut self, program: &Program) {
+        self.program_count += 1;
+        if self.first_start_at.is_none_or(|t| program.start_at < t) {
+            self.first_start_at = Some(program.start_at);
+        }
+        if self.last_start_at.is_none_or(|t| program.start_at > t) {
+            self.last_start_at = Some(program.start_at);
+        }
+    }
+}
+
+pub struct ServiceSummary {
+    pub id: ServiceId,
+    /// 最後に放送された番組のサービス情報
+    pub service: Option<Service>,
+    pub setting: DisplaySetting,
+    pub coverage: Coverage,
+}
+
+impl ServiceSummary {
+    pub fn display_name(&self) -> &str {
+        if self.setting.display_name.is_empty() {
+            self.service.as_ref().map_or("", |s| &s.name)
+        } else {
+            &self.setting.display_name
+        }
+    }
+
+    pub fn channel_type(&self) -> Option<ChannelType> {
+        self.service
+            .as_ref()
+            .and_then(|s| s.channel.as_ref())
+            .map(|c| c.channel_type)
+    }
+}
+
+pub struct ChannelSummary {
+    /// 最後に放送された番組のチャンネル情報
+    pub channel: Channel,
+    pub setting: DisplaySetting,
+    /// このチャンネルで受信したサービス
+    pub services: Vec<ServiceId>,
+    pub coverage: Coverage,
+}
+
+impl ChannelSummary {
+    pub fn display_name(&self) -> &str {
+        if self.setting.display_name.is_empty() {
+            &self.channel.name
+        } else {
+            &self.setting.display_name
+        }
+    }
+}
+
+/// サービスとチャンネルの表示設定。一覧そのものは番組から導出する。
+#[derive(Default)]
+pub struct Registry {
+    services: BTreeMap<ServiceId, DisplaySetting>,
+    channels: BTreeMap<ChannelId, DisplaySetting>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn from_persisted(services: Vec<PersistServiceSetting>, channels: Vec<PersistChannelSetting>) -> Self {
+        Registry {
+            services: services
+                .into_iter()
+                .map(|s| {
+                    (
+                        (s.network_id as u16, s.service_id as u16),
+                        DisplaySetting {
+                            display_name: s.display_name,
+                            sort_order: s.sort_order,
+                        },
+                    )
+                })
+                .collect(),
+            channels: channels
+                .into_iter()
+                .filter_map(|c| {
+                    let channel_type = ChannelType::from_i32(c.channel_type)?;
+                    Some((
+                        (channel_type, c.channel),
+                        DisplaySetting {
+                            display_name: c.display_name,
+                            sort_order: c.sort_order,
+                        },
+                    ))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn persist_services(&self) -> Vec<PersistServiceSetting> {
+        self.services
+            .iter()
+            .map(|((nid, sid), s)| PersistServiceSetting {
+                network_id: *nid as u32,
+                service_id: *sid as u32,
+                display_name: s.display_name.clone(),
+                sort_order: s.sort_order,
+            })
+            .collect()
+    }
+
+    pub fn persist_channels(&self) -> Vec<PersistChannelSetting> {
+        self.channels
+            .iter()
+            .map(|((channel_type, channel), s)| PersistChannelSetting {
+                channel_type: *channel_type as i32,
+                channel: channel.clone(),
+                display_name: s.display_name.clone(),
+                sort_order: s.sort_order,
+            })
+            .collect()
+    }
+
+    /// 1件分の設定を返す。設定がない場合は既定値を返す。
+    pub fn persist_service(&self, id: ServiceId) -> PersistServiceSetting {
+        let s = self.services.get(&id).cloned().unwrap_or_default();
+        PersistServiceSetting {
+            network_id: id.0 as u32,
+            service_id: id.1 as u32,
+            display_name: s.display_name,
+            sort_order: s.sort_order,
+        }
+    }
+
+    /// 1件分の設定を返す。設定がない場合は既定値を返す。
+    pub fn persist_channel(&self, id: &ChannelId) -> PersistChannelSetting {
+        let s = self.channels.get(id).cloned().unwrap_or_default();
+        PersistChannelSetting {
+            channel_type: id.0 as i32,
+            channel: id.1.clone(),
+            display_name: s.display_name,
+            sort_order: s.sort_order,
+        }
+    }
+
+    /// 変更ログに記録された設定を適用する。
+    pub fn apply_service(&mut self, s: PersistServiceSetting) {
+        let setting = DisplaySetting {
+            display_name: s.display_name,
+            sort_order: s.sort_order,
+        };
+        self.set_service((s.network_id as u16, s.service_id as u16), setting);
+    }
+
+    /// 変更ログに記録された設定を適用する。
+    pub fn apply_channel(&mut self, c: PersistChannelSetting) {
+        if let Some(channel_type) = ChannelType::from_i32(c.channel_type) {
+            let setting = DisplaySetting {
+                display_name: c.display_name,
+                sort_order: c.sort_order,
+            };
+            self.set_channel((channel_type, c.channel), setting);
+        }
+    }
+
+    /// 設定を変更する。既定値に戻した場合は設定を削除する。
+    pub fn set_service(&mut self, id: ServiceId, setting: DisplaySetting) {
+        if setting.display_name.is_empty() && setting.sort_order == 0 {
+            self.services.remove(&id);
+        } else {
+            self.services.insert(id, setting);
+        }
+    }
+
+    /// 設定を変更する。既定値に戻した場合は設定を削除する。
+    pub fn set_channel(&mut self, id: ChannelId, setting: DisplaySetting) {
+        if setting.display_name.is_empty() && setting.sort_order == 0 {
+            self.channels.remove(&id);
+        } else {
+            self.channels.insert(id, setting);
+        }
+    }
+
+    /// 番組からサービスの一覧を作り、並び順に返す。ゴミ箱の中の番組は数えない。
+    pub fn services<'a, I: IntoIterator<Item = &'a Arc<Program>>>(&self, programs: I) -> Vec<ServiceSummary> {
+        let mut summaries: BTreeMap<ServiceId, ServiceSummary> = BTreeMap::new();
+        for program in programs.into_iter().filter(|p| p.trashed_at.is_none()) {
+            let id = (program.network_id, program.service_id);
+            let summary = summaries.entry(id).or_insert_with(|| ServiceSummary {
+                id,
+                service: None,
+                setting: self.services.get(&id).cloned().unwrap_or_default(),
+                coverage: Coverage::default(),
+            });
+            if program.service.is_some() && summary.coverage.last_start_at.is_none_or(|t| program.start_at >= t) {
+                summary.service = program.service.clone();
+            }
+            summary.coverage.add(program);
+        }
+
+        let mut summaries: Vec<ServiceSummary> = summaries.into_values().collect();
+        summaries.sort_by_key(|s| s.setting.sort_order);
+        summaries
+    }
+
+    /// 番組からチャンネルの一覧を作り、並び順に返す。ゴミ箱の中の番組は数えない。
+    pub fn channels<'a, I: IntoIterator<Item = &'a Arc<Program>>>(&self, programs: I) -> Vec<ChannelSummary> {
+        let mut summaries: BTreeMap<ChannelId, (ChannelSummary, DateTime<Utc>)> = BTreeMap::new();
+        for program in programs.into_iter().filter(|p| p.trashed_at.is_none()) {
+            let channel = match program.service.as_ref().and_then(|s| s.channel.as_ref()) {
+                Some(c) => c,
+                None => continue,
+            };
+            let id = (channel.channel_type, channel.channel().to_string());
+            let (summary, latest) = summaries.entry(id.clone()).or_insert_with(|| {
+                (
+                    ChannelSummary {
+                        channel: channel.clone(),
+                        setting: self.channels.get(&id).cloned().unwrap_or_default(),
+                        services: vec![],
+                        coverage: Coverage::default(),
+                    },
+                    program.start_at,
+                )
+            });
+            if program.start_at >= *latest {
+                summary.channel = channel.clone();
+                *latest = program.start_at;
+            }
+            let service_id = (program.network_id, program.service_id);
+            if let Err(index) = summary.services.binary_search(&service_id) {
+                summary.services.insert(index, service_id);
+            }
+            summary.coverage.add(program);
+        }
+
+        let mut summaries: Vec<ChannelSummary> = summaries.into_values().map(|(s, _)| s).collect();
+        summaries.sort_by_key(|s| s.setting.sort_order);
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtvault_types::shibafu528::dtvault as types;
+
+    fn program(service_id: u32, event_id: u32, start_at: i64, service_name: &str, channel: &str) -> Arc<Program> {
+        let p = types::Program {
+            network_id: 32736,
+            service_id,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: start_at,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            name: "ニュース".to_string(),
+            service: Some(types::Service {
+                network_id: 32736,
+                service_id,
+                name: service_name.to_string(),
+                channel: Some(types::Channel {
+                    channel_type: types::ChannelType::Gr as i32,
+                    channel: channel.to_string(),
+                    name: channel.to_string(),
+                }),
+            }),
+            ..Default::default()
+        };
+        Arc::new(Program::from_exchanged(p).unwrap())
+    }
+
+    #[test]
+    fn test_services() {
+        let programs = vec![
+            program(1024, 1, 200, "NHK総合1", "27"),
+            program(1024, 2, 100, "NHK総合", "27"),
+            program(1032, 3, 300, "NHKEテレ1", "26"),
+        ];
+        let mut registry = Registry::new();
+        registry.set_service(
+            (32736, 1032),
+            DisplaySetting {
+                display_name: "Eテレ".to_string(),
+                sort_order: -1,
+            },
+        );
+
+        let services = registry.services(&programs);
+        assert_eq!(
+            vec![(32736, 1032), (32736, 1024)],
+            services.iter().map(|s| s.id).collect::<Vec<_>>()
+        );
+        assert_eq!("Eテレ", services[0].display_name());
+        assert_eq!("NHK総合1", services[1].display_name());
+        assert_eq!(2, services[1].coverage.program_count);
+        assert_eq!(100, services[1].coverage.first_start_at.unwrap().timestamp());
+        assert_eq!(200, services[1].coverage.last_start_at.unwrap().timestamp());
+
+        let channels = registry.channels(&programs);
+        assert_eq!(2, channels.len());
+        assert_eq!("26", channels[0].channel.channel());
+        assert_eq!(vec![(32736, 1024)], channels[1].services);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::program::{Program, ProgramKey};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use unicode_normalization::UnicodeNormalization;
+
+const GRAM_SIZE: usize = 2;
+
+// フィールドごとのスコアの重み
+const WEIGHT_NAME: usize = 3;
+const WEIGHT_DESCRIPTION: usize = 1;
+const WEIGHT_EXTENDED: usize = 1;
+
+/// 検索用に文字列を正規化する。
+/// 全角英数・半角カナなどの互換文字を NFKC で統一し、英字は小文字に、カタカナはひらがなに寄せる。
+pub fn normalize(s: &str) -> String {
+    s.nfkc()
+        .flat_map(|c| c.to_lowercase())
+        .map(|c| match c {
+            // ァ..ヶ => ぁ..ゖ
+            '\u{30A1}'..='\u{30F6}' => std::char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            _ => c,
+        })
+        .collect()
+}
+
+pub(crate) fn ngrams(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.len() < GRAM_SIZE {
+        return if chars.is_empty() {
+            vec![]
+        } else {
+            vec![chars.iter().collect()]
+        };
+    }
+    chars.windows(GRAM_SIZE).map(|w| w.iter().collect()).collect()
+}
+
+struct Document {
+    name: String,
+    description: String,
+    extended: String,
+}
+
+impl Document {
+    fn new(program: &Program) -> Self {
+        Document {
+            name: normalize(&program.name),
+            description: normalize(&program.description),
+            extended: program
+                .extended()
+                .iter()
+                .map(|e| normalize(e.value()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    fn grams(&self) -> BTreeSet<String> {
+        let mut grams = BTreeSet::new();
+        for field in &[&self.name, &self.description, &self.extended] {
+            for line in field.lines() {
+                grams.extend(ngrams(line));
+            }
+        }
+        grams
+    }
+
+    fn score(&self, term: &str) -> usize {
+        self.name.matches(term).count() * WEIGHT_NAME
+            + self.description.matches(term).count() * WEIGHT_DESCRIPTION
+            + self.extended.matches(term).count() * WEIGHT_EXTENDED
+    }
+}
+
+/// 番組名、概要、番組詳細を対象とした n-gram 転置インデックス
+#[derive(Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, BTreeSet<ProgramKey>>,
+    documents: BTreeMap<ProgramKey, Document>,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, program: &Program) {
+        let key = ProgramKey::from_stored_program(program);
+        self.remove(&key);
+
+        let document = Document::new(program);
+        for gram in document.grams() {
+            self.postings.entry(gram).or_default().insert(key.clone());
+        }
+        self.documents.insert(key, document);
+    }
+
+    pub fn remove(&mut self, key: &ProgramKey) {
+        let document = match self.documents.remove(key) {
+            Some(d) => d,
+            None => return,
+        };
+        for gram in document.grams() {
+            if let Some(keys) = self.postings.get_mut(&gram) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&gram);
+                }
+            }
+        }
+    }
+
+    /// 空白区切りの各語をすべて含む番組を、スコアの高い順に返す。
+    pub fn search(&self, query: &str) -> Vec<(ProgramKey, usize)> {
+        let normalized = normalize(query);
+        let terms: Vec<&str> = normalized.split_whitespace().collect();
+        if terms.is_empty() {
+            return vec![];
+        }
+
+        // n-gram で候補を絞り込む。GRAM_SIZE に満たない短い語しかない場合は全件が候補になる。
+        let mut candidates: Option<BTreeSet<&ProgramKey>> = None;
+        for term in &terms {
+            if term.chars().count() < GRAM_SIZE {
+                continue;
+            }
+            for gram in ngrams(term) {
+                let keys: BTreeSet<&ProgramKey> = match self.postings.get(&gram) {
+                    Some(keys) => keys.iter().collect(),
+                    None => return vec![],
+                };
+                candidates = Some(match candidates {
+                    Some(c) => c.intersection(&keys).cloned().collect(),
+                    None => keys,
+                });
+            }
+        }
+        let candidates: Vec<&ProgramKey> = match candidates {
+            Some(c) => c.into_iter().collect(),
+            None => self.documents.keys().collect(),
+        };
+
+        // n-gram の一致は語の一致を保証しないため、実際の文字列で確認しつつスコアを付ける
+        let mut result = vec![];
+        'candidate: for key in candidates {
+            let document = &self.documents[key];
+            let mut score = 0;
+            for term in &terms {
+                match document.score(term) {
+                    0 => continue 'candidate,
+                    n => score += n,
+                }
+            }
+            result.push((key.clone(), score));
+        }
+        result.sort_by(|(a_key, a_score), (b_key, b_score)| b_score.cmp(a_score).then_with(|| b_key.cmp(a_key)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtvault_types::shibafu528::dtvault as types;
+
+    fn program(event_id: u32, name: &str, description: &str) -> Program {
+        let p = types::Program {
+            network_id: 32736,
+            service_id: 1024,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: event_id as i64 * 100,
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            name: name.to_string(),
+            description: description.to_string(),
+            extended: vec![types::ExtendedEvent {
+                key: "出演者".to_string(),
+                value: "山田太郎".to_string(),
+            }],
+            ..Default::default()
+        };
+        Program::from_exchanged(p).unwrap()
+    }
+
+    fn event_ids(result: &[(ProgramKey, usize)]) -> Vec<u32> {
+        result.iter().map(|(k, _)| k.exchangeable().event_id).collect()
+    }
+
+    #[test]
+    fn test_normalize_width() {
+        assert_eq!("abc123", normalize("ＡＢＣ１２３"));
+        assert_eq!("が", normalize("ｶﾞ"));
+    }
+
+    #[test]
+    fn test_normalize_kana() {
+        assert_eq!("あにめ", normalize("アニメ"));
+        assert_eq!("あにめ", normalize("ｱﾆﾒ"));
+    }
+
+    #[test]
+    fn test_search() {
+        let mut index = SearchIndex::new();
+        index.insert(&program(1, "ドキドキ!秘蔵のもふもふ動物動画", "犬や猫"));
+        index.insert(&program(2, "ニュース", "今日の動物"));
+        index.insert(&program(3, "天気予報", ""));
+
+        assert_eq!(vec![1, 2], event_ids(&index.search("動物")));
+        assert_eq!(vec![1], event_ids(&index.search("ﾓﾌﾓﾌ")));
+        assert_eq!(vec![2], event_ids(&index.search("動物 ニュース")));
+        assert_eq!(vec![3, 2, 1], event_ids(&index.search("山田")));
+        assert!(index.search("宇宙").is_empty());
+    }
+
+    #[test]
+    fn test_search_single_char() {
+        let mut index = SearchIndex::new();
+        index.insert(&program(1, "犬", ""));
+        index.insert(&program(2, "猫", ""));
+
+        assert_eq!(vec![1], event_ids(&index.search("犬")));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut index = SearchIndex::new();
+        let p = program(1, "ニュース", "");
+        index.insert(&p);
+        index.remove(&ProgramKey::from_stored_program(&p));
+
+        assert!(index.search("ニュース").is_empty());
+        assert!(index.postings.is_empty());
+    }
+}
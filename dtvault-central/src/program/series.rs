@@ -0,0 +1,223 @@
+use crate::config::SeriesRule;
+use crate::program::{Program, ProgramKey};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use unicode_normalization::UnicodeNormalization;
+
+// 【新】【字】 や [字] [再] などの記号。角括弧は番組名の一部に使われることもあるため、短いものだけを対象とする。
+static MARKER_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"【[^】]*】|\[[^\]]{1,3}\]").unwrap());
+static EPISODE_PATTERN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"#\s*(?P<num>\d+)|第\s*(?P<kanji>\d+|[〇一二三四五六七八九十百千]+)\s*[話回]").unwrap());
+static SUBTITLE_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"「(?P<subtitle>[^」]+)」").unwrap());
+static SPACE_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+").unwrap());
+
+// シリーズ名の末尾に残りがちな区切り文字
+const TRAILING_SEPARATORS: &[char] = &['-', '・', ':', '/', '▽', '▼', '◆', '◇', '~'];
+
+/// 番組名から読み取ったシリーズ名と話数、サブタイトル
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ParsedTitle {
+    pub series_title: String,
+    pub number: Option<u32>,
+    pub subtitle: String,
+}
+
+/// 番組名を解析する。
+/// `アニメ #5「出会い」` や `アニメ 第五話「出会い」` のような番組名から、シリーズ名・話数・サブタイトルを取り出す。
+pub fn parse_title(name: &str) -> ParsedTitle {
+    // 囲み文字の記号 (🈑 など) は NFKC で普通の文字になってしまうため、正規化の前に取り除く
+    let name: String = name
+        .chars()
+        .filter(|c| !('\u{1F100}'..='\u{1F2FF}').contains(c))
+        .nfkc()
+        .collect();
+    let name = MARKER_PATTERN.replace_all(&name, " ");
+
+    let episode = EPISODE_PATTERN.captures(&name);
+    let subtitle = SUBTITLE_PATTERN.captures(&name);
+    let number = episode.as_ref().and_then(|c| match (c.name("num"), c.name("kanji")) {
+        (Some(num), _) => num.as_str().parse().ok(),
+        (_, Some(kanji)) => parse_kanji_number(kanji.as_str()),
+        _ => None,
+    });
+
+    // 話数やサブタイトルより前の部分をシリーズ名とみなす
+    let head = [
+        episode.as_ref().map(|c| c.get(0).unwrap().start()),
+        subtitle.as_ref().map(|c| c.get(0).unwrap().start()),
+    ]
+    .iter()
+    .flatten()
+    .min()
+    .copied();
+    let series_title = match head {
+        Some(head) if !clean_title(&name[..head]).is_empty() => clean_title(&name[..head]),
+        _ => {
+            let rest = EPISODE_PATTERN.replace_all(&name, " ");
+            clean_title(&SUBTITLE_PATTERN.replace_all(&rest, " "))
+        }
+    };
+
+    ParsedTitle {
+        series_title,
+        number,
+        subtitle: subtitle.map_or_else(String::new, |c| c["subtitle"].trim().to_string()),
+    }
+}
+
+fn clean_title(s: &str) -> String {
+    SPACE_PATTERN
+        .replace_all(s, " ")
+        .trim()
+        .trim_end_matches(TRAILING_SEPARATORS)
+        .trim()
+        .to_string()
+}
+
+/// 一万未満の漢数字を解釈する。`五`, `十二`, `二十`, `百五` や、位取りを使わない `一〇` に対応する。
+fn parse_kanji_number(s: &str) -> Option<u32> {
+    if let Ok(n) = s.parse() {
+        return Some(n);
+    }
+
+    let digit = |c: char| "〇一二三四五六七八九".chars().position(|d| d == c).map(|n| n as u32);
+    let mut total = 0;
+    let mut current: Option<u32> = None;
+    let mut positional = true;
+    for c in s.chars() {
+        let unit = match c {
+            '十' => 10,
+            '百' => 100,
+            '千' => 1000,
+            _ => {
+                let d = digit(c)?;
+                current = Some(current.map_or(d, |n| if positional { n * 10 + d } else { d }));
+                continue;
+            }
+        };
+        positional = false;
+        total += current.unwrap_or(1) * unit;
+        current = None;
+    }
+    let total = total + current.unwrap_or(0);
+    if total == 0 && !s.contains('〇') {
+        None
+    } else {
+        Some(total)
+    }
+}
+
+/// 番組が属するシリーズを判定する。シリーズとして扱わない場合は None を返す。
+pub fn detect_series(program: &Program, rules: &[SeriesRule]) -> Option<ParsedTitle> {
+    let mut parsed = parse_title(&program.name);
+    if let Some(rule) = rules.iter().find(|r| r.matches(program)) {
+        if rule.exclude {
+            return None;
+        }
+        parsed.series_title = rule.series_title.clone();
+    }
+
+    if parsed.series_title.is_empty() {
+        None
+    } else {
+        Some(parsed)
+    }
+}
+
+/// シリーズと、シリーズに属する番組
+pub struct ProgramSeries {
+    pub title: String,
+    /// 放送日時順
+    pub programs: Vec<(Arc<Program>, ParsedTitle)>,
+}
+
+/// シリーズ名から番組を引くためのインデックス
+#[derive(Default)]
+pub struct SeriesIndex {
+    series: BTreeMap<String, BTreeSet<ProgramKey>>,
+    programs: BTreeMap<ProgramKey, ParsedTitle>,
+}
+
+impl SeriesIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, program: &Program, rules: &[SeriesRule]) {
+        let key = ProgramKey::from_stored_program(program);
+        self.remove(&key);
+
+        if let Some(parsed) = detect_series(program, rules) {
+            self.series
+                .entry(parsed.series_title.clone())
+                .or_default()
+                .insert(key.clone());
+            self.programs.insert(key, parsed);
+        }
+    }
+
+    pub fn remove(&mut self, key: &ProgramKey) {
+        let parsed = match self.programs.remove(key) {
+            Some(p) => p,
+            None => return,
+        };
+        if let Some(keys) = self.series.get_mut(&parsed.series_title) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.series.remove(&parsed.series_title);
+            }
+        }
+    }
+
+    /// シリーズ名順に、シリーズ名とシリーズに属する番組を返す。
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &BTreeSet<ProgramKey>)> {
+        self.series.iter()
+    }
+
+    pub fn get(&self, series_title: &str) -> Option<&BTreeSet<ProgramKey>> {
+        self.series.get(series_title)
+    }
+
+    pub fn parsed_title(&self, key: &ProgramKey) -> Option<&ParsedTitle> {
+        self.programs.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str) -> (String, Option<u32>, String) {
+        let p = parse_title(name);
+        (p.series_title, p.number, p.subtitle)
+    }
+
+    #[test]
+    fn test_parse_title() {
+        assert_eq!(
+            ("アニメ".to_string(), Some(5), "出会い".to_string()),
+            parse("【新】アニメ #5「出会い」[字]")
+        );
+        assert_eq!(
+            ("アニメ".to_string(), Some(12), "別れ".to_string()),
+            parse("アニメ 第十二話「別れ」[再]")
+        );
+        assert_eq!(("アニメ".to_string(), Some(3), "".to_string()), parse("アニメ　＃３"));
+        assert_eq!(("ニュース".to_string(), None, "".to_string()), parse("ニュース🈑"));
+        assert_eq!(
+            ("[Alexandros]ライブ".to_string(), None, "".to_string()),
+            parse("[Alexandros]ライブ")
+        );
+    }
+
+    #[test]
+    fn test_parse_kanji_number() {
+        assert_eq!(Some(5), parse_kanji_number("五"));
+        assert_eq!(Some(20), parse_kanji_number("二十"));
+        assert_eq!(Some(105), parse_kanji_number("百五"));
+        assert_eq!(Some(10), parse_kanji_number("一〇"));
+        assert_eq!(None, parse_kanji_number("話"));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::program::{ChannelType, Program, ProgramKey, ServiceId, Video};
+use chrono::{Datelike, Local};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// 動画の本数と合計サイズ
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct Usage {
+    pub video_count: usize,
+    pub total_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, video: &Video) {
+        self.video_count += 1;
+        self.total_bytes += video.total_length;
+    }
+}
+
+/// ライブラリ全体の集計。ゴミ箱の中の番組と動画は trashed 以外には含めない。
+#[derive(Default)]
+pub struct Statistics {
+    pub program_count: usize,
+    pub total: Usage,
+    pub trashed: Usage,
+    pub videos_without_thumbnail: usize,
+    pub by_storage: BTreeMap<Uuid, Usage>,
+    /// 番組にチャンネルの情報がない場合は None
+    pub by_channel_type: BTreeMap<Option<ChannelType>, Usage>,
+    pub by_service: BTreeMap<ServiceId, Usage>,
+    pub by_provider: BTreeMap<String, Usage>,
+    /// 番組の放送開始日時 (ローカル時刻) の年と月
+    pub by_month: BTreeMap<(i32, u32), Usage>,
+}
+
+impl Statistics {
+    pub fn compute<'a, P, V>(programs: P, videos: V) -> Self
+    where
+        P: IntoIterator<Item = (&'a ProgramKey, &'a Arc<Program>)>,
+        V: IntoIterator<Item = &'a Arc<Video>>,
+    {
+        let programs: BTreeMap<&ProgramKey, &Arc<Program>> = programs.into_iter().collect();
+        let mut stats = Statistics {
+            program_count: programs.values().filter(|p| p.trashed_at.is_none()).count(),
+            ..Default::default()
+        };
+
+        for video in videos {
+            let program = programs.get(video.program_key());
+            if video.trashed_at.is_some() || program.is_some_and(|p| p.trashed_at.is_some()) {
+                stats.trashed.add(video);
+                continue;
+            }
+
+            stats.total.add(video);
+            if video.thumbnail_blob.is_none() {
+                stats.videos_without_thumbnail += 1;
+            }
+            stats.by_storage.entry(video.storage_id).or_default().add(video);
+            stats
+                .by_provider
+                .entry(video.provider_id.clone())
+                .or_default()
+                .add(video);
+            if let Some(program) = program {
+                let channel_type = program
+                    .service
+                    .as_ref()
+                    .and_then(|s| s.channel.as_ref())
+                    .map(|c| c.channel_type);
+                stats.by_channel_type.entry(channel_type).or_default().add(video);
+                stats
+                    .by_service
+                    .entry((program.network_id, program.service_id))
+                    .or_default()
+                    .add(video);
+                let start_at = program.start_at.with_timezone(&Local);
+                stats
+                    .by_month
+                    .entry((start_at.year(), start_at.month()))
+                    .or_default()
+                    .add(video);
+            }
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use dtvault_types::shibafu528::dtvault as types;
+    use dtvault_types::shibafu528::dtvault::storage::create_video_request::Header as VideoHeader;
+
+    fn program(event_id: u32) -> Arc<Program> {
+        let p = types::Program {
+            network_id: 32736,
+            service_id: 1024,
+            event_id,
+            start_at: Some(prost_types::Timestamp {
+                seconds: 1617202800, // 2021-04-01 00:00:00 +09:00
+                nanos: 0,
+            }),
+            duration: Some(prost_types::Duration {
+                seconds: 1800,
+                nanos: 0,
+            }),
+            name: "ニュース".to_string(),
+            ..Default::default()
+        };
+        Arc::new(Program::from_exchanged(p).unwrap())
+    }
+
+    fn video(program: &Program, total_length: u64) -> Video {
+        Video::from_exchanged(
+            program,
+            VideoHeader {
+                provider_id: "chinachu".to_string(),
+                total_length,
+                file_name: "video.m2ts".to_string(),
+                mime_type: "video/mp2t".to_string(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_compute() {
+        let mut trashed = (*program(2)).clone();
+        trashed.trashed_at = Some(Utc::now());
+        let programs = [program(1), Arc::new(trashed)];
+        let keys: Vec<ProgramKey> = programs.iter().map(|p| ProgramKey::from_stored_program(p)).collect();
+        let mut with_thumbnail = video(&programs[0], 100);
+        with_thumbnail.thumbnail_blob = Some("ffd8".to_string());
+        let videos = [
+            Arc::new(with_thumbnail),
+            Arc::new(video(&programs[0], 200)),
+            Arc::new(video(&programs[1], 400)),
+        ];
+
+        let stats = Statistics::compute(keys.iter().zip(programs.iter()), videos.iter());
+        assert_eq!(1, stats.program_count);
+        assert_eq!(
+            Usage {
+                video_count: 2,
+                total_bytes: 300
+            },
+            stats.total
+        );
+        assert_eq!(400, stats.trashed.total_bytes);
+        assert_eq!(1, stats.videos_without_thumbnail);
+        assert_eq!(300, stats.by_provider["chinachu"].total_bytes);
+        assert_eq!(300, stats.by_service[&(32736, 1024)].total_bytes);
+        assert_eq!(300, stats.by_channel_type[&None].total_bytes);
+        assert_eq!(1, stats.by_month.len());
+    }
+}
@@ -24,6 +24,39 @@ pub fn validate_service(value: &Service) -> Result<&Service, String> {
     Ok(value)
 }
 
+pub fn validate_metadata_key(value: &str) -> Result<&str, String> {
+    if value.is_empty() {
+        return Err("Invalid value: key".to_string());
+    }
+    if value.len() > 255 {
+        return Err("String too long: key".to_string());
+    }
+
+    Ok(value)
+}
+
+pub fn validate_metadata_value(value: &str) -> Result<&str, String> {
+    if value.len() > 1024 * 1024 {
+        return Err("String too long: value".to_string());
+    }
+
+    Ok(value)
+}
+
+pub fn validate_tag(value: &str) -> Result<&str, String> {
+    if value.trim().is_empty() {
+        return Err("Invalid value: tag".to_string());
+    }
+    if value.len() > 255 {
+        return Err("String too long: tag".to_string());
+    }
+    if value.chars().any(|c| c.is_control()) {
+        return Err("Invalid value: tag".to_string());
+    }
+
+    Ok(value)
+}
+
 pub fn validate_program_id(value: &ProgramIdentity) -> Result<&ProgramIdentity, String> {
     if value.service_id == 0 {
         return Err("Invalid value: service_id".to_string());
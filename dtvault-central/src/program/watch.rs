@@ -0,0 +1,117 @@
+use crate::program::prost_convert::{ToDateTimeExt, ToDurationExt, ToTimestampExt};
+use crate::program::{MessageConversionError, Persistence};
+use chrono::{DateTime, Utc};
+use dtvault_types::shibafu528::dtvault as types;
+use dtvault_types::shibafu528::dtvault::central::PersistWatchState;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// 動画の視聴状況
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct WatchState {
+    /// 最後に再生していた位置
+    pub position: Duration,
+    pub watched: bool,
+    pub play_count: u32,
+    pub last_watched_at: Option<DateTime<Utc>>,
+}
+
+impl WatchState {
+    pub fn apply(&mut self, update: &WatchStateUpdate, at: DateTime<Utc>) {
+        if update.started {
+            self.play_count += 1;
+        }
+        if let Some(position) = update.position {
+            self.position = position;
+        }
+        if let Some(watched) = update.watched {
+            self.watched = watched;
+        }
+        if update.started || update.position.is_some() {
+            self.last_watched_at = Some(at);
+        }
+    }
+
+    pub fn exchangeable(&self) -> types::WatchState {
+        types::WatchState {
+            position: Some(self.position.into()),
+            watched: self.watched,
+            play_count: self.play_count,
+            last_watched_at: self.last_watched_at.map(|t| t.to_timestamp()),
+        }
+    }
+}
+
+impl Persistence<PersistWatchState> for WatchState {
+    fn from_persisted(persisted: PersistWatchState) -> Result<Self, MessageConversionError> {
+        Ok(WatchState {
+            position: persisted.position.map_or_else(Duration::default, |d| d.to_duration()),
+            watched: persisted.watched,
+            play_count: persisted.play_count,
+            last_watched_at: persisted.last_watched_at.map(|t| t.to_utc()),
+        })
+    }
+
+    fn persist(&self) -> PersistWatchState {
+        PersistWatchState {
+            position: Some(self.position.into()),
+            watched: self.watched,
+            play_count: self.play_count,
+            last_watched_at: self.last_watched_at.map(|t| t.to_timestamp()),
+        }
+    }
+}
+
+/// 視聴状況の更新内容
+#[derive(Default, Debug)]
+pub struct WatchStateUpdate {
+    /// 再生を開始した
+    pub started: bool,
+    pub position: Option<Duration>,
+    pub watched: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_apply() {
+        let mut state = WatchState::default();
+        let at = Utc.timestamp(1000, 0);
+
+        state.apply(
+            &WatchStateUpdate {
+                started: true,
+                position: Some(Duration::from_secs(0)),
+                ..Default::default()
+            },
+            at,
+        );
+        assert_eq!(1, state.play_count);
+        assert_eq!(Some(at), state.last_watched_at);
+
+        state.apply(
+            &WatchStateUpdate {
+                position: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            at,
+        );
+        assert_eq!(1, state.play_count);
+        assert_eq!(Duration::from_secs(600), state.position);
+        assert!(!state.watched);
+
+        let later = Utc.timestamp(2000, 0);
+        state.apply(
+            &WatchStateUpdate {
+                watched: Some(true),
+                ..Default::default()
+            },
+            later,
+        );
+        assert!(state.watched);
+        assert_eq!(Some(at), state.last_watched_at);
+    }
+}
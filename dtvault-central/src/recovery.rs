@@ -0,0 +1,253 @@
+use crate::program::{PersistError, ProgramKey, ProgramStore};
+use crate::video_storage::IStorage;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// ストレージの内容と ProgramStore を突き合わせた結果
+#[derive(Default)]
+pub struct RecoveryReport {
+    /// ストレージと ProgramStore の両方にあり、内容が一致した動画の数
+    pub matched: usize,
+    /// 取り込んだ (dry_run の場合は取り込む予定の) 番組と動画の数
+    pub imported_programs: usize,
+    pub imported_videos: usize,
+    /// ProgramStore とストレージで内容が食い違っているもの。ProgramStore の内容を残す
+    pub conflicts: Vec<String>,
+    /// ストレージにだけあったもの。dry_run でなければ取り込む
+    pub orphans: Vec<String>,
+    /// ProgramStore にあるが、利用可能なストレージに見つからなかったもの
+    pub missing: Vec<String>,
+    /// 読み込めなかったストレージや動画についてのエラーメッセージ
+    pub failures: Vec<String>,
+}
+
+/// 全てのストレージから動画と一緒に保存した番組と動画の情報を読み込み、ProgramStore にないものを取り込む。
+/// 空の ProgramStore に対して行えば、ストレージの内容からデータベースを作り直すことになる。
+pub async fn recover(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    dry_run: bool,
+) -> Result<RecoveryReport, PersistError> {
+    let mut report = RecoveryReport::default();
+    let mut scanned_storages = HashMap::new();
+    let mut seen = HashMap::new();
+    let mut imported_programs = BTreeSet::new();
+
+    for storage in storages {
+        let storage_id = match storage.storage_id().await {
+            Ok(id) => id,
+            Err(e) => {
+                report.failures.push(format!("Storage `{}`: {}", storage.label(), e));
+                continue;
+            }
+        };
+        let scan = match storage.scan_sidecars().await {
+            Ok(scan) => scan,
+            Err(e) => {
+                report.failures.push(format!("Storage `{}`: {}", storage.label(), e));
+                continue;
+            }
+        };
+        scanned_storages.insert(storage_id, storage.label().to_string());
+        report.failures.extend(scan.failures);
+
+        for sidecar in scan.sidecars {
+            let video = sidecar.video;
+            if let Some(location) = seen.get(&video.id) {
+                report.conflicts.push(format!(
+                    "Video {} is found in both {} and {}",
+                    video.id, location, sidecar.location
+                ));
+                continue;
+            }
+            seen.insert(video.id, sidecar.location.clone());
+
+            if let Some(stored) = store.find_video(&video.id)? {
+                // 統合された番組の動画は、統合先の番組に付いていれば一致として扱う
+                let program_key = match store.find_with_alias(video.program_key())? {
+                    Some(p) => ProgramKey::from_stored_program(&p),
+                    None => video.program_key().clone(),
+                };
+                let mut differences = vec![];
+                if &program_key != stored.program_key() {
+                    differences.push("program");
+                }
+                if stored.storage_id != video.storage_id || stored.storage_prefix != video.storage_prefix {
+                    differences.push("location");
+                }
+                if stored.file_name != video.file_name {
+                    differences.push("file_name");
+                }
+                if differences.is_empty() {
+                    report.matched += 1;
+                } else {
+                    report.conflicts.push(format!(
+                        "Video {} in {} differs from database in {}",
+                        video.id,
+                        sidecar.location,
+                        differences.join(", ")
+                    ));
+                }
+                continue;
+            }
+
+            let key = ProgramKey::from_stored_program(&sidecar.program);
+            report
+                .orphans
+                .push(format!("Video {} (program {}) in {}", video.id, key, sidecar.location));
+            if store.find_with_alias(&key)?.is_none() && imported_programs.insert(key) {
+                report.imported_programs += 1;
+            }
+            report.imported_videos += 1;
+            if !dry_run {
+                store.import_video(sidecar.program, video, sidecar.thumbnail)?;
+            }
+        }
+    }
+
+    // ストレージで見つかった動画は全て seen に入っている
+    for video in store.snapshot()?.videos {
+        if seen.contains_key(&video.id) {
+            continue;
+        }
+        if let Some(label) = scanned_storages.get(&video.storage_id) {
+            report.missing.push(format!(
+                "Video {} (program {}) is not found in storage `{}`",
+                video.id,
+                video.program_key(),
+                label
+            ));
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::program::Video;
+    use crate::video_storage::FileSystem;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_dir = dir.path().join("storage");
+        std::fs::create_dir_all(&root_dir).unwrap();
+        let storage: Arc<IStorage> = Arc::new(FileSystem::new(
+            "default".to_string(),
+            root_dir.to_str().unwrap().to_string(),
+        ));
+        let storages = vec![storage.clone()];
+
+        let source_dir = dir.path().join("source");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        let source = ProgramStore::new(Config::for_test(&source_dir, "")).unwrap();
+        let (sp, _) = source
+            .find_or_create(dtvault_types::shibafu528::dtvault::Program {
+                network_id: 32736,
+                service_id: 1024,
+                event_id: 1,
+                start_at: Some(prost_types::Timestamp {
+                    seconds: 1617202800,
+                    nanos: 0,
+                }),
+                duration: Some(prost_types::Duration {
+                    seconds: 1800,
+                    nanos: 0,
+                }),
+                name: "ニュース".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        let key = ProgramKey::from_stored_program(&sp);
+        source.update_program_metadata(&key, "note", "sidecar").unwrap();
+        let sp = source.find(&key).unwrap().unwrap();
+        for provider_id in &["a", "b"] {
+            let mut video = Video::from_exchanged(
+                &sp,
+                dtvault_types::shibafu528::dtvault::storage::create_video_request::Header {
+                    provider_id: provider_id.to_string(),
+                    file_name: "video.m2ts".to_string(),
+                    mime_type: "video/mp2t".to_string(),
+                    ..Default::default()
+                },
+            );
+            video.storage_id = storage.storage_id().await.unwrap();
+            let mut writer = storage.create(&sp, &video).await.unwrap();
+            writer.write_all(b"ts").await.unwrap();
+            writer.flush().await.unwrap();
+            writer.as_mut().finish().await.unwrap();
+            source.create_video(&key, video).unwrap();
+        }
+        let videos = source.snapshot().unwrap().videos;
+        std::fs::remove_file(root_dir.join(videos[1].stringify_id()).join("video.json")).unwrap();
+
+        let target_dir = dir.path().join("target");
+        std::fs::create_dir_all(&target_dir).unwrap();
+        let target = ProgramStore::new(Config::for_test(&target_dir, "")).unwrap();
+        let report = recover(&target, &storages, true).await.unwrap();
+        assert_eq!((1, 1), (report.imported_programs, report.imported_videos));
+        assert!(target.find(&key).unwrap().is_none());
+
+        let report = recover(&target, &storages, false).await.unwrap();
+        assert_eq!(1, report.orphans.len());
+        let program = target.find(&key).unwrap().unwrap();
+        assert_eq!("ニュース", program.name);
+        assert_eq!(Some(&"sidecar".to_string()), program.metadata().get("note"));
+        assert_eq!(&vec![videos[0].id], program.video_ids());
+
+        let report = recover(&source, &storages, true).await.unwrap();
+        assert_eq!(1, report.matched);
+        assert_eq!(1, report.missing.len());
+        assert!(report.orphans.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recover_baseline_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_dir = dir.path().join("storage");
+        std::fs::create_dir_all(&root_dir).unwrap();
+        let storage: Arc<IStorage> = Arc::new(FileSystem::new(
+            "default".to_string(),
+            root_dir.to_str().unwrap().to_string(),
+        ));
+        let storage_id = storage.storage_id().await.unwrap();
+
+        // created_at などを書き出していなかった頃の形式
+        let video_id = "0b6b8d4c-5d0e-4c55-9a3e-1d2b5f7c9a10";
+        let video_dir = root_dir.join(video_id);
+        std::fs::create_dir_all(&video_dir).unwrap();
+        std::fs::write(
+            video_dir.join("program.json"),
+            r#"{"id":"6f1c3a2e-8b7d-4e5f-9a0b-1c2d3e4f5a6b","network_id":32736,"service_id":1024,"event_id":2,
+                "start_at":"2021-03-31T15:00:00Z","duration":{"secs":1800,"nanos":0},
+                "name":"ニュース","description":"","extended":[],"service":null}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            video_dir.join("video.json"),
+            format!(
+                r#"{{"id":"{}","provider_id":"old","program_id":{{"start_at":"2021-03-31T15:00:00Z","network_id":32736,
+                    "service_id":1024,"event_id":2}},"total_length":2,"file_name":"video.m2ts",
+                    "original_file_name":"video.m2ts","mime_type":"video/mp2t","storage_id":"{}","storage_prefix":""}}"#,
+                video_id, storage_id
+            ),
+        )
+        .unwrap();
+        std::fs::write(video_dir.join("video.m2ts"), b"ts").unwrap();
+
+        let store = ProgramStore::new(Config::for_test(dir.path(), "")).unwrap();
+        let report = recover(&store, &[storage], false).await.unwrap();
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+        assert_eq!((1, 1), (report.imported_programs, report.imported_videos));
+        let video = store
+            .find_video(&uuid::Uuid::parse_str(video_id).unwrap())
+            .unwrap()
+            .unwrap();
+        let program = store.find(video.program_key()).unwrap().unwrap();
+        assert_eq!(program.start_at, program.created_at);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::program::{Change, ChangeKind, MutexPoisonError, ProgramKey, ProgramStore, Video};
+use crate::video_storage::{find_storage_by_id, IStorage, SidecarError};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SyncError {
+    #[error("Program not found (id = {0})")]
+    ProgramNotFound(ProgramKey),
+    #[error("Storage not found (storage_id = {0})")]
+    StorageNotFound(Uuid),
+    #[error(transparent)]
+    SidecarError(#[from] SidecarError),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    Poisoned(#[from] MutexPoisonError),
+}
+
+/// まとめて書き直した結果
+#[derive(Default)]
+pub struct ResyncReport {
+    pub updated: usize,
+    pub failures: Vec<String>,
+}
+
+/// 動画と一緒に保存している番組と動画の情報を、ProgramStore の現在の内容で書き直す。
+pub async fn sync_video(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    video: &Video,
+    with_thumbnail: bool,
+) -> Result<(), SyncError> {
+    let program = store
+        .find(video.program_key())?
+        .ok_or_else(|| SyncError::ProgramNotFound(video.program_key().clone()))?;
+    let storage = find_storage_by_id(storages, &video.storage_id)
+        .await
+        .ok_or(SyncError::StorageNotFound(video.storage_id))?;
+    let thumbnail = match &video.thumbnail_blob {
+        Some(key) if with_thumbnail => store.read_blob(key)?,
+        _ => None,
+    };
+    storage.update_sidecars(&program, video, thumbnail.as_deref()).await?;
+    Ok(())
+}
+
+/// 全ての動画について、番組と動画の情報とサムネイルを書き直す。
+/// 一部の書き込みに失敗しても残りは続行し、失敗の内容を ResyncReport に記録する。
+pub async fn resync_all(store: &ProgramStore, storages: &[Arc<IStorage>]) -> Result<ResyncReport, MutexPoisonError> {
+    let mut report = ResyncReport::default();
+    for video in store.snapshot()?.videos {
+        match sync_video(store, storages, &video, true).await {
+            Ok(_) => report.updated += 1,
+            Err(e) => report.failures.push(format!("Video {}: {}", video.id, e)),
+        }
+    }
+    Ok(report)
+}
+
+/// 変更の影響を受ける動画の ID と、サムネイルも書き直すかどうか
+fn affected_videos(change: &Change) -> Vec<(Uuid, bool)> {
+    match change.kind {
+        // 削除された動画はディレクトリごと消える。作成時の内容は Storage::create で書き込み済み
+        ChangeKind::ProgramDeleted | ChangeKind::VideoDeleted | ChangeKind::VideoCreated => vec![],
+        ChangeKind::ProgramCreated | ChangeKind::ProgramUpdated => change
+            .program
+            .as_ref()
+            .map_or_else(Vec::new, |p| p.video_ids().iter().map(|id| (*id, false)).collect()),
+        ChangeKind::VideoUpdated => change.video.iter().map(|v| (v.id, false)).collect(),
+        ChangeKind::ThumbnailUpdated => change.video.iter().map(|v| (v.id, true)).collect(),
+    }
+}
+
+/// ProgramStore の変更を購読し、影響を受けた動画の番組と動画の情報を書き直すタスクを起動する。
+pub fn spawn_sidecar_writer(store: Arc<ProgramStore>, storages: Vec<Arc<IStorage>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (_, mut receiver) = match store.change_feed().subscribe(0) {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                eprintln!("[Sidecar] error: {}", e);
+                return;
+            }
+        };
+        loop {
+            match receiver.recv().await {
+                Ok(change) => {
+                    for (video_id, with_thumbnail) in affected_videos(&change) {
+                        // 変更が届くまでの間に更に変更されていることがあるので、最新の内容を書き込む
+                        let result = match store.find_video(&video_id) {
+                            Ok(Some(video)) => sync_video(&store, &storages, &video, with_thumbnail).await,
+                            Ok(None) => Ok(()),
+                            Err(e) => Err(e.into()),
+                        };
+                        if let Err(e) = result {
+                            eprintln!("[Sidecar] Video {}: {}", video_id, e);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("[Sidecar] {} changes skipped, resyncing all sidecars", skipped);
+                    match resync_all(&store, &storages).await {
+                        Ok(report) => {
+                            for failure in report.failures {
+                                eprintln!("[Sidecar] {}", failure);
+                            }
+                        }
+                        Err(e) => eprintln!("[Sidecar] error: {}", e),
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::program::FieldValue;
+    use crate::video_storage::FileSystem;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_resync_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_dir = dir.path().join("storage");
+        std::fs::create_dir_all(&root_dir).unwrap();
+        let storage: Arc<IStorage> = Arc::new(FileSystem::new(
+            "default".to_string(),
+            root_dir.to_str().unwrap().to_string(),
+        ));
+        let storages = vec![storage.clone()];
+        let store = ProgramStore::new(Config::for_test(dir.path(), "")).unwrap();
+
+        let (sp, _) = store
+            .find_or_create(dtvault_types::shibafu528::dtvault::Program {
+                network_id: 32736,
+                service_id: 1024,
+                event_id: 1,
+                start_at: Some(prost_types::Timestamp {
+                    seconds: 1617202800,
+                    nanos: 0,
+                }),
+                duration: Some(prost_types::Duration {
+                    seconds: 1800,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        let key = ProgramKey::from_stored_program(&sp);
+        let mut video = Video::from_exchanged(
+            &sp,
+            dtvault_types::shibafu528::dtvault::storage::create_video_request::Header {
+                file_name: "video.m2ts".to_string(),
+                mime_type: "video/mp2t".to_string(),
+                ..Default::default()
+            },
+        );
+        video.storage_id = storage.storage_id().await.unwrap();
+        let mut writer = storage.create(&sp, &video).await.unwrap();
+        writer.write_all(b"ts").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.as_mut().finish().await.unwrap();
+        let video = store.create_video(&key, Video::clone(&video)).unwrap();
+
+        store.update_program_metadata(&key, "note", "synced").unwrap();
+        store
+            .update_program(&key, vec![FieldValue::Name("修正済み".to_string())], chrono::Utc::now())
+            .unwrap();
+        store.add_tags(&key, &["news".to_string()]).unwrap();
+        store
+            .update_video_thumbnail(&video.id, vec![0xff, 0xd8], mime::IMAGE_JPEG)
+            .unwrap();
+        let report = resync_all(&store, &storages).await.unwrap();
+        assert_eq!(1, report.updated);
+        assert!(report.failures.is_empty());
+
+        let scan = storage.scan_sidecars().await.unwrap();
+        assert_eq!(1, scan.sidecars.len());
+        let sidecar = &scan.sidecars[0];
+        assert_eq!(Some(&"synced".to_string()), sidecar.program.metadata().get("note"));
+        assert_eq!("修正済み", sidecar.program.name);
+        assert_eq!(1, sidecar.program.edits().len());
+        assert!(sidecar.program.tags().contains("news"));
+        assert_eq!(Some(vec![0xff, 0xd8]), sidecar.thumbnail);
+        assert_eq!(Some(mime::IMAGE_JPEG), sidecar.video.thumbnail_mime_type);
+    }
+}
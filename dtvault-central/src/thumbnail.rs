@@ -0,0 +1,176 @@
+use dtvault_types::shibafu528::dtvault::central::get_thumbnail_request::Size;
+use dtvault_types::shibafu528::dtvault::encoder::encoder_service_client::EncoderServiceClient;
+use dtvault_types::shibafu528::dtvault::encoder::generate_thumbnail_request::{
+    Datagram as RequestDatagram, Header as RequestHeader, OutputFormat, Part as RequestPart,
+};
+use dtvault_types::shibafu528::dtvault::encoder::generate_thumbnail_response::Part as ResponsePart;
+use dtvault_types::shibafu528::dtvault::encoder::GenerateThumbnailRequest;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio_stream::StreamExt;
+use tonic::transport::Uri;
+use uuid::Uuid;
+
+// 縮小したサムネイルをメモリ上に保持しておく数
+const CACHE_CAPACITY: usize = 1024;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ThumbnailSize {
+    /// 動画の登録時に生成したサムネイル (854x480)
+    Original,
+    Small,
+    Medium,
+}
+
+impl ThumbnailSize {
+    pub fn from_exchanged(size: i32) -> Option<Self> {
+        match Size::from_i32(size)? {
+            Size::Original => Some(ThumbnailSize::Original),
+            Size::Small => Some(ThumbnailSize::Small),
+            Size::Medium => Some(ThumbnailSize::Medium),
+        }
+    }
+
+    pub fn exchangeable(&self) -> Size {
+        match self {
+            ThumbnailSize::Original => Size::Original,
+            ThumbnailSize::Small => Size::Small,
+            ThumbnailSize::Medium => Size::Medium,
+        }
+    }
+
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        match self {
+            ThumbnailSize::Original => None,
+            ThumbnailSize::Small => Some((320, 180)),
+            ThumbnailSize::Medium => Some((640, 360)),
+        }
+    }
+
+    /// original_digest を元のサムネイルの blob のキーとして、このサイズの ETag を作る。
+    pub fn etag(&self, original_digest: &str) -> String {
+        match self.dimensions() {
+            Some((width, height)) => format!("{}-{}x{}", original_digest, width, height),
+            None => original_digest.to_string(),
+        }
+    }
+}
+
+type CacheKey = (Uuid, ThumbnailSize);
+
+#[derive(Default)]
+struct CacheEntries {
+    /// ETag と画像
+    map: HashMap<CacheKey, (String, Arc<Vec<u8>>)>,
+    /// 追加した順
+    order: VecDeque<CacheKey>,
+}
+
+/// 縮小したサムネイルのキャッシュ。容量を超えた場合は古いものから捨てる。
+#[derive(Default)]
+pub struct ThumbnailCache {
+    entries: Mutex<CacheEntries>,
+}
+
+impl ThumbnailCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// etag が一致する場合だけ返す。
+    pub fn get(&self, video_id: Uuid, size: ThumbnailSize, etag: &str) -> Option<Arc<Vec<u8>>> {
+        let entries = self.entries.lock().ok()?;
+        match entries.map.get(&(video_id, size)) {
+            Some((cached_etag, data)) if cached_etag == etag => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, video_id: Uuid, size: ThumbnailSize, etag: String, data: Arc<Vec<u8>>) {
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        if entries.map.insert((video_id, size), (etag, data)).is_none() {
+            entries.order.push_back((video_id, size));
+        }
+        while entries.order.len() > CACHE_CAPACITY {
+            if let Some(key) = entries.order.pop_front() {
+                entries.map.remove(&key);
+            }
+        }
+    }
+}
+
+/// エンコーダを使って JPEG 画像を縮小する。
+pub async fn resize(
+    encoder_url: Uri,
+    image: &[u8],
+    size: ThumbnailSize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let (width, height) = match size.dimensions() {
+        Some(d) => d,
+        None => return Ok(image.to_vec()),
+    };
+
+    let requests = vec![
+        GenerateThumbnailRequest {
+            part: Some(RequestPart::Header(RequestHeader {
+                total_length: image.len() as u64,
+                output_format: OutputFormat::Jpeg as i32,
+                width,
+                height,
+                position: 0,
+            })),
+        },
+        GenerateThumbnailRequest {
+            part: Some(RequestPart::Datagram(RequestDatagram {
+                offset: 0,
+                payload: image.to_vec(),
+            })),
+        },
+    ];
+
+    let mut encoder_service_client = EncoderServiceClient::connect(encoder_url).await?;
+    let res = encoder_service_client
+        .generate_thumbnail(tokio_stream::iter(requests))
+        .await?;
+    let mut res_stream = res.into_inner();
+
+    let mut buffer = vec![];
+    while let Some(msg) = res_stream.next().await {
+        let msg = msg?;
+        #[allow(unreachable_patterns)]
+        match msg.part {
+            Some(ResponsePart::Datagram(mut data)) => buffer.append(&mut data.payload),
+            _ => Err("Invalid part: need datagram")?,
+        }
+    }
+    if buffer.is_empty() {
+        Err("Encoder returned an empty image")?;
+    }
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::digest;
+
+    #[test]
+    fn test_cache() {
+        let cache = ThumbnailCache::new();
+        let id = Uuid::new_v4();
+        let etag = ThumbnailSize::Small.etag(&digest(b"jpeg"));
+        cache.insert(id, ThumbnailSize::Small, etag.clone(), Arc::new(vec![1, 2, 3]));
+
+        assert_eq!(
+            Some(Arc::new(vec![1, 2, 3])),
+            cache.get(id, ThumbnailSize::Small, &etag)
+        );
+        assert_eq!(None, cache.get(id, ThumbnailSize::Medium, &etag));
+        let updated = ThumbnailSize::Small.etag(&digest(b"new jpeg"));
+        assert_eq!(None, cache.get(id, ThumbnailSize::Small, &updated));
+    }
+}
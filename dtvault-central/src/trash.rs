@@ -0,0 +1,237 @@
+use crate::config::Config;
+use crate::program::{Program, ProgramDeleteError, ProgramKey, ProgramStore, TrashError, Video, VideoDeleteError};
+use crate::video_storage::{delete_video_bin, require_storage_by_id, IStorage};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tonic::Status;
+use uuid::Uuid;
+
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// 完全に削除した番組と動画
+#[derive(Default)]
+pub struct PurgeReport {
+    pub programs: Vec<Arc<Program>>,
+    pub videos: Vec<Arc<Video>>,
+    pub failures: Vec<String>,
+}
+
+pub fn map_trash_error(e: TrashError) -> Status {
+    match e {
+        TrashError::ProgramNotFound(key) => Status::not_found(format!("Program not found (id = {})", key)),
+        TrashError::VideoNotFound(_) => Status::not_found("Video not found"),
+        TrashError::IoError(e) => Status::internal(format!("IO error: {}", e)),
+        TrashError::Poisoned(e) => Status::aborted(format!("{}", e)),
+    }
+}
+
+/// 動画をストレージとデータベースから完全に削除する。
+pub async fn purge_video(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    video: &Video,
+) -> Result<Arc<Video>, Status> {
+    let storage = require_storage_by_id(storages, video).await?;
+    delete_video_bin(storage.as_ref(), video).await?;
+    match store.delete_video(&video.id) {
+        Ok(v) => Ok(v),
+        Err(VideoDeleteError::VideoNotFound(_)) => Err(Status::not_found("Video not found")),
+        Err(VideoDeleteError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+        Err(VideoDeleteError::Poisoned(e)) => Err(Status::aborted(format!("{}", e))),
+    }
+}
+
+/// 番組と、番組に紐付く全ての動画をストレージとデータベースから完全に削除する。
+pub async fn purge_program(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    key: &ProgramKey,
+) -> Result<(Arc<Program>, Vec<Arc<Video>>), Status> {
+    let sp = match store.find(key) {
+        Ok(Some(sp)) => Ok(sp),
+        Ok(None) => Err(Status::not_found(format!("Program not found (id = {})", key))),
+        Err(e) => Err(Status::aborted(format!("{}", e))),
+    }?;
+    let videos = store
+        .find_videos(sp.video_ids())
+        .map_err(|e| Status::aborted(format!("{}", e)))?;
+
+    // 途中で止まって中途半端な状態にならないよう、先に全てのストレージが利用可能か確認する
+    let mut targets = vec![];
+    for video in videos.into_iter().flatten() {
+        let storage = require_storage_by_id(storages, &video).await?;
+        targets.push((video, storage));
+    }
+
+    for (video, storage) in &targets {
+        delete_video_bin(storage.as_ref(), video).await?;
+        match store.delete_video(&video.id) {
+            Ok(_) => {}
+            Err(VideoDeleteError::IoError(e)) => return Err(Status::internal(format!("IO error: {}", e))),
+            Err(e) => return Err(Status::aborted(format!("{}", e))),
+        }
+    }
+    let sp = match store.delete_program(key) {
+        Ok(sp) => Ok(sp),
+        Err(ProgramDeleteError::ProgramNotFound(key)) => {
+            Err(Status::not_found(format!("Program not found (id = {})", key)))
+        }
+        Err(ProgramDeleteError::IoError(e)) => Err(Status::internal(format!("IO error: {}", e))),
+        Err(ProgramDeleteError::Poisoned(e)) => Err(Status::aborted(format!("{}", e))),
+    }?;
+
+    Ok((sp, targets.into_iter().map(|(v, _)| v).collect()))
+}
+
+/// ゴミ箱に移動された日時が until 以前のものを完全に削除する。
+/// 一部の削除に失敗しても残りの削除は続行し、失敗の内容を PurgeReport に記録する。
+pub async fn empty_trash(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    until: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<PurgeReport, Status> {
+    let items = store
+        .trashed_items(until)
+        .map_err(|e| Status::aborted(format!("{}", e)))?;
+    if dry_run {
+        return Ok(PurgeReport {
+            programs: items.programs,
+            videos: items.videos,
+            failures: vec![],
+        });
+    }
+
+    let mut report = PurgeReport::default();
+    for program in items.programs {
+        let key = ProgramKey::from_stored_program(&program);
+        purge_trashed_program(store, storages, &key, until, &mut report).await;
+    }
+    for video in items.videos {
+        purge_trashed_video(store, storages, &video.id, until, &mut report).await;
+    }
+
+    Ok(report)
+}
+
+/// ゴミ箱の中の番組と、番組に紐付く全ての動画を完全に削除する。
+/// ゴミ箱から元に戻されたものを消してしまわないよう、ゴミ箱の中にあることを確かめながらデータベースから先に削除し、
+/// その後でストレージ上のファイルを削除する。
+async fn purge_trashed_program(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    key: &ProgramKey,
+    until: DateTime<Utc>,
+    report: &mut PurgeReport,
+) {
+    let sp = match store.find(key) {
+        Ok(Some(sp)) if sp.trashed_at.is_some_and(|t| t <= until) => sp,
+        Ok(_) => return,
+        Err(e) => {
+            report.failures.push(format!("Program {}: {}", key, e));
+            return;
+        }
+    };
+    let videos = match store.find_videos(sp.video_ids()) {
+        Ok(videos) => videos,
+        Err(e) => {
+            report.failures.push(format!("Program {}: {}", key, e));
+            return;
+        }
+    };
+
+    // 途中で止まって中途半端な状態にならないよう、先に全てのストレージが利用可能か確認する
+    for video in videos.into_iter().flatten() {
+        if let Err(e) = require_storage_by_id(storages, &video).await {
+            report.failures.push(format!("Program {}: {}", key, e.message()));
+            return;
+        }
+    }
+
+    let (sp, videos) = match store.delete_trashed_program(key, until) {
+        Ok(Some(deleted)) => deleted,
+        Ok(None) => return,
+        Err(e) => {
+            report.failures.push(format!("Program {}: {}", key, e));
+            return;
+        }
+    };
+    for video in &videos {
+        if let Err(e) = delete_trashed_video_bin(storages, video).await {
+            report.failures.push(format!("Video {}: {}", video.id, e.message()));
+        }
+    }
+    report.programs.push(sp);
+    report.videos.extend(videos);
+}
+
+/// ゴミ箱の中の動画を完全に削除する。削除の順序は purge_trashed_program と同じ。
+async fn purge_trashed_video(
+    store: &ProgramStore,
+    storages: &[Arc<IStorage>],
+    id: &Uuid,
+    until: DateTime<Utc>,
+    report: &mut PurgeReport,
+) {
+    let video = match store.find_video(id) {
+        Ok(Some(video)) if video.trashed_at.is_some_and(|t| t <= until) => video,
+        Ok(_) => return,
+        Err(e) => {
+            report.failures.push(format!("Video {}: {}", id, e));
+            return;
+        }
+    };
+    if let Err(e) = require_storage_by_id(storages, &video).await {
+        report.failures.push(format!("Video {}: {}", id, e.message()));
+        return;
+    }
+
+    let video = match store.delete_trashed_video(id, until) {
+        Ok(Some(video)) => video,
+        Ok(None) => return,
+        Err(e) => {
+            report.failures.push(format!("Video {}: {}", id, e));
+            return;
+        }
+    };
+    if let Err(e) = delete_trashed_video_bin(storages, &video).await {
+        report.failures.push(format!("Video {}: {}", id, e.message()));
+    }
+    report.videos.push(video);
+}
+
+async fn delete_trashed_video_bin(storages: &[Arc<IStorage>], video: &Video) -> Result<(), Status> {
+    let storage = require_storage_by_id(storages, video).await?;
+    delete_video_bin(storage.as_ref(), video).await
+}
+
+/// 保存期間を過ぎたゴミ箱の中身を定期的に削除するタスクを起動する。
+pub fn spawn_trash_purger(
+    config: Arc<Config>,
+    store: Arc<ProgramStore>,
+    storages: Vec<Arc<IStorage>>,
+) -> Option<JoinHandle<()>> {
+    let retention = config.trash.retention()?;
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match empty_trash(&store, &storages, Utc::now() - retention, false).await {
+                Ok(report) => {
+                    if !report.programs.is_empty() || !report.videos.is_empty() {
+                        println!(
+                            "[Trash] Purged {} programs, {} videos",
+                            report.programs.len(),
+                            report.videos.len()
+                        );
+                    }
+                    for failure in report.failures {
+                        eprintln!("[Trash] Purge failed: {}", failure);
+                    }
+                }
+                Err(e) => eprintln!("[Trash] error: {}", e),
+            }
+        }
+    }))
+}
@@ -9,13 +9,16 @@ pub use self::tempfile::*;
 use crate::config::Config;
 use crate::event::{Event, EventEmitter, VideoCreated};
 use crate::program::{validate_program_id, Program, ProgramKey, ProgramStore, Video, VideoWriteError};
+use crate::trash;
 use crate::video_storage::validator::validate_file_name;
+use chrono::Utc;
 use dtvault_types::shibafu528::dtvault::storage::create_video_request::Part as VideoPart;
 use dtvault_types::shibafu528::dtvault::storage::get_video_response::Datagram as GetVideoResponseDatagram;
 use dtvault_types::shibafu528::dtvault::storage::get_video_response::Part as GetVideoResponsePart;
 use dtvault_types::shibafu528::dtvault::storage::video_storage_service_server::VideoStorageService as VideoStorageServiceTrait;
 use dtvault_types::shibafu528::dtvault::storage::{
-    CreateVideoRequest, CreateVideoResponse, GetVideoRequest, GetVideoResponse,
+    CreateVideoRequest, CreateVideoResponse, DeleteVideoRequest, DeleteVideoResponse, GetVideoRequest,
+    GetVideoResponse, RestoreVideoRequest, RestoreVideoResponse,
 };
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -28,6 +31,41 @@ fn map_io_error(e: tokio::io::Error) -> Status {
     Status::internal(format!("IO error: {}", e))
 }
 
+pub async fn find_storage_by_id(storages: &[Arc<IStorage>], storage_id: &Uuid) -> Option<Arc<IStorage>> {
+    for storage in storages {
+        if let Ok(id) = storage.storage_id().await {
+            if id == *storage_id {
+                return Some(storage.clone());
+            }
+        }
+    }
+    None
+}
+
+/// 動画の格納先ストレージを探す。見つからない場合や利用できない場合は UNAVAILABLE を返す。
+pub async fn require_storage_by_id(storages: &[Arc<IStorage>], video: &Video) -> Result<Arc<IStorage>, Status> {
+    match find_storage_by_id(storages, &video.storage_id).await {
+        Some(s) if s.is_available() => Ok(s),
+        _ => Err(Status::unavailable(format!(
+            "Target storage is temporarily unavailable or not found (storage_id = {})",
+            video.storage_id
+        ))),
+    }
+}
+
+/// ストレージから動画を削除する。既にファイルが存在しない場合は成功として扱う。
+pub async fn delete_video_bin(storage: &IStorage, video: &Video) -> Result<(), Status> {
+    match storage.delete(video).await {
+        Ok(_) => Ok(()),
+        Err(DeleteError::NotFound) => {
+            eprintln!("Video {} is already missing in storage `{}`", video.id, storage.label());
+            Ok(())
+        }
+        Err(DeleteError::Unavailable(e)) => Err(Status::unavailable(format!("{}", e))),
+        Err(DeleteError::IoError(e)) => Err(Status::aborted(format!("{}", e))),
+    }
+}
+
 pub struct VideoStorageService {
     config: Arc<Config>,
     store: Arc<ProgramStore>,
@@ -80,17 +118,6 @@ impl VideoStorageService {
         // fallback
         self.primary_storage()
     }
-
-    async fn find_storage_by_id(&self, storage_id: &Uuid) -> Option<Arc<IStorage>> {
-        for storage in &self.storages {
-            if let Ok(id) = storage.storage_id().await {
-                if id == *storage_id {
-                    return Some(storage.clone());
-                }
-            }
-        }
-        None
-    }
 }
 
 #[tonic::async_trait]
@@ -144,17 +171,15 @@ impl VideoStorageServiceTrait for VideoStorageService {
         }
 
         // Check video existence
-        let videos = match self.store.find_videos(program.video_ids()) {
+        let videos = match self.store.find_videos_by_provider_id(&header.provider_id, true) {
             Ok(v) => Ok(v),
             Err(e) => Err(Status::aborted(format!("{}", e))),
         }?;
-        for video in videos.into_iter().filter_map(|v| v) {
-            if video.provider_id == header.provider_id {
-                return Err(Status::invalid_argument(format!(
-                    "Provider ID `{}` already exists",
-                    header.provider_id
-                )));
-            }
+        if videos.iter().any(|v| program.video_ids().contains(&v.id)) {
+            return Err(Status::invalid_argument(format!(
+                "Provider ID `{}` already exists",
+                header.provider_id
+            )));
         }
         let mut video = Video::from_exchanged(&program, header);
 
@@ -210,6 +235,7 @@ impl VideoStorageServiceTrait for VideoStorageService {
                 VideoWriteError::AlreadyExists(s) => {
                     Err(Status::invalid_argument(format!("Provider ID `{}` already exists", s)))
                 }
+                VideoWriteError::IoError(e) => Err(map_io_error(e)),
                 VideoWriteError::Poisoned(e) => Err(Status::aborted(format!("{}", e))),
             },
         }?;
@@ -257,14 +283,7 @@ impl VideoStorageServiceTrait for VideoStorageService {
             Err(e) => Err(Status::aborted(format!("{}", e))),
         }?;
 
-        let storage = match self.find_storage_by_id(&video.storage_id).await {
-            Some(s) => s,
-            None => {
-                return Err(Status::unavailable(
-                    "Target storage is temporarily unavailable or not found",
-                ))
-            }
-        };
+        let storage = require_storage_by_id(&self.storages, &video).await?;
         let mut reader = match storage.find_bin(&video).await {
             Ok(s) => s,
             Err(e) => return handle_find_status_error(e),
@@ -319,4 +338,65 @@ impl VideoStorageServiceTrait for VideoStorageService {
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn delete_video(
+        &self,
+        request: Request<DeleteVideoRequest>,
+    ) -> Result<Response<DeleteVideoResponse>, Status> {
+        let msg = request.into_inner();
+        if msg.video_id.is_empty() {
+            return Err(Status::invalid_argument("Invalid value: video_id"));
+        }
+
+        let video_id =
+            Uuid::parse_str(&msg.video_id).map_err(|_| Status::invalid_argument("Invalid value: video_id"))?;
+        let video = match self.store.find_video(&video_id) {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => Err(Status::not_found("Video not found")),
+            Err(e) => Err(Status::aborted(format!("{}", e))),
+        }?;
+
+        if msg.dry_run {
+            if msg.permanent {
+                require_storage_by_id(&self.storages, &video).await?;
+            }
+            return Ok(Response::new(DeleteVideoResponse {
+                video: Some(video.exchangeable()),
+            }));
+        }
+
+        let video = if msg.permanent {
+            let video = trash::purge_video(&self.store, &self.storages, &video).await?;
+            println!("DeleteVideo finish: {}", video.id);
+            video
+        } else {
+            let video = self
+                .store
+                .trash_video(&video_id, Utc::now())
+                .map_err(trash::map_trash_error)?;
+            println!("DeleteVideo moved to trash: {}", video.id);
+            video
+        };
+
+        Ok(Response::new(DeleteVideoResponse {
+            video: Some(video.exchangeable()),
+        }))
+    }
+
+    async fn restore_video(
+        &self,
+        request: Request<RestoreVideoRequest>,
+    ) -> Result<Response<RestoreVideoResponse>, Status> {
+        let msg = request.into_inner();
+        if msg.video_id.is_empty() {
+            return Err(Status::invalid_argument("Invalid value: video_id"));
+        }
+
+        let video_id =
+            Uuid::parse_str(&msg.video_id).map_err(|_| Status::invalid_argument("Invalid value: video_id"))?;
+        let video = self.store.restore_video(&video_id).map_err(trash::map_trash_error)?;
+        Ok(Response::new(RestoreVideoResponse {
+            video: Some(video.exchangeable()),
+        }))
+    }
 }
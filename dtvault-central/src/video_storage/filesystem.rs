@@ -2,9 +2,11 @@ use crate::program::{Program, Video};
 use crate::video_storage::storage::*;
 use fs2::FileExt;
 use pin_project::{pin_project, pinned_drop};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{Read, Seek, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::fs::File;
@@ -14,6 +16,7 @@ use uuid::Uuid;
 const FILE_PROGRAM: &str = "program.json";
 const FILE_PROGRAM_METADATA: &str = "metadata.json";
 const FILE_VIDEO: &str = "video.json";
+const FILE_THUMBNAIL: &str = "thumbnail";
 
 pub struct FileSystem {
     label: String,
@@ -121,35 +124,9 @@ impl FileSystem {
     }
 
     async fn store_metadata(&self, video_dir: &PathBuf, program: &Program, video: &Video) -> Result<(), CreateError> {
-        async fn write_json(path: PathBuf, json: String) -> Result<(), CreateError> {
-            match tokio::fs::File::create(path).await {
-                Ok(mut f) => match f.write_all(json.as_bytes()).await {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(CreateError::MetadataBackupFailed(e.to_string())),
-                },
-                Err(e) => Err(CreateError::MetadataBackupFailed(e.to_string())),
-            }
-        }
-
-        let program_json = match serde_json::to_string_pretty(&program) {
-            Ok(json) => json,
-            Err(e) => return Err(CreateError::MetadataBackupFailed(e.to_string())),
-        };
-        write_json(video_dir.join(FILE_PROGRAM), program_json).await?;
-
-        let metadata_json = match serde_json::to_string_pretty(program.metadata()) {
-            Ok(json) => json,
-            Err(e) => return Err(CreateError::MetadataBackupFailed(e.to_string())),
-        };
-        write_json(video_dir.join(FILE_PROGRAM_METADATA), metadata_json).await?;
-
-        let video_json = match serde_json::to_string_pretty(video) {
-            Ok(json) => json,
-            Err(e) => return Err(CreateError::MetadataBackupFailed(e.to_string())),
-        };
-        write_json(video_dir.join(FILE_VIDEO), video_json).await?;
-
-        Ok(())
+        write_sidecars(video_dir, program, video, None)
+            .await
+            .map_err(|e| CreateError::MetadataBackupFailed(e.to_string()))
     }
 }
 
@@ -221,6 +198,176 @@ impl Storage for FileSystem {
 
         Ok(Box::pin(FSWriter::new(file, video_dir, lock)))
     }
+
+    async fn delete(&self, video: &Video) -> Result<(), DeleteError> {
+        let lock = self.take_shared_lock()?;
+        if !verify_storage_id(video, &lock.metadata) {
+            return Err(DeleteError::Unavailable(UnavailableError {
+                reason: format!(
+                    "Storage ID mismatched (Required = {}, Mounted = {})",
+                    video.storage_id, lock.metadata.id
+                ),
+            }));
+        }
+
+        // 動画ファイルとメタデータのバックアップは、動画ごとのディレクトリにまとめて格納されている
+        let video_dir = self.find_video_dir(video);
+        if !video_dir.is_dir() {
+            return Err(DeleteError::NotFound);
+        }
+        tokio::fs::remove_dir_all(&video_dir).await?;
+
+        Ok(())
+    }
+
+    async fn update_sidecars(
+        &self,
+        program: &Program,
+        video: &Video,
+        thumbnail: Option<&[u8]>,
+    ) -> Result<(), SidecarError> {
+        let lock = self.take_shared_lock()?;
+        if !verify_storage_id(video, &lock.metadata) {
+            return Err(SidecarError::Unavailable(UnavailableError {
+                reason: format!(
+                    "Storage ID mismatched (Required = {}, Mounted = {})",
+                    video.storage_id, lock.metadata.id
+                ),
+            }));
+        }
+
+        let video_dir = self.find_video_dir(video);
+        if !video_dir.is_dir() {
+            return Err(SidecarError::NotFound);
+        }
+        write_sidecars(&video_dir, program, video, thumbnail).await
+    }
+
+    async fn scan_sidecars(&self) -> Result<SidecarScan, UnavailableError> {
+        let lock = self.take_shared_lock()?;
+        let root_dir = PathBuf::from(&self.root_dir);
+        let storage_id = lock.metadata.id;
+        let scan = tokio::task::spawn_blocking(move || {
+            let mut scan = SidecarScan::default();
+            scan_dir(&root_dir, &root_dir, storage_id, &mut scan);
+            scan
+        })
+        .await
+        .map_err(|e| UnavailableError { reason: e.to_string() })?;
+        drop(lock);
+
+        Ok(scan)
+    }
+}
+
+/// 動画ごとのディレクトリに番組と動画の情報を書き込む。途中で失敗しても、個々のファイルが壊れた状態にはならない。
+async fn write_sidecars(
+    video_dir: &Path,
+    program: &Program,
+    video: &Video,
+    thumbnail: Option<&[u8]>,
+) -> Result<(), SidecarError> {
+    write_atomic(video_dir.join(FILE_PROGRAM), &serde_json::to_vec_pretty(program)?).await?;
+    write_atomic(
+        video_dir.join(FILE_PROGRAM_METADATA),
+        &serde_json::to_vec_pretty(program.metadata())?,
+    )
+    .await?;
+    write_atomic(video_dir.join(FILE_VIDEO), &serde_json::to_vec_pretty(video)?).await?;
+    if let Some(thumbnail) = thumbnail {
+        write_atomic(video_dir.join(FILE_THUMBNAIL), thumbnail).await?;
+    }
+    Ok(())
+}
+
+async fn write_atomic(path: PathBuf, data: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&temp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&temp_path, &path).await
+}
+
+/// dir 以下から動画ごとのディレクトリを探し、番組と動画の情報を読み込む。
+fn scan_dir(root_dir: &Path, dir: &Path, storage_id: Uuid, scan: &mut SidecarScan) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            scan.failures.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                scan.failures.push(format!("{}: {}", dir.display(), e));
+                continue;
+            }
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        if path.join(FILE_VIDEO).is_file() {
+            match read_sidecar(root_dir, &path, storage_id) {
+                Ok(sidecar) => scan.sidecars.push(sidecar),
+                Err(e) => scan.failures.push(format!("{}: {}", path.display(), e)),
+            }
+        } else {
+            scan_dir(root_dir, &path, storage_id, scan);
+        }
+    }
+}
+
+fn read_sidecar(root_dir: &Path, video_dir: &Path, storage_id: Uuid) -> Result<Sidecar, String> {
+    fn read_json<T: DeserializeOwned>(path: PathBuf) -> Result<T, String> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let json = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", name, e))?;
+        serde_json::from_str(&json).map_err(|e| format!("{}: {}", name, e))
+    }
+
+    let mut program: serde_json::Value = read_json(video_dir.join(FILE_PROGRAM))?;
+    // created_at が存在しない古いデータは、放送開始時刻で代用する
+    if let Some(program) = program.as_object_mut() {
+        if !program.contains_key("created_at") {
+            if let Some(start_at) = program.get("start_at").cloned() {
+                program.insert("created_at".to_string(), start_at);
+            }
+        }
+    }
+    let mut program: Program = serde_json::from_value(program).map_err(|e| format!("{}: {}", FILE_PROGRAM, e))?;
+    let mut video: Video = read_json(video_dir.join(FILE_VIDEO))?;
+    let metadata_path = video_dir.join(FILE_PROGRAM_METADATA);
+    if metadata_path.is_file() {
+        *program.metadata_mut() = read_json::<HashMap<String, String>>(metadata_path)?;
+    }
+
+    if video_dir.file_name().and_then(|n| n.to_str()) != Some(&video.stringify_id()) {
+        return Err("directory name does not match video id".to_string());
+    }
+    let thumbnail_path = video_dir.join(FILE_THUMBNAIL);
+    let thumbnail = if thumbnail_path.is_file() {
+        Some(std::fs::read(thumbnail_path).map_err(|e| format!("{}: {}", FILE_THUMBNAIL, e))?)
+    } else {
+        None
+    };
+    if !video_dir.join(&video.file_name).is_file() {
+        return Err(format!("missing video file `{}`", video.file_name));
+    }
+    // ストレージを作り直したり移動したりしている場合もあるので、見つかった場所を正とする
+    video.storage_id = storage_id;
+    video.storage_prefix = video_dir
+        .parent()
+        .and_then(|dir| dir.strip_prefix(root_dir).ok())
+        .map_or_else(String::new, |prefix| prefix.to_string_lossy().to_string());
+
+    Ok(Sidecar {
+        location: video_dir.display().to_string(),
+        program,
+        video,
+        thumbnail,
+    })
 }
 
 pub struct FSSharedLock {
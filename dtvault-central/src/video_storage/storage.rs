@@ -13,6 +13,32 @@ pub trait Storage {
     async fn find_bin(&self, video: &Video) -> Result<Pin<Box<dyn StorageReader + Send>>, FindStatusError>;
     async fn create(&self, program: &Program, video: &Video)
         -> Result<Pin<Box<dyn StorageWriter + Send>>, CreateError>;
+    async fn delete(&self, video: &Video) -> Result<(), DeleteError>;
+    /// 動画と一緒に保存している番組と動画の情報を書き直す。thumbnail が None の場合、サムネイルは変更しない。
+    async fn update_sidecars(
+        &self,
+        program: &Program,
+        video: &Video,
+        thumbnail: Option<&[u8]>,
+    ) -> Result<(), SidecarError>;
+    /// 動画と一緒に保存した番組と動画の情報を全て読み込む。
+    async fn scan_sidecars(&self) -> Result<SidecarScan, UnavailableError>;
+}
+
+/// 動画と一緒に保存されていた番組と動画の情報
+pub struct Sidecar {
+    /// 見つかった場所 (報告用)
+    pub location: String,
+    pub program: Program,
+    pub video: Video,
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct SidecarScan {
+    pub sidecars: Vec<Sidecar>,
+    /// 読み込めなかったものについてのエラーメッセージ
+    pub failures: Vec<String>,
 }
 
 pub trait StorageReader: AsyncRead {}
@@ -40,6 +66,18 @@ pub enum CreateError {
     MetadataBackupFailed(String),
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum SidecarError {
+    #[error(transparent)]
+    Unavailable(#[from] UnavailableError),
+    #[error("Video not found")]
+    NotFound,
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SerializeError(#[from] serde_json::Error),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum FindStatusError {
     #[error(transparent)]
@@ -51,3 +89,13 @@ pub enum FindStatusError {
     #[error("Error reading status: {0}")]
     ReadError(String),
 }
+
+#[derive(thiserror::Error, Debug)]
+pub enum DeleteError {
+    #[error(transparent)]
+    Unavailable(#[from] UnavailableError),
+    #[error("Video not found")]
+    NotFound,
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
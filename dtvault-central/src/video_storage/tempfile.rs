@@ -1,5 +1,8 @@
 use crate::program::{Program, Video};
-use crate::video_storage::{CreateError, FindStatusError, Storage, StorageReader, StorageWriter, UnavailableError};
+use crate::video_storage::{
+    CreateError, DeleteError, FindStatusError, SidecarError, SidecarScan, Storage, StorageReader, StorageWriter,
+    UnavailableError,
+};
 use pin_project::pin_project;
 use std::collections::BTreeMap;
 use std::pin::Pin;
@@ -69,6 +72,28 @@ impl Storage for Tempfile {
             files: self.files.clone(),
         }))
     }
+
+    async fn delete(&self, video: &Video) -> Result<(), DeleteError> {
+        let mut files = self.files.write().await;
+        match files.remove(&video.id) {
+            Some(_) => Ok(()),
+            None => Err(DeleteError::NotFound),
+        }
+    }
+
+    async fn update_sidecars(
+        &self,
+        _program: &Program,
+        _video: &Video,
+        _thumbnail: Option<&[u8]>,
+    ) -> Result<(), SidecarError> {
+        Ok(())
+    }
+
+    async fn scan_sidecars(&self) -> Result<SidecarScan, UnavailableError> {
+        // 一時ファイルには番組と動画の情報を保存していない
+        Ok(SidecarScan::default())
+    }
 }
 
 #[pin_project]
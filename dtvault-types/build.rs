@@ -4,14 +4,23 @@ const PROTO_ROOT: &str = "../proto";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed={}", PROTO_ROOT);
-    tonic_build::configure().compile(
-        &[
-            concatcp!(PROTO_ROOT, "/shibafu528/dtvault/central/persistence.proto"),
-            concatcp!(PROTO_ROOT, "/shibafu528/dtvault/central/program_service.proto"),
-            concatcp!(PROTO_ROOT, "/shibafu528/dtvault/encoder/encoder_service.proto"),
-            concatcp!(PROTO_ROOT, "/shibafu528/dtvault/storage/video_storage_service.proto"),
-        ],
-        &[PROTO_ROOT],
-    )?;
+    tonic_build::configure()
+        .type_attribute(
+            ".shibafu528.dtvault.central.PersistLogEntry.entry",
+            "#[allow(clippy::large_enum_variant)]",
+        )
+        .type_attribute(
+            ".shibafu528.dtvault.storage.GetVideoResponse.part",
+            "#[allow(clippy::large_enum_variant)]",
+        )
+        .compile(
+            &[
+                concatcp!(PROTO_ROOT, "/shibafu528/dtvault/central/persistence.proto"),
+                concatcp!(PROTO_ROOT, "/shibafu528/dtvault/central/program_service.proto"),
+                concatcp!(PROTO_ROOT, "/shibafu528/dtvault/encoder/encoder_service.proto"),
+                concatcp!(PROTO_ROOT, "/shibafu528/dtvault/storage/video_storage_service.proto"),
+            ],
+            &[PROTO_ROOT],
+        )?;
     Ok(())
 }
@@ -2,7 +2,7 @@ const { createProxyMiddleware } = require('http-proxy-middleware');
 
 module.exports = function (app) {
     app.use(
-        ['/query', '/stream'],
+        ['/query', '/stream', '/thumbnail'],
         createProxyMiddleware({
             target: 'http://localhost:8080',
             changeOrigin: true,